@@ -0,0 +1,51 @@
+//! Allocation counts of the benchmarks of `search`, reported per operation.
+//!
+//! Run with `cargo bench --bench allocations`, optionally followed by `--`
+//! and a substring of the benchmark names. The counting allocator slows
+//! down every allocation, so timings are measured by the `search` bench.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod common;
+
+/// System allocator counting the allocations.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn main() {
+    let filter = common::Filter::from_args();
+    /* Allocations are deterministic; after a warm up a single run is
+     * enough */
+    common::benchmarks(&mut |name, _iterations, batch, routine| {
+        if !filter.enabled(name) {
+            return;
+        }
+        routine();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        routine();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        println!("{:<44} allocs {:>10.1}  (1 x {})",
+                 name, allocations as f64 / batch as f64, batch);
+    });
+}
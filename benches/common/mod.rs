@@ -0,0 +1,290 @@
+//! Synthetic corpus and benchmark cases shared by the bench binaries.
+
+use std::hint::black_box;
+
+use fuzzdex::fuzzdex::query::Query;
+use fuzzdex::fuzzdex::seeker::Index;
+use fuzzdex::fuzzdex::Indexer;
+use fuzzdex::utils;
+
+const PHRASES: usize = 100_000;
+const SEED: u64 = 0x5eed_f022_de70_0001;
+
+const SYLLABLES: &[&str] = &[
+    "war", "sza", "wa", "kra", "ków", "gdań", "sk", "po", "zna", "łódź", "ka",
+    "to", "wi", "ce", "lu", "blin", "ole", "śnie", "bia", "ły", "sto", "rze",
+    "szów", "go", "rzów", "mar", "ki", "zie", "lo", "na", "gó", "ra", "by",
+    "dgo", "szcz", "cin", "kie", "lce", "opo", "le", "ryb", "nik", "ow",
+];
+
+const KINDS: &[&str] = &["ulica", "aleja", "plac", "osiedle", "rondo"];
+
+/// Xorshift generator, to keep the corpus reproducible without
+/// additional dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+/// Word of 2-4 syllables, capitalized.
+fn word(rng: &mut Rng) -> String {
+    let syllables = 2 + rng.below(3);
+    let word: String = (0..syllables).map(|_| rng.pick(SYLLABLES)).collect();
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => word,
+    }
+}
+
+/// Street-like phrases with a region constraint, eg. "Aleja Krakowska 12".
+/// Words are drawn from a limited dictionary, so tokens repeat across
+/// phrases as they do in real gazetteers.
+fn corpus(size: usize) -> Vec<(String, String)> {
+    let mut rng = Rng(SEED);
+    let dictionary: Vec<String> = (0..size / 20).map(|_| word(&mut rng)).collect();
+    (0..size)
+        .map(|_| {
+            let mut phrase = String::from(rng.pick(KINDS));
+            for _ in 0..1 + rng.below(2) {
+                phrase.push(' ');
+                phrase.push_str(&dictionary[rng.below(dictionary.len())]);
+            }
+            if rng.below(3) == 0 {
+                phrase.push_str(&format!(" {}", 1 + rng.below(200)));
+            }
+            let region = format!("PL-{:02}", rng.below(16) * 2 + 2);
+            (phrase, region)
+        })
+        .collect()
+}
+
+fn indexer(corpus: &[(String, String)]) -> Indexer {
+    let mut indexer = Indexer::new();
+    for (phrase_idx, (phrase, region)) in corpus.iter().enumerate() {
+        indexer.add_phrase_str_constraints(phrase, phrase_idx, &[region.as_str()]).unwrap();
+    }
+    indexer
+}
+
+/// Must tokens of the benchmarks: indexed tokens with a typo, so the
+/// searches do some fuzzy work.
+fn must_tokens(index: &Index, count: usize) -> Vec<String> {
+    let mut rng = Rng(SEED ^ 0xffff);
+    (0..count)
+        .filter_map(|_| {
+            let tokens = index.phrase_tokens(rng.below(index.len()))?;
+            let token = tokens.iter().skip(1).max_by_key(|token| token.len())?;
+            let mut chars: Vec<char> = token.chars().collect();
+            let swapped = rng.below(chars.len() - 1);
+            chars.swap(swapped, swapped + 1);
+            Some(chars.into_iter().collect())
+        })
+        .collect()
+}
+
+/// Phrases of 4 random words of 6 letters of several scripts, so nearly
+/// all of their trigrams are distinct; 300k phrases give millions of them.
+fn trigram_heavy_indexer(size: usize) -> Indexer {
+    let alphabet: Vec<char> = ('a'..='z')
+        .chain('а'..='я')
+        .chain('α'..='ω')
+        .chain('ա'..='ֆ')
+        .chain('ა'..='ჰ')
+        .collect();
+    let mut rng = Rng(SEED ^ 0xabcd);
+    let mut indexer = Indexer::new();
+    for phrase_idx in 0..size {
+        let phrase: Vec<String> = (0..4)
+            .map(|_| (0..6).map(|_| alphabet[rng.below(alphabet.len())]).collect())
+            .collect();
+        indexer.add_phrase(&phrase.join(" "), phrase_idx, None).unwrap();
+    }
+    indexer
+}
+
+/// Pairs of 32 letter tokens compared by the scan: every fourth is the
+/// token with 1-3 substitutions or swaps, the others are unrelated tokens of
+/// the same length.
+fn long_token_pairs(count: usize) -> Vec<(String, String)> {
+    let mut rng = Rng(SEED ^ 0x1062);
+    let token = |rng: &mut Rng| -> Vec<char> {
+        (0..32).map(|_| (b'a' + rng.below(26) as u8) as char).collect()
+    };
+    (0..count)
+        .map(|pair| {
+            let side_a = token(&mut rng);
+            let side_b = if pair % 4 == 0 {
+                let mut side_b = side_a.clone();
+                for _ in 0..1 + rng.below(3) {
+                    let position = rng.below(side_b.len() - 1);
+                    if rng.below(2) == 0 {
+                        side_b.swap(position, position + 1);
+                    } else {
+                        side_b[position] = (b'a' + rng.below(26) as u8) as char;
+                    }
+                }
+                side_b
+            } else {
+                token(&mut rng)
+            };
+            (side_a.into_iter().collect(), side_b.into_iter().collect())
+        })
+        .collect()
+}
+
+/// Names of the benchmarks to run: the first argument which isn't a flag
+/// (`cargo bench` passes `--bench`) filters them by substring.
+pub struct Filter(Option<String>);
+
+impl Filter {
+    pub fn from_args() -> Filter {
+        Filter(std::env::args().skip(1).find(|arg| !arg.starts_with("--")))
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        self.0.as_ref().is_none_or(|filter| name.contains(filter.as_str()))
+    }
+}
+
+/// Measures a benchmark: its name, number of iterations, operations per
+/// iteration and the routine doing them.
+pub type Run<'a> = dyn FnMut(&str, usize, usize, &mut dyn FnMut()) + 'a;
+
+/// Run all benchmarks with the measurement of the bench binary.
+pub fn benchmarks(run: &mut Run) {
+    let corpus = corpus(PHRASES);
+
+    run("finish/100k", 3, 1, &mut || {
+        let indexer = indexer(&corpus);
+        black_box(indexer.finish());
+    });
+
+    /* Scoring pass of finish; compare builds with and without the
+     * `parallel` feature */
+    let mut heavy = trigram_heavy_indexer(PHRASES * 3);
+    run("rescore/300k", 3, 1, &mut || heavy.rescore());
+    #[cfg(feature = "parallel")]
+    {
+        let single = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        run("rescore/300k/threads=1", 3, 1, &mut || single.install(|| heavy.rescore()));
+    }
+    drop(heavy);
+
+    /* Bounded distances against full ones, on candidates of the scan */
+    let pairs = long_token_pairs(1000);
+    run("distance/len=32/full", 5, pairs.len(), &mut || {
+        for (side_a, side_b) in pairs.iter() {
+            black_box(utils::distance(side_a, side_b));
+        }
+    });
+    run("distance/len=32/damerau", 5, pairs.len(), &mut || {
+        for (side_a, side_b) in pairs.iter() {
+            black_box(utils::damerau_distance(side_a, side_b));
+        }
+    });
+    for max in [1, 2] {
+        run(&format!("distance/len=32/within={}", max), 5, pairs.len(), &mut || {
+            for (side_a, side_b) in pairs.iter() {
+                black_box(utils::distance_within(side_a, side_b, max));
+            }
+        });
+        run(&format!("distance/len=32/damerau_within={}", max), 5, pairs.len(), &mut || {
+            for (side_a, side_b) in pairs.iter() {
+                black_box(utils::damerau_within(side_a, side_b, max));
+            }
+        });
+    }
+
+    /* Many constraints per phrase: borrowed sets are cloned by the index */
+    let many = &corpus[..10_000];
+    run("add/constraints=256/borrowed", 5, many.len(), &mut || {
+        let mut indexer = Indexer::new();
+        for (phrase_idx, (phrase, _region)) in many.iter().enumerate() {
+            let constraints = (phrase_idx..phrase_idx + 256).collect();
+            indexer.add_phrase(phrase, phrase_idx, Some(&constraints)).unwrap();
+        }
+        black_box(indexer);
+    });
+    run("add/constraints=256/owned", 5, many.len(), &mut || {
+        let mut indexer = Indexer::new();
+        for (phrase_idx, (phrase, _region)) in many.iter().enumerate() {
+            let constraints = (phrase_idx..phrase_idx + 256).collect();
+            indexer.add_phrase_owned(phrase, phrase_idx, constraints).unwrap();
+        }
+        black_box(indexer);
+    });
+
+    /* Cache of 1 and alternating tokens: each heatmap is computed anew */
+    let cold = indexer(&corpus).finish_with_cache(1);
+    let tokens = must_tokens(&cold, 200);
+    run("heatmap/cold", 10, tokens.len(), &mut || {
+        for token in tokens.iter() {
+            black_box(cold.heatmap_for(token));
+        }
+    });
+
+    let warm = indexer(&corpus).finish_with_cache(tokens.len());
+    warm.warm_cache(&tokens.iter().map(|token| token.as_str()).collect::<Vec<_>>());
+    run("heatmap/warm", 10, tokens.len(), &mut || {
+        for token in tokens.iter() {
+            black_box(warm.heatmap_for(token));
+        }
+    });
+
+    /* Searches are much slower than heatmaps; use fewer of them */
+    let tokens = &tokens[..50];
+    for limit in [Some(1), Some(10), Some(100), None] {
+        for max_distance in [Some(0), Some(1), Some(2), None] {
+            let name = format!("search/limit={:?}/max_distance={:?}", limit, max_distance);
+            let queries: Vec<Query> = tokens
+                .iter()
+                .map(|token| {
+                    warm.query(&[token.as_str()], &["ulica"])
+                        .limit(limit)
+                        .max_distance(max_distance)
+                })
+                .collect();
+            run(&name, 5, queries.len(), &mut || {
+                for query in queries.iter() {
+                    black_box(warm.search(query));
+                }
+            });
+        }
+    }
+
+    /* Should scores under a constraint check each should position against
+     * the constraint */
+    let should = ["ulica", "aleja", "plac", "osiedle"];
+    for constrained in [false, true] {
+        let name = format!("search/should=4/constrained={}", constrained);
+        let queries: Vec<Query> = tokens
+            .iter()
+            .zip(corpus.iter())
+            .map(|(token, (_phrase, region))| {
+                warm.query(&[token.as_str()], &should)
+                    .constraint_str(constrained.then_some(region.as_str()))
+                    .limit(Some(10))
+            })
+            .collect();
+        run(&name, 5, queries.len(), &mut || {
+            for query in queries.iter() {
+                black_box(warm.search(query));
+            }
+        });
+    }
+}
@@ -0,0 +1,33 @@
+//! Timings of the core index paths on a synthetic corpus.
+//!
+//! Run with `cargo bench`; pass a substring to run only matching benchmarks
+//! (eg. `cargo bench -- heatmap`). The corpus is generated from a fixed seed,
+//! so results of different builds are comparable. Allocations are counted
+//! by the `allocations` bench, as counting them here would skew the timings.
+
+use std::time::{Duration, Instant};
+
+mod common;
+
+fn main() {
+    let filter = common::Filter::from_args();
+    /* Time `iterations` runs of the routine, each doing `batch` operations,
+     * and print the per operation median and minimum */
+    common::benchmarks(&mut |name, iterations, batch, routine| {
+        if !filter.enabled(name) {
+            return;
+        }
+        /* Warm up allocator and caches the routine doesn't own */
+        routine();
+        let mut times: Vec<Duration> = (0..iterations)
+            .map(|_| {
+                let start = Instant::now();
+                routine();
+                start.elapsed() / batch as u32
+            })
+            .collect();
+        times.sort_unstable();
+        println!("{:<44} median {:>12.3?}  min {:>12.3?}  ({} x {})",
+                 name, times[times.len() / 2], times[0], iterations, batch);
+    });
+}
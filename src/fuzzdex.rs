@@ -1,17 +1,28 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
+use serde::{Serialize, Deserialize};
 
 /* Fast hashing, but requires AES-ni extensions */
-type FastHash = ahash::RandomState;
+#[cfg(feature = "ahash")]
+pub(crate) type FastHash = ahash::RandomState;
+/* Portable std SipHash for targets without AES-ni */
+#[cfg(not(feature = "ahash"))]
+pub(crate) type FastHash = std::collections::hash_map::RandomState;
 
+pub mod config;
 pub mod query;
 pub mod indexer;
 pub mod seeker;
+pub mod persist;
+pub mod federated;
+pub mod jsonl;
+
+pub use config::IndexerConfig;
 
 #[cfg(test)]
 mod tests;
 
-/// Single possible error type.
+/// Phrase index is already used.
 #[derive(Debug)]
 pub struct DuplicateId;
 
@@ -22,8 +33,142 @@ impl std::fmt::Display for DuplicateId {
 }
 impl std::error::Error for DuplicateId {}
 
-/// Token owning a trigram is uniquely identified by phrase index + token index.
+/// Error while adding a phrase to the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddError {
+    /// Phrase index is already used.
+    DuplicateId,
+    /// Phrase has no tokens (eg. only separators) and could never be found.
+    EmptyPhrase,
+    /// Phrase has tokens, but none of them produce trigrams (eg. combining
+    /// marks only), so it could never be found. Only reported by
+    /// `Indexer::check_phrase` and `Indexer::add_phrase_searchable`; other
+    /// methods add such phrases (see `Indexer::unsearchable_phrases`).
+    NoTrigrams,
+    /// Phrase has numeric constraints, but the index uses string ones, or
+    /// the other way around. Interned strings are numbered like numeric
+    /// constraints, so they can't be told apart.
+    MixedConstraints,
+}
+
+impl std::fmt::Display for AddError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddError::DuplicateId => write!(f, "Duplicated Phrase ID"),
+            AddError::EmptyPhrase => write!(f, "Phrase contains no tokens"),
+            AddError::NoTrigrams => write!(f, "Phrase tokens produce no trigrams"),
+            AddError::MixedConstraints => write!(f, "Index can't mix numeric and string constraints"),
+        }
+    }
+}
+impl std::error::Error for AddError {}
+
+impl From<DuplicateId> for AddError {
+    fn from(_: DuplicateId) -> Self {
+        AddError::DuplicateId
+    }
+}
+
+/// Error while merging indexers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeError {
+    /// Both indexers contain the same phrase index.
+    DuplicateId,
+    /// Indexers were created with different configurations.
+    ConfigMismatch,
+    /// One indexer uses numeric constraints and the other string ones.
+    MixedConstraints,
+}
+
+impl std::fmt::Display for MergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeError::DuplicateId => write!(f, "Duplicated Phrase ID"),
+            MergeError::ConfigMismatch => write!(f, "Can't merge differently configured indexers"),
+            MergeError::MixedConstraints => write!(f, "Can't merge numeric and string constraints"),
+        }
+    }
+}
+impl std::error::Error for MergeError {}
+
+/// Query was created with a different configuration than the index uses.
 #[derive(Debug)]
+pub struct ConfigMismatch;
+
+impl std::fmt::Display for ConfigMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Query was created for a differently configured index, use Index::query")
+    }
+}
+impl std::error::Error for ConfigMismatch {}
+
+/// Must token of a query is shorter than the required minimum.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MustTooShort {
+    /// Offending must token.
+    pub token: String,
+    /// Required minimal length in graphemes.
+    pub min_length: usize,
+}
+
+impl std::fmt::Display for MustTooShort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Must token {:?} is shorter than {} characters", self.token, self.min_length)
+    }
+}
+impl std::error::Error for MustTooShort {}
+
+/// Must token tokenizes into many tokens and the query uses `MustSplit::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiTokenMust {
+    /// Offending must token.
+    pub token: String,
+    /// Tokens it was split into.
+    pub tokens: Vec<String>,
+}
+
+impl std::fmt::Display for MultiTokenMust {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Must token {:?} splits into multiple tokens {:?}", self.token, self.tokens)
+    }
+}
+impl std::error::Error for MultiTokenMust {}
+
+/// Internal inconsistency of the index found by `Indexer::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InconsistencyError {
+    /// Trigram position points to a phrase which doesn't exist.
+    MissingPhrase { trigram: String, phrase_idx: usize },
+    /// Trigram position points past the tokens of its phrase.
+    TokenOutOfBounds { trigram: String, phrase_idx: usize, token_idx: u32, tokens: usize },
+    /// Trigram score is NaN or infinite.
+    InvalidScore { trigram: String, score: f32 },
+}
+
+impl std::fmt::Display for InconsistencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InconsistencyError::MissingPhrase { trigram, phrase_idx } =>
+                write!(f, "Trigram {:?} points to missing phrase {}", trigram, phrase_idx),
+            InconsistencyError::TokenOutOfBounds { trigram, phrase_idx, token_idx, tokens } =>
+                write!(f, "Trigram {:?} points to token {} of phrase {} with {} tokens",
+                       trigram, token_idx, phrase_idx, tokens),
+            InconsistencyError::InvalidScore { trigram, score } =>
+                write!(f, "Trigram {:?} has invalid score {}", trigram, score),
+        }
+    }
+}
+impl std::error::Error for InconsistencyError {}
+
+/// Tag of the field (eg. name, city) a token of a multi-field phrase comes
+/// from. Tokens of phrases added without fields belong to `DEFAULT_FIELD`.
+pub type FieldId = u16;
+
+/// Field of tokens of phrases added with `Indexer::add_phrase`.
+pub const DEFAULT_FIELD: FieldId = 0;
+
+/// Token owning a trigram is uniquely identified by phrase index + token index.
+#[derive(Debug, Serialize, Deserialize)]
 struct Position {
     /// Phrase index / value
     phrase_idx: usize,
@@ -32,34 +177,89 @@ struct Position {
 }
 
 /// Trigram data inside the Index
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct TrigramEntry {
     /// Where trigram appears (phrase / token).
     positions: Vec<Position>,
-    /// Trigram score; the more unique trigram, the higher score.
+    /// Trigram score; the more unique trigram, the higher score. Calculated
+    /// from the number of positions by `Indexer::rescore` and `finish`.
     score: f32,
+    /// Positions were truncated to `ScoringParams::max_positions`.
+    capped: bool,
 }
 
 /// Information stored about the inserted phrase
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct PhraseEntry {
     /// Phrase index, as given by the user.
     idx: usize,
     /// Original phrase.
     origin: String,
+    /// Lowercased phrase, stored with `IndexerConfig::normalized_origin`.
+    normalized_origin: Option<String>,
     /// Tokens that build this phrase.
     tokens: Vec<String>,
+    /// Field of each token. Empty when all tokens are in `DEFAULT_FIELD`.
+    fields: Vec<FieldId>,
     /// Constraints with which this phrase is valid.
-    constraints: HashSet<usize, FastHash>
+    constraints: HashSet<usize, FastHash>,
+    /// Number of phrases inserted into the indexer before this one.
+    sequence: u64,
+}
+
+/// Parameters of the trigram scoring curve applied in `finish`:
+/// `0.5 + tanh(steepness * (average - popularity - center_offset) / max) / 2`
+/// clamped to 0-1. When all trigrams are equally popular they all score 0.5.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoringParams {
+    /// Higher values separate scores of rare and common trigrams more.
+    pub steepness: f32,
+    /// Shifts popularity that scores 0.5 away from the average.
+    pub center_offset: f32,
+    /// Keep at most this many positions of each trigram, preferring
+    /// positions within the shortest tokens. Bounds the heatmap cost of
+    /// tokens with very popular trigrams at the cost of recall: phrases
+    /// matching only through dropped positions can't be found. Scores still
+    /// use the real trigram popularity.
+    pub max_positions: Option<usize>,
+    /// Trigrams occurring fewer times are scored as if they occurred this
+    /// many times, so rare trigrams from typos in the corpus don't dominate
+    /// heatmaps. 1 keeps scores unchanged.
+    pub min_occurrences: usize,
+}
+
+impl Default for ScoringParams {
+    fn default() -> Self {
+        ScoringParams {
+            steepness: 5.0,
+            center_offset: 1.0,
+            max_positions: None,
+            min_occurrences: 1,
+        }
+    }
 }
 
 /// Initial Index instance that can gather entries, but can't be queried.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Indexer {
     /// Trigram entries: {"abc": TrigramEntry, "cde": ...}.
     db: HashMap<String, TrigramEntry, FastHash>,
 
     /// Phrase metadata.
-    phrases: HashMap<usize, PhraseEntry, FastHash>
+    phrases: HashMap<usize, PhraseEntry, FastHash>,
+
+    /// Tokenization settings, used for queries too.
+    config: IndexerConfig,
+
+    /// Interned string constraints mapped to their internal ids.
+    constraint_names: HashMap<String, usize, FastHash>,
+
+    /// Number of phrases inserted so far; sequence of the next phrase.
+    inserted: u64,
+
+    /// Emptied position vectors of the trigrams removed by `clear`, reused
+    /// by new trigrams.
+    #[serde(skip)]
+    spare_positions: Vec<Vec<Position>>,
 }
 
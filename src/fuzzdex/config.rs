@@ -0,0 +1,199 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use serde::{Serialize, Deserialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::utils;
+
+/// Settings of the tokenization and trigramization. They are stored within
+/// the index and used identically when indexing phrases and querying, so the
+/// query is always processed the same way as the indexed data.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IndexerConfig {
+    /// Generate additional edge trigrams for tokens of 3-9 graphemes.
+    pub extended_tolerance: bool,
+    /// Lowercase tokens with `utils::case_fold`, which adds common Unicode
+    /// case foldings to `str::to_lowercase`.
+    pub case_folding: bool,
+    /// Split phrases on any Unicode whitespace and punctuation instead of
+    /// the fixed separator list (see `utils::tokenize_unicode`).
+    pub unicode_separators: bool,
+    /// Tokens with fewer graphemes are dropped from phrases and queries.
+    pub min_token_length: usize,
+    /// Extra weight of trigrams at the start of the must token, in percent
+    /// (see `prefix_boost`).
+    pub prefix_boost: u16,
+    /// Split tokens into runs of digits and letters (see `split_digits`).
+    pub split_digits: bool,
+    /// Combining marks removed before trigramization (see `marks`).
+    pub marks: utils::MarkPolicy,
+    /// Store a lowercased copy of each phrase (see `normalized_origin`).
+    pub normalized_origin: bool,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        IndexerConfig {
+            extended_tolerance: false,
+            case_folding: false,
+            unicode_separators: false,
+            min_token_length: 1,
+            prefix_boost: 0,
+            split_digits: false,
+            marks: utils::MarkPolicy::default(),
+            normalized_origin: false,
+        }
+    }
+}
+
+impl IndexerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Improve typo-tolerance of tokens shorter than 10 graphemes by
+    /// generating additional trigrams (see `utils::trigramize_with_tolerance`).
+    /// Increases the index size.
+    pub fn extended_tolerance(mut self, enabled: bool) -> Self {
+        self.extended_tolerance = enabled;
+        self
+    }
+
+    /// Use case folding when tokenizing, so eg. "İstanbul" and "Straße"
+    /// match "istanbul" and "strasse" exactly. Default lowercasing keeps a
+    /// combining dot over "i" and leaves "ß" as is.
+    pub fn case_folding(mut self, enabled: bool) -> Self {
+        self.case_folding = enabled;
+        self
+    }
+
+    /// Split tokens on all Unicode whitespace and punctuation characters, eg.
+    /// ideographic space and full-width punctuation of CJK texts. Default
+    /// separators are a fixed set of ASCII and common Polish punctuation.
+    pub fn unicode_separators(mut self, enabled: bool) -> Self {
+        self.unicode_separators = enabled;
+        self
+    }
+
+    /// Drop tokens shorter than `length` graphemes (eg. initials or single
+    /// digits), which are rarely meaningful and bloat the index. Default of 1
+    /// keeps all tokens; 0 is treated as 1.
+    pub fn min_token_length(mut self, length: usize) -> Self {
+        self.min_token_length = length.max(1);
+        self
+    }
+
+    /// Weight must trigrams by their position, for type-ahead where users
+    /// get the start of the word right. Trigram starting at the i-th
+    /// grapheme (counted from 0) of the must token contributes its score
+    /// multiplied by `1 + percent / 100 / (i + 1)` to the heatmap; pseudo and
+    /// edge trigrams of short tokens keep weight 1. Trigram scores computed
+    /// by `finish` are not changed; only their sum per candidate, which
+    /// orders the scan and is reported as the result `score`, is. Default
+    /// of 0 weights all trigrams uniformly.
+    pub fn prefix_boost(mut self, percent: u16) -> Self {
+        self.prefix_boost = percent;
+        self
+    }
+
+    /// Split mixed alphanumeric tokens into runs of digits and of other
+    /// characters, so "A1" is tokenized as "a" and "1" and "12B" as "12" and
+    /// "b". Applies to phrases and queries alike; `min_token_length` is
+    /// applied to the split tokens.
+    pub fn split_digits(mut self, enabled: bool) -> Self {
+        self.split_digits = enabled;
+        self
+    }
+
+    /// Choose which combining marks are removed from tokens before they are
+    /// split into trigrams. By default nonspacing marks (accents) are, so
+    /// "y̆es" and "yes" share trigrams; `MarkPolicy::Keep` keeps tokens
+    /// differing only by marks apart, as needed for eg. Indic scripts.
+    /// Tokens themselves, and so the distances, are never changed.
+    pub fn marks(mut self, policy: utils::MarkPolicy) -> Self {
+        self.marks = policy;
+        self
+    }
+
+    /// Store each phrase lowercased the same way as its tokens (see
+    /// `normalize`) next to the original, and return it as
+    /// `SearchResult::normalized_origin`, eg. for case-insensitive
+    /// highlighting of matched tokens. Roughly doubles the memory used by
+    /// phrase texts.
+    pub fn normalized_origin(mut self, enabled: bool) -> Self {
+        self.normalized_origin = enabled;
+        self
+    }
+
+    /// Lowercase a text like tokens are lowercased: with `case_folding` or
+    /// `str::to_lowercase`. Separators are kept.
+    pub fn normalize(&self, text: &str) -> String {
+        if self.case_folding {
+            utils::case_fold(text)
+        } else {
+            text.to_lowercase()
+        }
+    }
+
+    /// Short hash of the settings which change how queries are tokenized
+    /// and trigramized. Stored within queries to detect queries created for
+    /// a differently configured index. Settings applied only by the index,
+    /// like `prefix_boost` or `normalized_origin`, are left out, so
+    /// `Query::new` works with them.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.extended_tolerance.hash(&mut hasher);
+        self.case_folding.hash(&mut hasher);
+        self.unicode_separators.hash(&mut hasher);
+        self.min_token_length.hash(&mut hasher);
+        self.split_digits.hash(&mut hasher);
+        self.marks.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Split phrase into tokens.
+    pub fn tokenize(&self, phrase: &str) -> Vec<String> {
+        if !self.split_digits {
+            return self.split_phrase(phrase, self.min_token_length);
+        }
+        /* Length is checked after splitting */
+        self.split_phrase(phrase, 1)
+            .iter()
+            .flat_map(|token| utils::split_digit_runs(token))
+            .filter(|token| token.graphemes(true).count() >= self.min_token_length)
+            .map(|token| token.to_string())
+            .collect()
+    }
+
+    fn split_phrase(&self, phrase: &str, min_length: usize) -> Vec<String> {
+        if self.unicode_separators {
+            utils::tokenize_unicode(phrase, min_length, self.case_folding)
+        } else {
+            utils::tokenize_with_folding(phrase, min_length, self.case_folding)
+        }
+    }
+
+    /// Split token into trigrams.
+    pub fn trigramize(&self, token: &str) -> Vec<String> {
+        utils::trigramize_with_marks(token, self.extended_tolerance, self.marks)
+    }
+
+    /// Trigrams of consecutive graphemes of the token (see
+    /// `utils::regular_trigrams`).
+    pub(crate) fn regular_trigrams(&self, token: &str) -> Vec<String> {
+        utils::regular_trigrams_with_marks(token, self.marks)
+    }
+
+    /// Weights of the token trigrams, in the `trigramize` order, by
+    /// `prefix_boost`. Empty when all weights are 1.
+    pub(crate) fn trigram_weights(&self, token: &str) -> Vec<f32> {
+        if self.prefix_boost == 0 {
+            return Vec::new();
+        }
+        let boost = self.prefix_boost as f32 / 100.0;
+        /* Regular trigrams come first, in the order of their position */
+        (0..self.regular_trigrams(token).len())
+            .map(|position| 1.0 + boost / (position + 1) as f32)
+            .collect()
+    }
+}
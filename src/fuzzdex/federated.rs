@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use super::query::Query;
+use super::seeker::{self, Index, SearchResult};
+
+/// Search over a corpus sharded into many indices, as if it was a single
+/// index.
+///
+/// Each shard is searched on its own and results are merged using the same
+/// final ranking as `Index::search`. Trigram scores and the `scan_cutoff`
+/// leader score are local to each shard: a shard with only weak candidates
+/// compares them to its own best candidate, not to the global one, so
+/// federated search scans at least as deep within each shard as a single
+/// index would. Scores of results from different shards are comparable only
+/// as long as shards have similar trigram statistics. `limit_per_constraint`
+/// is applied within each shard. Shards may share phrase indices; with
+/// `Query::limit_distinct_index` results of the best indices are returned
+/// from all shards, as long as they fit within the limit of each shard.
+pub struct FederatedIndex {
+    pub shards: Vec<Arc<Index>>,
+}
+
+impl FederatedIndex {
+    pub fn new(shards: Vec<Arc<Index>>) -> FederatedIndex {
+        FederatedIndex { shards }
+    }
+
+    /// Create a query for the shards. All shards are expected to share the
+    /// same configuration.
+    ///
+    /// Panics if there are no shards.
+    pub fn query(&self, must: &[&str], should: &[&str]) -> Query {
+        self.shards.first().expect("Federated index has no shards").query(must, should)
+    }
+
+    /// Number of phrases in all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+
+    /// Search all shards and merge their results. With the `parallel`
+    /// feature shards are searched on rayon threads. With
+    /// `Query::fallback_best` the nearest phrase of all shards is returned
+    /// only if no shard has a result within the distance.
+    ///
+    /// Queries created for a different index configuration are normalized
+    /// again, like in `Index::search`.
+    pub fn search(&self, query: &Query) -> Vec<SearchResult<'_>> {
+        if !query.fallback_best {
+            return self.merged_search(query);
+        }
+        /* A shard without matches would return its nearest phrase */
+        let results = self.merged_search(&query.clone().fallback_best(false));
+        if !results.is_empty() || query.max_distance.is_none() {
+            return results;
+        }
+        let nearest = query.clone().fallback_best(false).max_distance(None).limit(Some(1));
+        let mut results = self.merged_search(&nearest);
+        for result in results.iter_mut() {
+            result.within_distance = false;
+        }
+        results
+    }
+
+    /// Search all shards and rank their results together.
+    fn merged_search(&self, query: &Query) -> Vec<SearchResult<'_>> {
+        #[cfg(feature = "parallel")]
+        let shards = self.shards.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let shards = self.shards.iter();
+
+        let mut results: Vec<SearchResult> = shards
+            .map(|shard| shard.search(query))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flatten()
+            .collect();
+        seeker::sort_ranked(query, &mut results);
+        if let Some(distinct) = query.limit_distinct_index {
+            seeker::limit_distinct_index(&mut results, distinct);
+        }
+        results.truncate(query.limit.unwrap_or(usize::MAX));
+        results
+    }
+}
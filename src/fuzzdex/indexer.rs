@@ -1,67 +1,649 @@
 use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+
+use std::io::BufRead;
+use itertools::Itertools;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
-use crate::utils;
 use super::*;
 use super::seeker::*;
 
 impl PhraseEntry {
-    fn new(idx: usize, phrase: &str, constraints: Option<&HashSet<usize, FastHash>>) -> PhraseEntry {
-        let constraints = constraints.map_or_else(
-            || HashSet::with_hasher(FastHash::new()),
-            |c| c.clone()
-        );
-        let phrase_tokens = utils::tokenize(phrase, 1);
+    pub(super) fn new(idx: usize, phrase: &str, config: &IndexerConfig,
+           constraints: HashSet<usize, FastHash>) -> PhraseEntry {
+        let phrase_tokens = config.tokenize(phrase);
 
         PhraseEntry {
             idx,
             origin: phrase.to_string(),
+            normalized_origin: config.normalized_origin.then(|| config.normalize(phrase)),
             tokens: phrase_tokens,
+            fields: Vec::new(),
             constraints,
+            sequence: 0,
+        }
+    }
+
+    /// Create a phrase from tagged fields. Origin joins the field texts with
+    /// spaces.
+    fn with_fields(idx: usize, fields: &[(FieldId, &str)], config: &IndexerConfig,
+                   constraints: Option<&HashSet<usize, FastHash>>) -> PhraseEntry {
+        let origin = fields.iter().map(|(_, text)| *text).join(" ");
+        let constraints = constraints.cloned().unwrap_or_default();
+        let mut entry = PhraseEntry::new(idx, &origin, config, constraints);
+        entry.tokens.clear();
+        for (field, text) in fields {
+            for token in config.tokenize(text) {
+                entry.tokens.push(token);
+                entry.fields.push(*field);
+            }
         }
+        entry
     }
+
+    /// Field of the token.
+    pub(crate) fn field(&self, token_idx: usize) -> FieldId {
+        self.fields.get(token_idx).copied().unwrap_or(DEFAULT_FIELD)
+    }
+}
+
+/// Counts of lines read by `Indexer::add_from_reader`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadStats {
+    /// Phrases added to the index.
+    pub added: usize,
+    /// Lines that couldn't be parsed, or with constraints of the other
+    /// kind than the index uses (see `AddError::MixedConstraints`).
+    pub skipped: usize,
+    /// Lines with already indexed phrase index; they are not added.
+    pub duplicates: usize,
+    /// Lines with a phrase without any tokens; they are not added.
+    pub empty: usize,
+    /// Added phrases that produced no trigrams; see
+    /// `Indexer::unsearchable_phrases`.
+    pub unsearchable: usize,
+}
+
+impl LoadStats {
+    /// Count the outcome of adding a phrase.
+    pub(crate) fn count(&mut self, added: Result<usize, AddError>) {
+        match added {
+            Ok(trigrams) => {
+                self.added += 1;
+                if trigrams == 0 {
+                    self.unsearchable += 1;
+                }
+            }
+            Err(AddError::DuplicateId) => self.duplicates += 1,
+            Err(AddError::EmptyPhrase) => self.empty += 1,
+            Err(AddError::MixedConstraints) => self.skipped += 1,
+            Err(AddError::NoTrigrams) => unreachable!("Only add_phrase_searchable rejects unsearchable phrases"),
+        }
+    }
+}
+
+/// Error of merging shards of `Indexer::add_parallel`, which all share the
+/// configuration of the indexer.
+#[cfg(feature = "parallel")]
+fn merge_add_error(err: MergeError) -> AddError {
+    match err {
+        MergeError::DuplicateId => AddError::DuplicateId,
+        MergeError::MixedConstraints => AddError::MixedConstraints,
+        MergeError::ConfigMismatch => unreachable!("Shards are created with the indexer configuration"),
+    }
+}
+
+/// Score of trigrams that can't be told apart by popularity.
+const NEUTRAL_SCORE: f32 = 0.5;
+
+/// Phrase index, phrase and optional constraints for bulk indexing.
+pub type PhraseItem = (usize, String, Option<HashSet<usize, FastHash>>);
+
+/// Outcome of `Indexer::upsert_phrase`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upsert {
+    /// Phrase index was not yet used.
+    Inserted,
+    /// Phrase with the same index was replaced.
+    Replaced,
 }
 
 impl Indexer {
     /// Create a new empty fuzzdex in an "indexing" state.
     pub fn new() -> Indexer {
+        Indexer::with_config(IndexerConfig::default())
+    }
+
+    /// Create a new empty fuzzdex with custom tokenization settings.
+    pub fn with_config(config: IndexerConfig) -> Indexer {
         Indexer {
             db: HashMap::with_capacity_and_hasher(32768, FastHash::new()),
             phrases: HashMap::with_hasher(FastHash::new()),
+            config,
+            constraint_names: HashMap::with_hasher(FastHash::new()),
+            inserted: 0,
+            spare_positions: Vec::new(),
         }
     }
 
-    fn add_token(&mut self, token: &str, phrase_idx: usize, token_idx: u32) {
-        for trigram in utils::trigramize(token) {
-            let entry = self.db.entry(trigram).or_insert(
-                TrigramEntry { positions: Vec::new(), score: 0.0 }
-            );
+    /// Create a new empty fuzzdex with maps pre-sized for an expected number
+    /// of phrases and distinct trigrams to avoid rehashing on large builds.
+    pub fn with_capacity(phrase_hint: usize, trigram_hint: usize) -> Indexer {
+        let mut indexer = Indexer::new();
+        indexer.phrases.reserve(phrase_hint);
+        indexer.db.reserve(trigram_hint);
+        indexer
+    }
+
+    /// Reserve space for at least `additional` more phrases.
+    pub fn reserve(&mut self, additional: usize) {
+        self.phrases.reserve(additional);
+    }
+
+    /// Remove all phrases, keeping the configuration and the allocated
+    /// capacity of the trigram and phrase maps and of the trigram positions,
+    /// so one indexer can be reused to build many small indices.
+    pub fn clear(&mut self) {
+        for (_trigram, mut entry) in self.db.drain() {
+            entry.positions.clear();
+            self.spare_positions.push(entry.positions);
+        }
+        self.phrases.clear();
+        self.constraint_names.clear();
+        self.inserted = 0;
+    }
+
+    /// Check if a phrase with the index was already added.
+    pub fn contains(&self, phrase_idx: usize) -> bool {
+        self.phrases.contains_key(&phrase_idx)
+    }
+
+    /// Settings used for indexing and querying.
+    pub fn config(&self) -> &IndexerConfig {
+        &self.config
+    }
+
+    /// Check that every trigram position points to an existing phrase token
+    /// and that all scores are finite. Returns all problems found, ordered
+    /// by the trigram. Scans the whole index; meant for tests and debugging.
+    pub fn validate(&self) -> Result<(), Vec<InconsistencyError>> {
+        let mut errors = Vec::new();
+        for (trigram, entry) in self.db.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            if !entry.score.is_finite() {
+                errors.push(InconsistencyError::InvalidScore {
+                    trigram: trigram.clone(),
+                    score: entry.score,
+                });
+            }
+            for position in entry.positions.iter() {
+                let phrase = match self.phrases.get(&position.phrase_idx) {
+                    Some(phrase) => phrase,
+                    None => {
+                        errors.push(InconsistencyError::MissingPhrase {
+                            trigram: trigram.clone(),
+                            phrase_idx: position.phrase_idx,
+                        });
+                        continue;
+                    }
+                };
+                if position.token_idx as usize >= phrase.tokens.len() {
+                    errors.push(InconsistencyError::TokenOutOfBounds {
+                        trigram: trigram.clone(),
+                        phrase_idx: position.phrase_idx,
+                        token_idx: position.token_idx,
+                        tokens: phrase.tokens.len(),
+                    });
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Trigramize a token the same way for indexing and querying.
+    pub(crate) fn trigramize(&self, token: &str) -> Vec<String> {
+        self.config.trigramize(token)
+    }
+
+    /// Index token trigrams and return their count.
+    fn add_token(&mut self, token: &str, phrase_idx: usize, token_idx: u32) -> usize {
+        let trigrams = self.trigramize(token);
+        let count = trigrams.len();
+        let spare_positions = &mut self.spare_positions;
+        for trigram in trigrams {
+            let entry = self.db.entry(trigram).or_insert_with(|| TrigramEntry {
+                positions: spare_positions.pop().unwrap_or_default(),
+                score: 0.0,
+                capped: false,
+            });
             entry.positions.push(Position { phrase_idx, token_idx });
-            entry.score += 1.0;
         }
+        count
+    }
+
+    /// Add phrase and return the number of its indexed trigrams.
+    fn insert_phrase(&mut self, phrase: &str, phrase_idx: usize,
+                     constraints: HashSet<usize, FastHash>) -> Result<usize, AddError> {
+        let entry = PhraseEntry::new(phrase_idx, phrase, &self.config, constraints);
+        self.insert_entry(entry)
+    }
+
+    /// Index tokens of the phrase entry with numeric constraints and return
+    /// the number of its trigrams.
+    pub(super) fn insert_entry(&mut self, entry: PhraseEntry) -> Result<usize, AddError> {
+        self.check_entry(&entry)?;
+        if !entry.constraints.is_empty() && !self.constraint_names.is_empty() {
+            return Err(AddError::MixedConstraints);
+        }
+        Ok(self.index_entry(entry))
+    }
+
+    /// Index tokens of the phrase entry with string constraints and return
+    /// the number of its trigrams. Names are interned only once the entry is
+    /// accepted, so rejected phrases don't use up ids.
+    pub(super) fn insert_named_entry(&mut self, mut entry: PhraseEntry,
+                                     names: &[&str]) -> Result<usize, AddError> {
+        self.check_entry(&entry)?;
+        if !names.is_empty() && self.has_numeric_constraints() {
+            return Err(AddError::MixedConstraints);
+        }
+        entry.constraints = names.iter().map(|name| self.intern_constraint(name)).collect();
+        Ok(self.index_entry(entry))
+    }
+
+    /// Tell if the phrase entry can be inserted, apart from its constraints.
+    fn check_entry(&self, entry: &PhraseEntry) -> Result<(), AddError> {
+        if self.phrases.contains_key(&entry.idx) {
+            return Err(AddError::DuplicateId);
+        }
+        if entry.tokens.is_empty() {
+            return Err(AddError::EmptyPhrase);
+        }
+        Ok(())
+    }
+
+    /// Tell if phrases use numeric constraints. String constraints are
+    /// interned into the same ids, so an index uses only one kind.
+    fn has_numeric_constraints(&self) -> bool {
+        self.constraint_names.is_empty()
+            && self.phrases.values().any(|phrase| !phrase.constraints.is_empty())
+    }
+
+    /// Index tokens of an accepted phrase entry and return the number of its
+    /// trigrams.
+    fn index_entry(&mut self, mut entry: PhraseEntry) -> usize {
+        let phrase_idx = entry.idx;
+        let mut trigrams = 0;
+        for (token_idx, token) in entry.tokens.iter().enumerate() {
+            trigrams += self.add_token(token, phrase_idx, token_idx as u32);
+        }
+        entry.sequence = self.inserted;
+        self.inserted += 1;
+        self.phrases.insert(phrase_idx, entry);
+        trigrams
     }
 
     /// Add a phrase mapped to an index. Phrase can be found by one of it's
     /// fuzzy-matched tokens. Phrase index must be unique within the index (or
-    /// Err is returned) and can reference some external dictionary.
+    /// Err is returned) and can reference some external dictionary. Phrases
+    /// without any tokens (eg. "- . ,") are rejected with
+    /// `AddError::EmptyPhrase`.
     pub fn add_phrase(&mut self, phrase: &str, phrase_idx: usize,
-                      constraints: Option<&HashSet<usize, FastHash>>) -> Result<(), DuplicateId> {
+                      constraints: Option<&HashSet<usize, FastHash>>) -> Result<(), AddError> {
+        self.add_phrase_owned(phrase, phrase_idx, constraints.cloned().unwrap_or_default())
+    }
+
+    /// Add a phrase like `add_phrase`, moving the constraints into the index
+    /// instead of cloning them.
+    pub fn add_phrase_owned(&mut self, phrase: &str, phrase_idx: usize,
+                            constraints: HashSet<usize, FastHash>) -> Result<(), AddError> {
+        self.insert_phrase(phrase, phrase_idx, constraints).map(|_trigrams| ())
+    }
+
+    /// Tell if the phrase would be added, without adding it: the index has
+    /// to be unused and the phrase has to produce some trigrams.
+    pub fn check_phrase(&self, phrase: &str, phrase_idx: usize) -> Result<(), AddError> {
         if self.phrases.contains_key(&phrase_idx) {
-            Err(DuplicateId {})
+            return Err(AddError::DuplicateId);
+        }
+        let tokens = self.config.tokenize(phrase);
+        if tokens.is_empty() {
+            return Err(AddError::EmptyPhrase);
+        }
+        if tokens.iter().all(|token| self.trigramize(token).is_empty()) {
+            return Err(AddError::NoTrigrams);
+        }
+        Ok(())
+    }
+
+    /// Add a phrase like `add_phrase`, but reject phrases that could never
+    /// be found with `AddError::NoTrigrams` instead of adding them.
+    pub fn add_phrase_searchable(&mut self, phrase: &str, phrase_idx: usize,
+                                 constraints: Option<&HashSet<usize, FastHash>>) -> Result<(), AddError> {
+        self.check_phrase(phrase, phrase_idx)?;
+        self.add_phrase(phrase, phrase_idx, constraints)
+    }
+
+    /// Add a phrase with string constraints (eg. region codes). Strings are
+    /// interned into internal ids (see `constraint_id`) and queried with
+    /// `Query::constraint_str`. Ids are assigned from 0, so an index uses
+    /// either numeric or string constraints: mixing them is rejected with
+    /// `AddError::MixedConstraints`.
+    pub fn add_phrase_str_constraints(&mut self, phrase: &str, phrase_idx: usize,
+                                      constraints: &[&str]) -> Result<(), AddError> {
+        let entry = PhraseEntry::new(phrase_idx, phrase, &self.config, HashSet::default());
+        self.insert_named_entry(entry, constraints).map(|_trigrams| ())
+    }
+
+    /// Internal id of the string constraint, assigning a new one if needed.
+    pub(super) fn intern_constraint(&mut self, name: &str) -> usize {
+        let next_id = self.constraint_names.len();
+        *self.constraint_names.entry(name.to_string()).or_insert(next_id)
+    }
+
+    /// Internal id of an interned string constraint.
+    pub fn constraint_id(&self, name: &str) -> Option<usize> {
+        self.constraint_names.get(name).copied()
+    }
+
+    /// Add a phrase made of several fields (eg. name, city, category). Each
+    /// token remembers its field, so queries can match the must or should
+    /// tokens against a single field only (`Query::must_field`).
+    pub fn add_phrase_fields(&mut self, fields: &[(FieldId, &str)], phrase_idx: usize,
+                             constraints: Option<&HashSet<usize, FastHash>>) -> Result<(), AddError> {
+        let entry = PhraseEntry::with_fields(phrase_idx, fields, &self.config, constraints);
+        self.insert_entry(entry).map(|_trigrams| ())
+    }
+
+    /// Indices of phrases that produced no trigrams and can't be found by any
+    /// search, e.g. phrases made of separators or combining marks only.
+    /// Ordered by phrase index.
+    pub fn unsearchable_phrases(&self) -> Vec<usize> {
+        self.phrases
+            .values()
+            .filter(|phrase| phrase.tokens.iter().all(|token| self.trigramize(token).is_empty()))
+            .map(|phrase| phrase.idx)
+            .sorted()
+            .collect()
+    }
+
+    /// Remove a phrase with all its trigram positions. Returns false if there
+    /// was no such phrase.
+    pub fn remove_phrase(&mut self, phrase_idx: usize) -> bool {
+        let entry = match self.phrases.remove(&phrase_idx) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        for token in entry.tokens.iter() {
+            for trigram in self.config.trigramize(token) {
+                /* Repeated trigrams were already purged on the first occurrence */
+                if let Some(trigram_entry) = self.db.get_mut(&trigram) {
+                    trigram_entry.positions.retain(|position| position.phrase_idx != phrase_idx);
+                    if trigram_entry.positions.is_empty() {
+                        self.db.remove(&trigram);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Remove all phrases with the constraint (eg. of a retired data source)
+    /// and their trigram positions. Returns the number of removed phrases.
+    /// Scores are stale until `rescore` or `finish`.
+    pub fn remove_by_constraint(&mut self, constraint: usize) -> usize {
+        let removed: HashSet<usize, FastHash> = self.phrases
+            .values()
+            .filter(|phrase| phrase.constraints.contains(&constraint))
+            .map(|phrase| phrase.idx)
+            .collect();
+        let mut trigrams: HashSet<String, FastHash> = HashSet::with_hasher(FastHash::new());
+        for phrase_idx in removed.iter() {
+            let entry = self.phrases.remove(phrase_idx).expect("Phrase was just found");
+            for token in entry.tokens.iter() {
+                trigrams.extend(self.config.trigramize(token));
+            }
+        }
+        /* Purge each affected trigram once instead of once per phrase */
+        for trigram in trigrams {
+            if let Some(trigram_entry) = self.db.get_mut(&trigram) {
+                trigram_entry.positions.retain(|position| !removed.contains(&position.phrase_idx));
+                if trigram_entry.positions.is_empty() {
+                    self.db.remove(&trigram);
+                }
+            }
+        }
+        removed.len()
+    }
+
+    /// Add a phrase, replacing the existing one with the same index. An
+    /// empty phrase is rejected and the existing one is kept.
+    pub fn upsert_phrase(&mut self, phrase: &str, phrase_idx: usize,
+                         constraints: Option<&HashSet<usize, FastHash>>) -> Result<Upsert, AddError> {
+        let constraints = constraints.cloned().unwrap_or_default();
+        let entry = PhraseEntry::new(phrase_idx, phrase, &self.config, constraints);
+        if entry.tokens.is_empty() {
+            return Err(AddError::EmptyPhrase);
+        }
+        if !entry.constraints.is_empty() && !self.constraint_names.is_empty() {
+            return Err(AddError::MixedConstraints);
+        }
+        let replaced = self.remove_phrase(phrase_idx);
+        self.insert_entry(entry).expect("Phrase index was just removed");
+        if replaced {
+            Ok(Upsert::Replaced)
         } else {
-            let entry = PhraseEntry::new(phrase_idx, phrase, constraints);
-            for (token_idx, token) in entry.tokens.iter().enumerate() {
-                self.add_token(token, phrase_idx, token_idx as u32);
+            Ok(Upsert::Inserted)
+        }
+    }
+
+    /// Move all phrases of another indexer into this one. Fails without
+    /// modifying anything if both contain the same phrase index. Merged
+    /// phrases count as inserted after the phrases of this indexer.
+    /// Indexers created with different configurations can't be merged.
+    pub fn merge(&mut self, other: Indexer) -> Result<(), MergeError> {
+        if self.config != other.config {
+            return Err(MergeError::ConfigMismatch);
+        }
+        if other.phrases.keys().any(|phrase_idx| self.phrases.contains_key(phrase_idx)) {
+            return Err(MergeError::DuplicateId);
+        }
+        if !self.constraint_names.is_empty() && other.has_numeric_constraints()
+            || !other.constraint_names.is_empty() && self.has_numeric_constraints() {
+            return Err(MergeError::MixedConstraints);
+        }
+        /* Interned ids of the other indexer may mean different strings here */
+        let remap: HashMap<usize, usize, FastHash> = other.constraint_names
+            .iter()
+            .map(|(name, &other_id)| (other_id, self.intern_constraint(name)))
+            .filter(|(other_id, id)| other_id != id)
+            .collect();
+        for (trigram, other_entry) in other.db {
+            match self.db.entry(trigram) {
+                Entry::Occupied(mut occupied) => {
+                    let entry = occupied.get_mut();
+                    entry.positions.extend(other_entry.positions);
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(other_entry);
+                }
             }
-            self.phrases.insert(phrase_idx, entry);
-            Ok(())
         }
+        /* Merged phrases count as inserted after the present ones */
+        for (phrase_idx, mut phrase) in other.phrases {
+            if !remap.is_empty() {
+                phrase.constraints = phrase.constraints
+                    .iter()
+                    .map(|constraint| remap.get(constraint).copied().unwrap_or(*constraint))
+                    .collect();
+            }
+            phrase.sequence += self.inserted;
+            self.phrases.insert(phrase_idx, phrase);
+        }
+        self.inserted += other.inserted;
+        Ok(())
+    }
+
+    /// Build an indexer from phrases tokenized and trigramized on all rayon
+    /// threads. Positions are sorted by `finish`, so the finished index
+    /// doesn't depend on the number of threads nor on the order of phrases.
+    #[cfg(feature = "parallel")]
+    pub fn build_parallel<I>(items: I) -> Result<Indexer, AddError>
+    where I: IntoParallelIterator<Item = PhraseItem> {
+        let mut indexer = Indexer::new();
+        indexer.add_parallel(items)?;
+        Ok(indexer)
+    }
+
+    /// Add phrases in parallel into per-thread shards and merge them into
+    /// this indexer. Insertion order of the added phrases is unspecified.
+    #[cfg(feature = "parallel")]
+    pub fn add_parallel<I>(&mut self, items: I) -> Result<(), AddError>
+    where I: IntoParallelIterator<Item = PhraseItem> {
+        /* Shards start small; rayon creates many of them */
+        let shard = || Indexer {
+            db: HashMap::with_hasher(FastHash::new()),
+            phrases: HashMap::with_hasher(FastHash::new()),
+            config: self.config.clone(),
+            constraint_names: HashMap::with_hasher(FastHash::new()),
+            inserted: 0,
+            spare_positions: Vec::new(),
+        };
+        let merged = items
+            .into_par_iter()
+            .try_fold(shard, |mut indexer, (phrase_idx, phrase, constraints)| {
+                indexer.add_phrase_owned(&phrase, phrase_idx, constraints.unwrap_or_default())?;
+                Ok::<_, AddError>(indexer)
+            })
+            .try_reduce(shard, |mut indexer, other| {
+                indexer.merge(other).map_err(merge_add_error)?;
+                Ok(indexer)
+            })?;
+        self.merge(merged).map_err(merge_add_error)
+    }
+
+    /// Release slack memory of the growing containers and sort trigram
+    /// positions by phrase for better locality during scans. Called by
+    /// `finish`; useful when keeping an Indexer around for a long time.
+    pub fn compact(&mut self) {
+        for entry in self.db.values_mut() {
+            entry.positions.sort_by_key(|position| (position.phrase_idx, position.token_idx));
+            entry.positions.shrink_to_fit();
+        }
+        for phrase in self.phrases.values_mut() {
+            phrase.tokens.shrink_to_fit();
+            phrase.fields.shrink_to_fit();
+            phrase.constraints.shrink_to_fit();
+        }
+        self.db.shrink_to_fit();
+        self.phrases.shrink_to_fit();
+        self.spare_positions = Vec::new();
+    }
+
+    /// Rough estimate of the heap memory allocated by the index data in bytes.
+    pub fn heap_size(&self) -> usize {
+        use std::mem::size_of;
+        let db_size = self.db.capacity() * size_of::<(String, TrigramEntry)>()
+            + self.db.iter()
+            .map(|(trigram, entry)| trigram.capacity()
+                 + entry.positions.capacity() * size_of::<Position>())
+            .sum::<usize>();
+        let phrases_size = self.phrases.capacity() * size_of::<(usize, PhraseEntry)>()
+            + self.phrases.values()
+            .map(|phrase| phrase.origin.capacity()
+                 + phrase.normalized_origin.as_ref().map_or(0, |origin| origin.capacity())
+                 + phrase.tokens.capacity() * size_of::<String>()
+                 + phrase.tokens.iter().map(|token| token.capacity()).sum::<usize>()
+                 + phrase.fields.capacity() * size_of::<FieldId>()
+                 + phrase.constraints.capacity() * size_of::<usize>())
+            .sum::<usize>();
+        let names_size = self.constraint_names.capacity() * size_of::<(String, usize)>()
+            + self.constraint_names.keys().map(|name| name.capacity()).sum::<usize>();
+        let spare_size = self.spare_positions.capacity() * size_of::<Vec<Position>>()
+            + self.spare_positions.iter()
+            .map(|positions| positions.capacity() * size_of::<Position>())
+            .sum::<usize>();
+        db_size + phrases_size + names_size + spare_size
+    }
+
+    /// Add phrases read line by line, without keeping the whole input in the
+    /// memory. `parse` converts a line into a phrase index and phrase or
+    /// returns None to skip it. Duplicated phrase indices are counted and
+    /// skipped instead of aborting the load.
+    pub fn add_from_reader<R: BufRead>(&mut self, reader: R,
+                                       parse: impl Fn(&str) -> Option<(usize, String)>)
+                                       -> std::io::Result<LoadStats> {
+        let mut stats = LoadStats::default();
+        for line in reader.lines() {
+            match parse(&line?) {
+                Some((phrase_idx, phrase)) => {
+                    stats.count(self.insert_phrase(&phrase, phrase_idx, HashSet::default()));
+                }
+                None => stats.skipped += 1,
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Add phrases from tab separated lines: `phrase_idx<TAB>phrase`.
+    pub fn add_from_tsv<R: BufRead>(&mut self, reader: R) -> std::io::Result<LoadStats> {
+        self.add_from_reader(reader, |line| {
+            let (phrase_idx, phrase) = line.split_once('\t')?;
+            let phrase_idx = phrase_idx.trim().parse().ok()?;
+            Some((phrase_idx, phrase.to_string()))
+        })
     }
 
     /// Consume original Indexer and return Index class with querying ability
     /// and given internal cache size.
-    pub fn finish_with_cache(mut self, cache_size: usize) -> Index {
+    pub fn finish_with_cache(self, cache_size: usize) -> Index {
+        self.finish_with_params(cache_size, ScoringParams::default())
+    }
+
+    /// Consume original Indexer and return queryable Index with heatmap cache
+    /// limited to estimated `max_bytes` of memory (see `Index::with_byte_cache`).
+    pub fn finish_with_byte_cache(self, max_bytes: usize) -> Index {
+        self.finish_with_cache(1).with_byte_cache(max_bytes)
+    }
+
+    /// Consume original Indexer and return queryable Index using custom
+    /// trigram scoring curve.
+    pub fn finish_with_params(self, cache_size: usize, params: ScoringParams) -> Index {
+        self.finish_scoring(cache_size, params, |_processed, _total| {})
+    }
+
+    /// Finish with default scoring, calling `progress(processed, total)`
+    /// every few thousand trigrams of the scoring pass and once at its end.
+    /// With the `parallel` feature trigrams are scored on rayon threads in
+    /// chunks of 16384, and progress is reported between the chunks.
+    pub fn finish_with_progress(self, cache_size: usize,
+                                progress: impl FnMut(usize, usize)) -> Index {
+        self.finish_scoring(cache_size, ScoringParams::default(), progress)
+    }
+
+    /// Recompute scores of all trigrams from the current number of their
+    /// positions with default scoring params. Scores are otherwise stale
+    /// after adding or removing phrases; `finish` rescores internally, so
+    /// it's needed only to inspect scores during incremental editing.
+    pub fn rescore(&mut self) {
+        self.rescore_with_params(ScoringParams::default());
+    }
+
+    /// Recompute scores of all trigrams with custom scoring params. With the
+    /// `parallel` feature trigrams are scored on rayon threads.
+    pub fn rescore_with_params(&mut self, params: ScoringParams) {
+        self.score_trigrams(params, |_processed, _total| {});
+    }
+
+    /// Scoring pass over all trigrams, reporting progress.
+    fn score_trigrams(&mut self, params: ScoringParams, mut progress: impl FnMut(usize, usize)) {
         if self.db.is_empty() {
-            return Index::new(self, cache_size);
+            progress(0, 0);
+            return;
         }
 
         /*
@@ -84,33 +666,115 @@ impl Indexer {
          * 0.5 + tanh(5.0 * (avg - val - 1) / max)/2
          * Will have around 0.5 at average, max 1. Distinguish all lower values
          * (higher score), and will distinguish plenty of higher values.
+         *
+         * 5.0 (steepness) and 1 (center offset) can be altered with ScoringParams.
          */
 
         let average: f32 = self.db.values()
             .map(|v| v.positions.len())
             .sum::<usize>() as f32 / self.db.len() as f32;
 
-        let max: usize = self.db
+        let (min, max) = self.db
             .values()
             .map(|v| v.positions.len())
-            .max_by_key(|val| *val)
-            .unwrap_or(1);
+            .minmax()
+            .into_option()
+            .unwrap_or((1, 1));
+        /* Empty positions are never stored, but don't divide by zero anyway */
+        let max = max.max(1);
 
-        for (_trigram, entry) in self.db.iter_mut() {
-            let popularity = entry.score;
-            let centered = average - popularity - 1.0;
-            let ranged = 5.0 * centered / (max as f32);
+        /* With a single distinct trigram, or all trigrams equally popular,
+         * there's nothing to tell them apart - the curve would put them all
+         * at an arbitrary point depending on the center offset only. */
+        let uniform = min == max;
+
+        /* Each score depends only on the trigram and the precomputed stats */
+        let score = |entry: &TrigramEntry| -> f32 {
+            if uniform {
+                return NEUTRAL_SCORE;
+            }
+            let popularity = entry.positions.len().max(params.min_occurrences) as f32;
+            let centered = average - popularity - params.center_offset;
+            let ranged = params.steepness * centered / (max as f32);
             let zero_to_one = 0.5 + (ranged).tanh() / 2.0;
-            let score = zero_to_one;
-            entry.score = score;
+            /* Keep odd scoring params from leaking NaNs into rankings */
+            if zero_to_one.is_finite() {
+                zero_to_one.clamp(0.0, 1.0)
+            } else {
+                NEUTRAL_SCORE
+            }
+        };
+
+        let total = self.db.len();
+        #[cfg(not(feature = "parallel"))]
+        {
+            const PROGRESS_STEP: usize = 4096;
+            for (processed, entry) in self.db.values_mut().enumerate() {
+                if processed % PROGRESS_STEP == 0 {
+                    progress(processed, total);
+                }
+                entry.score = score(entry);
+            }
+        }
+        /* Chunks are scored on rayon threads, progress is reported between
+         * them from the calling thread */
+        #[cfg(feature = "parallel")]
+        {
+            const PROGRESS_STEP: usize = 16384;
+            let mut entries: Vec<&mut TrigramEntry> = self.db.values_mut().collect();
+            for (chunk_idx, chunk) in entries.chunks_mut(PROGRESS_STEP).enumerate() {
+                progress(chunk_idx * PROGRESS_STEP, total);
+                chunk.par_iter_mut().for_each(|entry| entry.score = score(entry));
+            }
+        }
+        progress(total, total);
+    }
+
+    /// Finish with custom scoring and a progress callback.
+    pub(crate) fn finish_scoring(mut self, cache_size: usize, params: ScoringParams,
+                      progress: impl FnMut(usize, usize)) -> Index {
+        self.compact();
+        self.score_trigrams(params, progress);
+
+        if let Some(max_positions) = params.max_positions {
+            let phrases = &self.phrases;
+            for entry in self.db.values_mut() {
+                if entry.positions.len() <= max_positions {
+                    continue;
+                }
+                /* Trigram of a short token is more significant */
+                entry.positions.sort_by_key(|position| {
+                    let token = &phrases[&position.phrase_idx].tokens[position.token_idx as usize];
+                    (token.len(), position.phrase_idx, position.token_idx)
+                });
+                entry.positions.truncate(max_positions);
+                entry.positions.sort_by_key(|position| (position.phrase_idx, position.token_idx));
+                entry.positions.shrink_to_fit();
+                entry.capped = true;
+            }
         }
         Index::new(self, cache_size)
     }
 
-    /// Consume original Indexer and return Index class with querying ability and default cache
-    /// size of 500 entries (in our testcases 1000 entries is enough to have < 1% misses).
+    /// Consume original Indexer and return Index class with querying ability and the
+    /// `default_cache_size` heatmap cache.
     pub fn finish(self) -> Index {
-        self.finish_with_cache(2000)
+        let cache_size = self.default_cache_size();
+        self.finish_with_cache(cache_size)
+    }
+
+    /// Heatmap cache size used by `finish`: a tenth of the number of distinct
+    /// phrase tokens, clamped to 500-20000 entries. Must tokens of real
+    /// queries repeat, so a fraction of the vocabulary gives a good hit rate
+    /// (in our testcases 1000 entries were enough for < 1% misses). Indices
+    /// of fewer than 5000 distinct tokens get the 500 entries minimum, which
+    /// can exceed their vocabulary; misspelled must tokens are cached too.
+    pub fn default_cache_size(&self) -> usize {
+        let tokens: HashSet<&str, FastHash> = self.phrases
+            .values()
+            .flat_map(|phrase| phrase.tokens.iter().map(|token| token.as_str()))
+            .collect();
+        (tokens.len() / 10).clamp(500, 20_000)
     }
 }
 
@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use itertools::Itertools;
+
+use super::{Indexer, PhraseEntry, FieldId, FastHash, AddError};
+use super::indexer::LoadStats;
+use super::seeker::Index;
+
+/* JSON lines format of phrases, one object per line:
+ *
+ *   {"idx":1,"origin":"Warszawa Centralna","tokens":["warszawa","centralna"],"constraints":[1,2]}
+ *
+ * Constraints are numbers, or strings for interned string constraints.
+ * Optional "fields" list the field of each token of phrases added with
+ * fields. It's parsed with a small built-in parser, as the crate doesn't
+ * depend on a JSON library; lines nested deeper than `MAX_DEPTH` are
+ * rejected. */
+
+/// Parsed JSON value. Numbers are kept as text, so large indices don't lose
+/// precision.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match self {
+            Value::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Deepest nesting of arrays and objects accepted by the parser. Phrase
+/// records nest only two levels; the limit keeps malformed input from
+/// overflowing the stack of the recursive parser.
+const MAX_DEPTH: usize = 16;
+
+/// Recursive descent parser of a single JSON document.
+struct Parser<'a> {
+    text: &'a str,
+    offset: usize,
+    /// Arrays and objects currently open.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parse the whole text as a single value.
+    fn parse(text: &'a str) -> Option<Value> {
+        let mut parser = Parser { text, offset: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        (parser.offset == text.len()).then_some(value)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.offset += ch.len_utf8();
+        Some(ch)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.offset += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn expect(&mut self, literal: &str) -> Option<()> {
+        self.rest().starts_with(literal).then(|| self.offset += literal.len())
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_whitespace();
+        match self.peek()? {
+            'n' => self.expect("null").map(|_| Value::Null),
+            't' => self.expect("true").map(|_| Value::Bool(true)),
+            'f' => self.expect("false").map(|_| Value::Bool(false)),
+            '"' => self.string().map(Value::String),
+            '[' | '{' if self.depth >= MAX_DEPTH => None,
+            '[' => {
+                self.depth += 1;
+                let array = self.array();
+                self.depth -= 1;
+                array
+            }
+            '{' => {
+                self.depth += 1;
+                let object = self.object();
+                self.depth -= 1;
+                object
+            }
+            '-' | '0'..='9' => self.number(),
+            _ => None,
+        }
+    }
+
+    fn number(&mut self) -> Option<Value> {
+        let rest = self.rest();
+        let length = rest
+            .find(|ch: char| !(ch.is_ascii_digit() || matches!(ch, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(rest.len());
+        let number = &rest[..length];
+        number.parse::<f64>().ok()?;
+        self.offset += length;
+        Some(Value::Number(number.to_string()))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect("\"")?;
+        let mut string = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Some(string),
+                '\\' => {
+                    let ch = match self.next()? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return None,
+                    };
+                    string.push(ch);
+                }
+                ch if ch < ' ' => return None,
+                ch => string.push(ch),
+            }
+        }
+    }
+
+    /// Character of a \uXXXX escape, combining UTF-16 surrogate pairs.
+    fn unicode_escape(&mut self) -> Option<char> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high);
+        }
+        self.expect("\\u")?;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.rest().get(..4)?;
+        let code = u32::from_str_radix(digits, 16).ok()?;
+        self.offset += 4;
+        Some(code)
+    }
+
+    fn array(&mut self) -> Option<Value> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.expect("]").is_some() {
+            return Some(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                ']' => return Some(Value::Array(items)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn object(&mut self) -> Option<Value> {
+        self.expect("{")?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.expect("}").is_some() {
+            return Some(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let name = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            members.push((name, self.value()?));
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => continue,
+                '}' => return Some(Value::Object(members)),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Append a JSON string literal.
+fn write_string(out: &mut String, string: &str) {
+    out.push('"');
+    for ch in string.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if ch < ' ' => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+/// Constraint of an imported phrase.
+enum Constraint {
+    Id(usize),
+    Name(String),
+}
+
+/// Phrase read from a JSON line.
+struct PhraseRecord {
+    idx: usize,
+    origin: String,
+    tokens: Option<Vec<String>>,
+    fields: Vec<FieldId>,
+    constraints: Vec<Constraint>,
+}
+
+impl PhraseRecord {
+    fn parse(line: &str) -> Option<PhraseRecord> {
+        let value = Parser::parse(line)?;
+        let idx = value.get("idx")?.as_usize()?;
+        let origin = value.get("origin")?.as_str()?.to_string();
+        let tokens = match value.get("tokens") {
+            None | Some(Value::Null) => None,
+            Some(tokens) => Some(tokens.as_array()?
+                .iter()
+                .map(|token| token.as_str().map(|token| token.to_string()))
+                .collect::<Option<Vec<String>>>()?),
+        };
+        let fields = match value.get("fields") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(fields) => fields.as_array()?
+                .iter()
+                .map(|field| field.as_usize().and_then(|field| FieldId::try_from(field).ok()))
+                .collect::<Option<Vec<FieldId>>>()?,
+        };
+        /* Fields annotate the given tokens */
+        if !fields.is_empty() && tokens.as_ref().is_none_or(|tokens| tokens.len() != fields.len()) {
+            return None;
+        }
+        let constraints = match value.get("constraints") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(constraints) => constraints.as_array()?
+                .iter()
+                .map(|constraint| match constraint {
+                    Value::String(name) => Some(Constraint::Name(name.clone())),
+                    other => other.as_usize().map(Constraint::Id),
+                })
+                .collect::<Option<Vec<Constraint>>>()?,
+        };
+        Some(PhraseRecord { idx, origin, tokens, fields, constraints })
+    }
+}
+
+impl Indexer {
+    /// Add phrases from JSON lines written by `Index::export_jsonl` or
+    /// generated by other tools: objects with an `idx`, `origin`, optional
+    /// `tokens`, `fields` of the tokens and `constraints` (numbers, or
+    /// strings added like with `add_phrase_str_constraints`). Given tokens
+    /// are indexed as they are, so they should be normalized like the index
+    /// does; without them the origin is tokenized. Lines which can't be
+    /// parsed are counted as skipped, like in `add_from_reader`, and so are
+    /// phrases mixing numeric and string constraints.
+    pub fn import_jsonl<R: BufRead>(&mut self, reader: R) -> std::io::Result<LoadStats> {
+        let mut stats = LoadStats::default();
+        for line in reader.lines() {
+            let record = match PhraseRecord::parse(&line?) {
+                Some(record) => record,
+                None => {
+                    stats.skipped += 1;
+                    continue;
+                }
+            };
+            let mut ids: HashSet<usize, FastHash> = HashSet::default();
+            let mut names: Vec<&str> = Vec::new();
+            for constraint in record.constraints.iter() {
+                match constraint {
+                    Constraint::Id(id) => {
+                        ids.insert(*id);
+                    }
+                    Constraint::Name(name) => names.push(name),
+                }
+            }
+            let mut entry = PhraseEntry::new(record.idx, &record.origin, &self.config, ids);
+            if let Some(tokens) = record.tokens {
+                entry.tokens = tokens;
+                entry.fields = record.fields;
+            }
+            let added = if names.is_empty() {
+                self.insert_entry(entry)
+            } else if entry.constraints.is_empty() {
+                self.insert_named_entry(entry, &names)
+            } else {
+                Err(AddError::MixedConstraints)
+            };
+            stats.count(added);
+        }
+        Ok(stats)
+    }
+}
+
+impl Index {
+    /// Write all phrases as JSON lines (see `Indexer::import_jsonl`), in the
+    /// order they were inserted. Unlike `save` the format doesn't depend on
+    /// the internal layout: it can be inspected, diffed or generated by
+    /// other tools. Trigrams and scores are not written; they are rebuilt
+    /// by `finish` of the importing indexer.
+    pub fn export_jsonl<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let names: HashMap<usize, &str, FastHash> = self.index.constraint_names
+            .iter()
+            .map(|(name, &id)| (id, name.as_str()))
+            .collect();
+        let mut line = String::new();
+        for phrase in self.index.phrases.values().sorted_by_key(|phrase| (phrase.sequence, phrase.idx)) {
+            line.clear();
+            line.push_str(&format!("{{\"idx\":{},\"origin\":", phrase.idx));
+            write_string(&mut line, &phrase.origin);
+            line.push_str(",\"tokens\":[");
+            for (position, token) in phrase.tokens.iter().enumerate() {
+                if position > 0 {
+                    line.push(',');
+                }
+                write_string(&mut line, token);
+            }
+            line.push(']');
+            if !phrase.fields.is_empty() {
+                line.push_str(&format!(",\"fields\":[{}]", phrase.fields.iter().join(",")));
+            }
+            line.push_str(",\"constraints\":[");
+            for (position, constraint) in phrase.constraints.iter().sorted().enumerate() {
+                if position > 0 {
+                    line.push(',');
+                }
+                match names.get(constraint) {
+                    Some(name) => write_string(&mut line, name),
+                    None => line.push_str(&constraint.to_string()),
+                }
+            }
+            line.push_str("]}\n");
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()
+    }
+}
+
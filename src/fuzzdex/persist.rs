@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::Indexer;
+use super::seeker::Index;
+
+/// File header marking a saved fuzzdex.
+const MAGIC: &[u8; 8] = b"FUZZDEX\0";
+
+/// Bumped whenever the serialized layout of the Indexer changes.
+const FORMAT_VERSION: u32 = 12;
+
+/// Error while saving or loading an index.
+#[derive(Debug)]
+pub enum PersistError {
+    /// File can't be read or written.
+    Io(std::io::Error),
+    /// File is not a fuzzdex index.
+    NotAnIndex,
+    /// Index was saved by an incompatible fuzzdex version.
+    Version { found: u32, expected: u32 },
+    /// Data is truncated or otherwise malformed.
+    Corrupted(String),
+}
+
+impl std::fmt::Display for PersistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PersistError::Io(err) => write!(f, "I/O error: {}", err),
+            PersistError::NotAnIndex => write!(f, "File is not a fuzzdex index"),
+            PersistError::Version { found, expected } =>
+                write!(f, "Unsupported index format version {} (expected {})", found, expected),
+            PersistError::Corrupted(reason) => write!(f, "Corrupted index data: {}", reason),
+        }
+    }
+}
+impl std::error::Error for PersistError {}
+
+impl From<std::io::Error> for PersistError {
+    fn from(err: std::io::Error) -> Self {
+        PersistError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for PersistError {
+    fn from(err: bincode::Error) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                PersistError::Corrupted("unexpected end of file".to_string()),
+            bincode::ErrorKind::Io(err) => PersistError::Io(err),
+            other => PersistError::Corrupted(other.to_string()),
+        }
+    }
+}
+
+impl Index {
+    /// Write the finished index (with its trigram scores) to a file. Cache is
+    /// not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PersistError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &self.index)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read index saved with `save`, skipping the indexing phase entirely.
+    pub fn load<P: AsRef<Path>>(path: P, cache_size: usize) -> Result<Index, PersistError> {
+        Index::load_with_cache_size(path, Some(cache_size))
+    }
+
+    /// Like `load`, but without a cache size the heatmap cache is sized by
+    /// `Indexer::default_cache_size`, like in `finish`.
+    pub fn load_with_cache_size<P: AsRef<Path>>(path: P, cache_size: Option<usize>)
+                                                -> Result<Index, PersistError> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        let mut version = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(PersistError::NotAnIndex);
+        }
+        reader.read_exact(&mut version)
+            .map_err(|_| PersistError::Corrupted("missing format version".to_string()))?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(PersistError::Version { found: version, expected: FORMAT_VERSION });
+        }
+
+        let indexer: Indexer = bincode::deserialize_from(&mut reader)?;
+        let cache_size = cache_size.unwrap_or_else(|| indexer.default_cache_size());
+        Ok(Index::new(indexer, cache_size))
+    }
+}
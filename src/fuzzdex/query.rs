@@ -1,77 +1,776 @@
-use crate::utils;
+use std::sync::Arc;
+use unicode_segmentation::UnicodeSegmentation;
 
-#[derive(Debug)]
+use super::{IndexerConfig, MustTooShort, MultiTokenMust, FieldId};
+use super::seeker::{SearchResult, OrderedF32};
+
+/// Edit metric used to compare must token with phrase tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Metric {
+    /// Levenshtein distance over graphemes. `max_distance` is a number of edits.
+    #[default]
+    Levenshtein,
+    /// Jaro-Winkler similarity, better for short names with swapped letters.
+    /// Reported `distance` is `round(100 * (1 - similarity))`, hence
+    /// `max_distance` is a similarity threshold in percent: 10 accepts tokens
+    /// with similarity of at least 0.9.
+    JaroWinkler,
+}
+
+/// Similarity of the must token to phrase tokens used to order the scan and
+/// reported as the result `score`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Similarity {
+    /// Sum of scores of the shared trigrams. Favors long tokens, which
+    /// simply have more trigrams.
+    #[default]
+    SummedScore,
+    /// Score of the shared trigrams divided by the size of the union of
+    /// the must and token trigram sets, normalizing for the token length.
+    Jaccard,
+}
+
+/// Order of results with equal distances and scores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Tiebreak {
+    /// Shorter phrases first, then by the phrase text and index.
+    #[default]
+    Origin,
+    /// By the phrase index.
+    Index,
+    /// Phrases added to the indexer earlier first. Upserted phrases count
+    /// as added at the time of the upsert.
+    InsertionOrder,
+}
+
+/// How the must token is compared with phrase tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SearchMode {
+    /// Whole tokens are compared using the query `Metric`.
+    #[default]
+    Token,
+    /// The must token may match any contiguous part of a longer phrase
+    /// token, eg. "straat" matches "beethovenstraat" with distance 0.
+    /// Distance is the Levenshtein distance of the best matching part; the
+    /// metric and transpositions are ignored. Slower than `Token`.
+    SubToken,
+}
+
+/// Which token represents a phrase when several of its tokens match the must.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TokenPreference {
+    /// First token within the distance by decreasing trigram score; shorter
+    /// tokens first for equal scores.
+    #[default]
+    Score,
+    /// Token with the lowest distance; for equal distances the one with
+    /// length closest to the must token, then by trigram score. Computes the
+    /// distance to all candidate tokens of a phrase.
+    ClosestLength,
+}
+
+/// What to do with a must token which the index tokenizer splits into many
+/// tokens, eg. "new york".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MustSplit {
+    /// The longest token is the must and others are moved to should tokens,
+    /// so "new" of "new york" is optional.
+    #[default]
+    LongestAsMust,
+    /// The longest token is the must and others are added to the additional
+    /// must tokens, so all of them have to match.
+    AllMust,
+    /// Reject the must token with `MultiTokenMust`.
+    Error,
+}
+
+/// Custom rank of a result set with `Query::rank_fn`; higher is better.
+#[derive(Clone)]
+pub struct RankFn(Arc<dyn Fn(&SearchResult) -> f64 + Send + Sync>);
+
+impl RankFn {
+    pub fn rank(&self, result: &SearchResult) -> f64 {
+        (self.0)(result)
+    }
+}
+
+/* Query stays unwind safe like before; a panicking rank function can't leave
+ * the index in an inconsistent state */
+impl std::panic::UnwindSafe for RankFn {}
+impl std::panic::RefUnwindSafe for RankFn {}
+
+impl std::fmt::Debug for RankFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RankFn")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Query {
-    /// Token that must match with given maximal distance
+    /// Token that must match with given maximal distance. Can be empty when
+    /// the constraint is set to browse phrases ranked by should tokens.
     pub must: String,
+    /// Must token split by the tokenizer into many tokens, concatenated in
+    /// the original order (eg. "newyork" for "new york"). Used by
+    /// `join_adjacent`.
+    pub must_joined: Option<String>,
+    /// Additional tokens that must all match some phrase token with the
+    /// maximal distance too.
+    pub additional_must: Vec<String>,
+    /// Alternative spellings of the must token; phrases matching any of
+    /// them are returned. Tokenized by the index when searching.
+    pub must_any: Vec<String>,
     /// Optional `should` tokens that increase phrase score so it has higher
     /// probability of fitting within the `limit`.
     pub should: Vec<String>,
+    /// Match must tokens only against tokens of this field.
+    pub must_field: Option<FieldId>,
+    /// Score should tokens only against tokens of this field.
+    pub should_field: Option<FieldId>,
     /// Optional constraints that must match.
     /// TODO: This could support a HashSet of various constraints (ORed)
     pub constraint: Option<usize>,
+    /// String constraint resolved by the index to its interned id. Takes
+    /// precedence over `constraint`.
+    pub constraint_name: Option<String>,
     /// Limit result count. Scanning can be faster with low limit.
     pub limit: Option<usize>,
+    /// Return at most this many best results sharing the same constraint.
+    /// Applied before the `limit`.
+    pub limit_per_constraint: Option<usize>,
+    /// Return only results of this many distinct, best ranked indices.
+    /// Applied before the `limit`.
+    pub limit_distinct_index: Option<usize>,
     /// Max levenshtein distance for "must" token to be a valid result.
     pub max_distance: Option<usize>,
-    /// Cutoff phrase scanning when it's score is < `cutoff*max_score`.
+    /// Cutoff phrase scanning when it's score is < `cutoff*max_score`. 0.0
+    /// scans all candidates.
     pub scan_cutoff: f32,
+    /// Distance of the best result that allows the scan cutoff to stop the
+    /// scan. 0 breaks only after an exact match.
+    pub early_break_distance: usize,
+    /// Skip must token trigrams scored below this value when looking for
+    /// candidates. Common trigrams add many candidates, but tell little.
+    pub must_trigram_cutoff: Option<f32>,
+    /// Compute distance only to tokens sharing at least this many trigrams
+    /// with the must token. 0 checks all tokens found in the heatmap.
+    pub min_trigram_overlap: usize,
+    /// Metric used for the must token distance.
+    pub metric: Metric,
+    /// Order of otherwise equally ranked results.
+    pub tiebreak: Tiebreak,
+    /// How phrases are scored before computing the distance.
+    pub similarity: Similarity,
+    /// Which of the matching tokens is reported for a phrase.
+    pub token_preference: TokenPreference,
+    /// Whether must tokens match whole phrase tokens or their parts.
+    pub mode: SearchMode,
+    /// Also match the must token against concatenations of adjacent phrase
+    /// tokens, and the joined must against single phrase tokens.
+    pub join_adjacent: bool,
+    /// Custom order of the final results, replacing the default ranking.
+    pub rank_fn: Option<RankFn>,
+    /// Stop scanning after examining this many candidate phrases, even if
+    /// their score is still high. Bounds worst-case query time.
+    pub max_candidates: Option<usize>,
+    /// Stop scanning after examining `limit * scan_multiplier` candidate
+    /// phrases. Ignored without a `limit`.
+    pub scan_multiplier: Option<f32>,
+    /// Multiplier of should scores. Must trigram score and should score are
+    /// summed to order the scan, so with low weights should tokens only
+    /// break near-ties instead of overriding a strong must match.
+    pub should_weight: f32,
+    /// Weights of the should tokens in their order, applied on top of the
+    /// `should_weight`; tokens without one weigh 1.0.
+    pub should_weights: Vec<f32>,
+    /// Score should tokens by edit distance in addition to trigram overlap,
+    /// so a misspelled should token still boosts the phrase. Slower.
+    pub fuzzy_should: bool,
+    /// Drop phrases matching fewer distinct should tokens.
+    pub min_should_matches: usize,
+    /// Count a swap of two adjacent graphemes as a single edit
+    /// (Damerau-Levenshtein). Used only with the Levenshtein metric.
+    pub transpositions: bool,
+    /// Ignore up to this many junk graphemes at the edges of the phrase
+    /// token when computing the distance.
+    pub edge_trim: usize,
+    /// Compare must tokens with phrase tokens with accents stripped, the same
+    /// way trigrams are (see `IndexerConfig::marks`), so accent differences
+    /// cost no edits.
+    pub fold_accents: bool,
+    /// Scan phrases containing the must token literally before all others,
+    /// so exact matches aren't crowded out by high scored fuzzy ones.
+    pub prefer_exact: bool,
+    /// Gather additional data explaining the results (eg. should breakdown).
+    pub explain: bool,
+    /// When nothing is within `max_distance`, return the nearest phrase
+    /// flagged with `SearchResult::within_distance` set to false.
+    pub fallback_best: bool,
+    /// Policy used to split must tokens when the query was created.
+    pub must_split: MustSplit,
+    /// Fingerprint of the configuration used to tokenize the must tokens.
+    /// `Index::try_search` refuses queries created for a different
+    /// configuration; other search methods normalize them again.
+    pub config_fingerprint: u64,
+}
+
+/// Fields of a query deciding its results, to key cached results of
+/// `Index::search_cached`. Queries are keyed after being normalized for the
+/// index, so the normalization settings are left out; so is the `rank_fn`,
+/// which can't be compared.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct QueryKey {
+    must: String,
+    must_joined: Option<String>,
+    additional_must: Vec<String>,
+    must_any: Vec<String>,
+    should: Vec<String>,
+    must_field: Option<FieldId>,
+    should_field: Option<FieldId>,
+    constraint: Option<usize>,
+    constraint_name: Option<String>,
+    limit: Option<usize>,
+    limit_per_constraint: Option<usize>,
+    limit_distinct_index: Option<usize>,
+    max_distance: Option<usize>,
+    scan_cutoff: OrderedF32,
+    early_break_distance: usize,
+    must_trigram_cutoff: Option<OrderedF32>,
+    min_trigram_overlap: usize,
+    metric: Metric,
+    tiebreak: Tiebreak,
+    similarity: Similarity,
+    token_preference: TokenPreference,
+    mode: SearchMode,
+    join_adjacent: bool,
+    max_candidates: Option<usize>,
+    scan_multiplier: Option<OrderedF32>,
+    should_weight: OrderedF32,
+    should_weights: Vec<OrderedF32>,
+    fuzzy_should: bool,
+    min_should_matches: usize,
+    transpositions: bool,
+    edge_trim: usize,
+    fold_accents: bool,
+    prefer_exact: bool,
+    explain: bool,
+    fallback_best: bool,
+}
+
+/// Normalize a single must token. If the tokenizer splits it into many
+/// tokens, the longest is returned and others are moved to `rest` (unless
+/// the `split` policy rejects it).
+fn split_must(config: &IndexerConfig, must: &str, split: MustSplit,
+              rest: &mut Vec<String>) -> Result<String, MultiTokenMust> {
+    /* Sometimes must token passed in query is not tokenized in the same way we do */
+    let mut tokens: Vec<String> = config.tokenize(must);
+    if tokens.is_empty() {
+        return Ok(must.to_string());
+    }
+    if tokens.len() > 1 && split == MustSplit::Error {
+        return Err(MultiTokenMust { token: must.to_string(), tokens });
+    }
+    tokens.sort_by_key(|token| - (token.len() as i64));
+    rest.extend(tokens.drain(1..));
+    Ok(tokens.swap_remove(0))
 }
 
 impl Query {
     /// Create a Query with must/should tokens. You should tokenize things and
     /// pass a single token, but if the internal tokenizer splits must into many
     /// tokens, the longest will be set as a `must` and others moved to
-    /// `should`.
+    /// `should` (see `with_must_split` for other policies).
     ///
-    /// Currently only a single `must` token is allowed, but API accepts list
-    /// for future compatibility.
+    /// When more than one `must` token is given, first is used to find the
+    /// candidate phrases and all others have to match some token of the
+    /// phrase as well (AND).
     pub fn new(must: &[&str], should: &[&str]) -> Self {
+        Self::with_config(&IndexerConfig::default(), must, should)
+    }
+
+    /// Create a Query for an index with a non-default configuration. Must
+    /// tokens are normalized the same way as the indexed phrases.
+    pub fn with_config(config: &IndexerConfig, must: &[&str], should: &[&str]) -> Self {
+        Self::with_must_split(config, must, should, MustSplit::LongestAsMust)
+            .expect("Must tokens are split without errors")
+    }
 
+    /// Create a Query for an index with a non-default configuration, with
+    /// an explicit policy for must tokens split by the tokenizer into many.
+    pub fn with_must_split(config: &IndexerConfig, must: &[&str], should: &[&str],
+                           split: MustSplit) -> Result<Self, MultiTokenMust> {
         let mut should_tokens: Vec<String> = should.iter().map(|s| s.to_string()).collect();
+        let mut split_tokens: Vec<String> = Vec::new();
 
-        assert!(must.len() == 1, "Only one `must token` is currently supported");
-        let must = must[0];
-
-        /* Sometimes must token passed in query is not tokenized in the same way we do */
-        let mut tokens: Vec<String> = utils::tokenize(must, 1);
-        let must_token: String = if tokens.len() > 1 {
-            tokens.sort_unstable_by_key(|token| - (token.len() as i64));
-            for token in tokens[1..].iter() {
-                should_tokens.push(token.to_owned());
-            }
-            tokens[0].to_owned()
-        } else {
-            must.to_string()
-        };
+        assert!(!must.is_empty(), "At least one `must token` is required");
+        let must_token = split_must(config, must[0], split, &mut split_tokens)?;
+        let must_joined = Some(config.tokenize(must[0]))
+            .filter(|tokens| tokens.len() > 1)
+            .map(|tokens| tokens.concat());
+        let mut additional_must: Vec<String> = Vec::with_capacity(must.len() - 1);
+        for must in must[1..].iter() {
+            additional_must.push(split_must(config, must, split, &mut split_tokens)?);
+        }
+        match split {
+            MustSplit::AllMust => additional_must.append(&mut split_tokens),
+            _ => should_tokens.append(&mut split_tokens),
+        }
 
-        Self {
+        Ok(Self {
             must: must_token,
+            must_joined,
+            additional_must,
+            must_any: Vec::new(),
             should: should_tokens,
+            must_field: None,
+            should_field: None,
             constraint: None,
+            constraint_name: None,
             limit: None,
+            limit_per_constraint: None,
+            limit_distinct_index: None,
             max_distance: Some(2),
             scan_cutoff: 0.3,
+            early_break_distance: 0,
+            must_trigram_cutoff: None,
+            min_trigram_overlap: 0,
+            metric: Metric::default(),
+            tiebreak: Tiebreak::default(),
+            token_preference: TokenPreference::default(),
+            mode: SearchMode::default(),
+            join_adjacent: false,
+            similarity: Similarity::default(),
+            rank_fn: None,
+            max_candidates: None,
+            scan_multiplier: None,
+            should_weight: 1.0,
+            should_weights: Vec::new(),
+            fuzzy_should: false,
+            min_should_matches: 0,
+            transpositions: false,
+            edge_trim: 0,
+            fold_accents: false,
+            prefer_exact: false,
+            explain: false,
+            fallback_best: false,
+            must_split: split,
+            config_fingerprint: config.fingerprint(),
+        })
+    }
+
+    /// Key of the cached results of the query (see `QueryKey`).
+    pub(crate) fn key(&self) -> QueryKey {
+        /* Destructured, so new fields have to be considered here */
+        let Query {
+            must,
+            must_joined,
+            additional_must,
+            must_any,
+            should,
+            must_field,
+            should_field,
+            constraint,
+            constraint_name,
+            limit,
+            limit_per_constraint,
+            limit_distinct_index,
+            max_distance,
+            scan_cutoff,
+            early_break_distance,
+            must_trigram_cutoff,
+            min_trigram_overlap,
+            metric,
+            tiebreak,
+            similarity,
+            token_preference,
+            mode,
+            join_adjacent,
+            max_candidates,
+            scan_multiplier,
+            should_weight,
+            should_weights,
+            fuzzy_should,
+            min_should_matches,
+            transpositions,
+            edge_trim,
+            fold_accents,
+            prefer_exact,
+            explain,
+            fallback_best,
+            rank_fn: _,
+            must_split: _,
+            config_fingerprint: _,
+        } = self;
+        QueryKey {
+            must: must.clone(),
+            must_joined: must_joined.clone(),
+            additional_must: additional_must.clone(),
+            must_any: must_any.clone(),
+            should: should.clone(),
+            must_field: *must_field,
+            should_field: *should_field,
+            constraint: *constraint,
+            constraint_name: constraint_name.clone(),
+            limit: *limit,
+            limit_per_constraint: *limit_per_constraint,
+            limit_distinct_index: *limit_distinct_index,
+            max_distance: *max_distance,
+            scan_cutoff: OrderedF32(*scan_cutoff),
+            early_break_distance: *early_break_distance,
+            must_trigram_cutoff: must_trigram_cutoff.map(OrderedF32),
+            min_trigram_overlap: *min_trigram_overlap,
+            metric: *metric,
+            tiebreak: *tiebreak,
+            similarity: *similarity,
+            token_preference: *token_preference,
+            mode: *mode,
+            join_adjacent: *join_adjacent,
+            max_candidates: *max_candidates,
+            scan_multiplier: scan_multiplier.map(OrderedF32),
+            should_weight: OrderedF32(*should_weight),
+            should_weights: should_weights.iter().copied().map(OrderedF32).collect(),
+            fuzzy_should: *fuzzy_should,
+            min_should_matches: *min_should_matches,
+            transpositions: *transpositions,
+            edge_trim: *edge_trim,
+            fold_accents: *fold_accents,
+            prefer_exact: *prefer_exact,
+            explain: *explain,
+            fallback_best: *fallback_best,
         }
     }
 
+    /// The query with must tokens normalized again for an index with the
+    /// given configuration, for queries created for another one. Tokens
+    /// split further follow the `must_split` policy, except that they are
+    /// never rejected: the longest part becomes the must instead.
+    pub(crate) fn conformed(&self, config: &IndexerConfig) -> Query {
+        let split = match self.must_split {
+            MustSplit::Error => MustSplit::LongestAsMust,
+            split => split,
+        };
+        let mut split_tokens: Vec<String> = Vec::new();
+        let must = split_must(config, &self.must, split, &mut split_tokens)
+            .expect("Must tokens are split without errors");
+        let must_joined = Some(config.tokenize(&self.must))
+            .filter(|tokens| tokens.len() > 1)
+            .map(|tokens| tokens.concat())
+            .or_else(|| self.must_joined.clone());
+        let mut additional_must: Vec<String> = Vec::with_capacity(self.additional_must.len());
+        for must in self.additional_must.iter() {
+            additional_must.push(split_must(config, must, split, &mut split_tokens)
+                                 .expect("Must tokens are split without errors"));
+        }
+        let mut should = self.should.clone();
+        match split {
+            MustSplit::AllMust => additional_must.append(&mut split_tokens),
+            _ => should.append(&mut split_tokens),
+        }
+        Query {
+            must,
+            must_joined,
+            additional_must,
+            should,
+            config_fingerprint: config.fingerprint(),
+            ..self.clone()
+        }
+    }
+
+    /// Also match phrases similar to any of the alternative spellings of
+    /// the must token (eg. "mohammed" for "muhammad"). Each phrase is
+    /// returned once, with the best matching alternative in
+    /// `SearchResult::matched_must`. Used by `Index::search` and the methods
+    /// based on it.
+    pub fn must_any(mut self, variants: &[&str]) -> Self {
+        self.must_any = variants.iter().map(|variant| variant.to_string()).collect();
+        self
+    }
+
+    /// Must alternatives normalized like must tokens, after the must itself.
+    pub(crate) fn must_alternatives(&self, config: &IndexerConfig) -> Vec<String> {
+        let mut ignored = Vec::new();
+        std::iter::once(self.must.clone())
+            .chain(self.must_any.iter().filter_map(|variant| {
+                split_must(config, variant, MustSplit::LongestAsMust, &mut ignored).ok()
+            }))
+            .collect()
+    }
+
     pub fn constraint(mut self, constraint: Option<usize>) -> Self {
         self.constraint = constraint;
         self
     }
 
+    /// Require a string constraint added with
+    /// `Indexer::add_phrase_str_constraints`. A string unknown to the index
+    /// matches no phrases.
+    pub fn constraint_str(mut self, name: Option<&str>) -> Self {
+        self.constraint_name = name.map(|name| name.to_string());
+        self
+    }
+
+    /// Match must tokens only against phrase tokens of the field (see
+    /// `Indexer::add_phrase_fields`).
+    pub fn must_field(mut self, field: Option<FieldId>) -> Self {
+        self.must_field = field;
+        self
+    }
+
+    /// Score should tokens only against phrase tokens of the field.
+    pub fn should_field(mut self, field: Option<FieldId>) -> Self {
+        self.should_field = field;
+        self
+    }
+
     pub fn max_distance(mut self, max_distance: Option<usize>) -> Self {
         self.max_distance = max_distance;
         self
     }
 
+    /// Limit result count. Zero limit would return nothing, and is treated
+    /// as no limit instead.
     pub fn limit(mut self, limit: Option<usize>) -> Self {
-        self.limit = limit;
+        self.limit = limit.filter(|&limit| limit > 0);
+        self
+    }
+
+    pub fn limit_per_constraint(mut self, limit: Option<usize>) -> Self {
+        self.limit_per_constraint = limit;
         self
     }
 
+    /// Limit the number of distinct result indices instead of results. All
+    /// phrases of a single index have distinct indices, so it matters when
+    /// merging results of many indices sharing them (see
+    /// `FederatedIndex`): results of the best `limit` indices are returned,
+    /// possibly many for each. Zero is treated as no limit.
+    pub fn limit_distinct_index(mut self, limit: Option<usize>) -> Self {
+        self.limit_distinct_index = limit.filter(|&limit| limit > 0);
+        self
+    }
+
+    /// Maximal number of results of a single index, where each result has a
+    /// distinct index.
+    pub(crate) fn result_limit(&self) -> Option<usize> {
+        match (self.limit, self.limit_distinct_index) {
+            (Some(limit), Some(distinct)) => Some(limit.min(distinct)),
+            (limit, distinct) => limit.or(distinct),
+        }
+    }
+
+    /// Stop scanning candidates when an exact match (see
+    /// `early_break_distance`) was found and the trigram score drops below
+    /// `cutoff` times the best score. 0.0 disables early
+    /// breaks entirely and scans all candidates.
     pub fn scan_cutoff(mut self, cutoff: f32) -> Self {
         self.scan_cutoff = cutoff;
         self
     }
+
+    /// Allow the scan cutoff to stop scanning once a result within
+    /// `distance` is found, not only an exact one. Faster when exact matches
+    /// are unlikely (eg. spelling variations), at the cost of recall.
+    pub fn early_break_distance(mut self, distance: usize) -> Self {
+        self.early_break_distance = distance;
+        self
+    }
+
+    /// Ignore must token trigrams with index score below `cutoff` (0-1)
+    /// when collecting candidates, like should tokens use only their first
+    /// trigrams. Speeds up tokens with very common trigrams (eg. "the").
+    /// The best scored trigram is used even if it's below the cutoff, so
+    /// a must of only common trigrams still finds something.
+    pub fn must_trigram_cutoff(mut self, cutoff: Option<f32>) -> Self {
+        self.must_trigram_cutoff = cutoff;
+        self
+    }
+
+    /// Skip candidate tokens sharing fewer than `overlap` trigrams with the
+    /// must token before computing their distance. Tokens with a single
+    /// shared trigram rarely are within `max_distance`, but short tokens
+    /// have few trigrams; keep it low for them.
+    pub fn min_trigram_overlap(mut self, overlap: usize) -> Self {
+        self.min_trigram_overlap = overlap;
+        self
+    }
+
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Order results tied on distance and all scores. Gives a deterministic
+    /// order matching the source data with `Tiebreak::InsertionOrder`.
+    pub fn tiebreak(mut self, tiebreak: Tiebreak) -> Self {
+        self.tiebreak = tiebreak;
+        self
+    }
+
+    /// Select the `token` of a phrase matching the must token with
+    /// `TokenPreference::ClosestLength` to avoid reporting a token much longer
+    /// or shorter than the query when another one matches as well.
+    pub fn token_preference(mut self, preference: TokenPreference) -> Self {
+        self.token_preference = preference;
+        self
+    }
+
+    /// Match must tokens against any part of the phrase tokens with
+    /// `SearchMode::SubToken`, eg. a component of a compound word. Phrase
+    /// tokens are still found by the shared trigrams, so the must token
+    /// should be at least a few graphemes long.
+    pub fn mode(mut self, mode: SearchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Order results by decreasing value of `rank`, eg. a weighted sum of
+    /// the distance, scores and the token length. Results with equal ranks
+    /// keep the default order; NaN ranks go last. It only reorders the final set:
+    /// candidates are still scanned, cut off and filtered by the distance
+    /// using trigram scores, and `limit` keeps the best results by `rank`.
+    pub fn rank_fn(mut self, rank: impl Fn(&SearchResult) -> f64 + Send + Sync + 'static) -> Self {
+        self.rank_fn = Some(RankFn(Arc::new(rank)));
+        self
+    }
+
+    /// Score candidates with `Similarity::Jaccard` to compare tokens of
+    /// different lengths fairly. Computes trigrams of each candidate token,
+    /// so it's slower than the default.
+    pub fn similarity(mut self, similarity: Similarity) -> Self {
+        self.similarity = similarity;
+        self
+    }
+
+    pub fn max_candidates(mut self, max_candidates: Option<usize>) -> Self {
+        self.max_candidates = max_candidates;
+        self
+    }
+
+    /// Bound latency relative to the limit: scan at most about `limit *
+    /// multiplier` best scored candidates and return the best results found
+    /// among them. Unlike `scan_cutoff` it stops even when no close enough
+    /// result was found yet, eg. with `max_distance` of 1 or more and no
+    /// exact match, at the cost of recall. Combined with `max_candidates`
+    /// the lower budget applies.
+    pub fn scan_multiplier(mut self, multiplier: Option<f32>) -> Self {
+        self.scan_multiplier = multiplier;
+        self
+    }
+
+    /// Number of candidates to scan at most, from `max_candidates` and
+    /// `scan_multiplier`.
+    pub(crate) fn scan_budget(&self) -> Option<usize> {
+        let relative = match (self.limit, self.scan_multiplier) {
+            (Some(limit), Some(multiplier)) => Some((limit as f32 * multiplier).ceil().max(1.0) as usize),
+            _ => None,
+        };
+        match (self.max_candidates, relative) {
+            (Some(absolute), Some(relative)) => Some(absolute.min(relative)),
+            (absolute, relative) => absolute.or(relative),
+        }
+    }
+
+    pub fn should_weight(mut self, weight: f32) -> Self {
+        self.should_weight = weight;
+        self
+    }
+
+    /// Weigh should tokens individually, in the order they were given.
+    /// A negative weight turns a token into a soft constraint: phrases
+    /// containing it get lower should score and are scanned and ranked
+    /// after otherwise equal phrases, but are not excluded. Penalized
+    /// tokens don't count towards `min_should_matches`.
+    ///
+    /// Panics if any weight is not finite.
+    pub fn should_weights(mut self, weights: &[f32]) -> Self {
+        assert!(weights.iter().all(|weight| weight.is_finite()), "Should weights have to be finite");
+        self.should_weights = weights.to_vec();
+        self
+    }
+
+    /// Weight of the should token at the position, including the
+    /// `should_weight`.
+    pub(crate) fn should_token_weight(&self, should_idx: usize) -> f32 {
+        self.should_weight * self.should_weights.get(should_idx).copied().unwrap_or(1.0)
+    }
+
+    pub fn fuzzy_should(mut self, fuzzy_should: bool) -> Self {
+        self.fuzzy_should = fuzzy_should;
+        self
+    }
+
+    /// Require at least `count` distinct should tokens to contribute to the
+    /// should score of a phrase, eg. 2 context words out of 5. Phrases with
+    /// fewer are not returned at all. Default of 0 only scores.
+    pub fn min_should_matches(mut self, count: usize) -> Self {
+        self.min_should_matches = count;
+        self
+    }
+
+    pub fn transpositions(mut self, transpositions: bool) -> Self {
+        self.transpositions = transpositions;
+        self
+    }
+
+    /// Tolerate garbage at edges of phrase tokens, eg. of OCR output: the
+    /// distance is the smallest one after removing up to `graphemes` in
+    /// total from the start and the end of the phrase token, so phrase token
+    /// "xxwarszawa" matches must "warszawa" with distance 0 when set to 2.
+    /// The must token is compared whole. Trimmed graphemes cost nothing, so
+    /// keep it small; default of 0 compares whole tokens. Each trimmed
+    /// variant costs a distance calculation.
+    pub fn edge_trim(mut self, graphemes: usize) -> Self {
+        self.edge_trim = graphemes;
+        self
+    }
+
+    pub fn fold_accents(mut self, fold_accents: bool) -> Self {
+        self.fold_accents = fold_accents;
+        self
+    }
+
+    /// Match tokens written together in one of the must and the phrase and
+    /// apart in the other: "newyork" finds "New York" and "new york" finds
+    /// "Newyork". Concatenations of adjacent phrase tokens are compared
+    /// with the must token, and a must token split by the tokenizer is
+    /// compared joined with single phrase tokens, both within the
+    /// `max_distance`. Only phrases found by the must trigrams are checked,
+    /// but each of them costs additional distance calculations.
+    pub fn join_adjacent(mut self, join_adjacent: bool) -> Self {
+        self.join_adjacent = join_adjacent;
+        self
+    }
+
+    pub fn prefer_exact(mut self, prefer_exact: bool) -> Self {
+        self.prefer_exact = prefer_exact;
+        self
+    }
+
+    pub fn explain(mut self, explain: bool) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    /// Return the single nearest phrase instead of no results when no phrase
+    /// matches within `max_distance`, so the caller can offer it as
+    /// a suggestion. Used by `Index::search` and the methods based on it.
+    pub fn fallback_best(mut self, fallback_best: bool) -> Self {
+        self.fallback_best = fallback_best;
+        self
+    }
+
+    /// Require all must tokens to have at least `min_length` graphemes.
+    /// Short tokens have little typo tolerance and match large candidate
+    /// sets slowly; the error lets the caller choose a different strategy.
+    /// Empty must (browsing by constraint) is always accepted.
+    pub fn min_must_length(self, min_length: usize) -> Result<Self, MustTooShort> {
+        let too_short = std::iter::once(&self.must)
+            .filter(|must| !must.is_empty())
+            .chain(self.additional_must.iter())
+            .find(|must| must.graphemes(true).count() < min_length);
+        match too_short {
+            Some(token) => Err(MustTooShort { token: token.clone(), min_length }),
+            None => Ok(self),
+        }
+    }
 }
@@ -1,24 +1,241 @@
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::cmp::Ordering;
+use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
 // sorted_by
 use itertools::Itertools;
+use unicode_segmentation::UnicodeSegmentation;
 
 use std::sync::Arc;
 use std::sync::Mutex;
 use lru::LruCache;
+use serde::{Serialize, Deserialize};
 
 use crate::utils;
-use super::query::Query;
-use super::{Indexer, FastHash};
+use super::query::{Query, Metric, MustSplit, SearchMode, Similarity, Tiebreak, TokenPreference, QueryKey};
+use super::{Indexer, IndexerConfig, ConfigMismatch, InconsistencyError, MultiTokenMust, FastHash,
+            TrigramEntry, PhraseEntry};
 
-mod heatmap;
-use heatmap::Heatmap;
+pub mod heatmap;
+pub use heatmap::{Heatmap, TokenHeatmap};
 
-/// Query result
-#[derive(Debug, Clone, PartialEq)]
+/// Max edit distance of a phrase token to a should token in fuzzy should mode.
+const FUZZY_SHOULD_DISTANCE: usize = 2;
+
+/// Number of top results compared by `Index::recommend_scan_cutoff`.
+const CALIBRATION_TOP: usize = 10;
+
+/// Distance of a phrase token to a must token using the query metric, or
+/// None if it exceeds the query `max_distance`. With `Query::fold_accents`
+/// marks are stripped by the index `marks` policy, like in trigrams.
+fn token_distance(query: &Query, marks: utils::MarkPolicy, token: &str, must: &str) -> Option<usize> {
+    if query.fold_accents {
+        let (token, must) = (utils::fold_marks(token, marks), utils::fold_marks(must, marks));
+        return trimmed_distance(query, &token, &must);
+    }
+    trimmed_distance(query, token, must)
+}
+
+/// Smallest distance of the must to the phrase token with up to
+/// `Query::edge_trim` graphemes removed from the token edges.
+fn trimmed_distance(query: &Query, token: &str, must: &str) -> Option<usize> {
+    let distance = metric_distance(query, token, must);
+    if query.edge_trim == 0 || distance == Some(0) {
+        return distance;
+    }
+    utils::edge_trims(token, query.edge_trim)
+        .into_iter()
+        .map(|trimmed| metric_distance(query, trimmed, must))
+        .fold(distance, |best, distance| best.into_iter().chain(distance).min())
+}
+
+/// Distance like `token_distance`, counting the computed distances in the
+/// stats. Exact lookups (`max_distance` of 0 between whole tokens) only
+/// compare the tokens, as the Levenshtein distance is 0 only for equal ones.
+fn counted_distance(query: &Query, marks: utils::MarkPolicy, token: &str, must: &str,
+                    stats: &mut SearchStats) -> Option<usize> {
+    if query.max_distance == Some(0) && query.metric == Metric::Levenshtein
+        && query.mode == SearchMode::Token && !query.fold_accents && query.edge_trim == 0 {
+        return (token == must).then_some(0);
+    }
+    stats.distance_calls += 1;
+    token_distance(query, marks, token, must)
+}
+
+fn metric_distance(query: &Query, token: &str, must: &str) -> Option<usize> {
+    if query.mode == SearchMode::SubToken {
+        return within_distance(query, utils::window_distance(token, must));
+    }
+    let distance = match (query.metric, query.max_distance) {
+        (Metric::Levenshtein, Some(max_distance)) => {
+            /* Most candidates are too far; don't calculate the whole distance */
+            return if query.transpositions {
+                utils::damerau_within(token, must, max_distance)
+            } else {
+                utils::distance_within(token, must, max_distance)
+            };
+        }
+        (Metric::Levenshtein, None) if query.transpositions => utils::damerau_distance(token, must),
+        (Metric::Levenshtein, None) => utils::distance(token, must),
+        (Metric::JaroWinkler, _) => {
+            let similarity = utils::jaro_winkler(token, must);
+            ((1.0 - similarity) * 100.0).round() as usize
+        }
+    };
+    within_distance(query, distance)
+}
+
+/// Edits turning the token into the must token, computed on the same
+/// strings as `token_distance`. Empty when they can't describe the reported
+/// distance: for other metrics, sub-token matches, or when transpositions,
+/// trimmed edges or joining adjacent tokens made the distance smaller.
+fn edit_ops(query: &Query, marks: utils::MarkPolicy, token: &str, distance: usize) -> Vec<utils::EditOp> {
+    if query.metric != Metric::Levenshtein || query.mode != SearchMode::Token {
+        return Vec::new();
+    }
+    let (ops_distance, ops) = if query.fold_accents {
+        utils::distance_ops(&utils::fold_marks(token, marks), &utils::fold_marks(&query.must, marks))
+    } else {
+        utils::distance_ops(token, &query.must)
+    };
+    if ops_distance == distance {
+        ops
+    } else {
+        Vec::new()
+    }
+}
+
+fn within_distance(query: &Query, distance: usize) -> Option<usize> {
+    match query.max_distance {
+        Some(max_distance) if distance > max_distance => None,
+        _ => Some(distance),
+    }
+}
+
+/// Final ranking of the results: by distance, then by decreasing token and
+/// should scores. Ties are ordered by the query `tiebreak`. Even NaN scores
+/// (eg. from a NaN should weight) have a defined place: they rank below all
+/// numbers (see `decreasing`).
+pub(crate) fn rank_order(a: &SearchResult, b: &SearchResult, tiebreak: Tiebreak) -> Ordering {
+    score_order(a.distance, a.score, a.should_score, b.distance, b.score, b.should_score)
+        .then_with(|| tiebreak_order(a, b, tiebreak))
+}
+
+/// Order by distance, then by decreasing scores; total over all floats.
+fn score_order(distance_a: usize, score_a: f32, should_a: f32,
+               distance_b: usize, score_b: f32, should_b: f32) -> Ordering {
+    distance_a.cmp(&distance_b)
+        .then_with(|| decreasing(score_a, score_b))
+        .then_with(|| decreasing(should_a, should_b))
+}
+
+/// Decreasing order of scores, total over all floats: NaN goes after all
+/// numbers, so a broken score never outranks valid ones.
+fn decreasing(score_a: f32, score_b: f32) -> Ordering {
+    score_a.is_nan().cmp(&score_b.is_nan())
+        .then_with(|| score_b.total_cmp(&score_a))
+}
+
+/// Keep only results of the first `limit` distinct indices of ranked
+/// results.
+pub(crate) fn limit_distinct_index(results: &mut Vec<SearchResult>, limit: usize) {
+    let mut indices: HashSet<usize, FastHash> = HashSet::with_hasher(FastHash::new());
+    results.retain(|result| {
+        indices.contains(&result.index) || indices.len() < limit && indices.insert(result.index)
+    });
+}
+
+/// Rank results of a query: by the custom `Query::rank_fn` if set, then by
+/// `rank_order`. The custom rank is computed once per result.
+pub(crate) fn sort_ranked(query: &Query, results: &mut Vec<SearchResult>) {
+    let rank_fn = match &query.rank_fn {
+        Some(rank_fn) => rank_fn,
+        None => {
+            results.sort_unstable_by(|a, b| rank_order(a, b, query.tiebreak));
+            return;
+        }
+    };
+    let mut ranked: Vec<(f64, SearchResult)> = results
+        .drain(..)
+        .map(|result| (rank_fn.rank(&result), result))
+        .collect();
+    ranked.sort_unstable_by(|(rank_a, a), (rank_b, b)| {
+        custom_rank_order(*rank_a, *rank_b).then_with(|| rank_order(a, b, query.tiebreak))
+    });
+    results.extend(ranked.into_iter().map(|(_rank, result)| result));
+}
+
+/// Higher custom ranks first; NaN ranks last, like in `decreasing`.
+fn custom_rank_order(rank_a: f64, rank_b: f64) -> Ordering {
+    rank_a.is_nan().cmp(&rank_b.is_nan())
+        .then_with(|| rank_b.total_cmp(&rank_a))
+}
+
+/// Order of equally ranked results. By default shorter phrases are
+/// preferred and phrases with identical origins are ordered by index.
+fn tiebreak_order(a: &SearchResult, b: &SearchResult, tiebreak: Tiebreak) -> Ordering {
+    match tiebreak {
+        Tiebreak::Origin => (a.origin.len(), a.origin, a.index).cmp(&(b.origin.len(), b.origin, b.index)),
+        Tiebreak::Index => a.index.cmp(&b.index),
+        Tiebreak::InsertionOrder => (a.sequence, a.index).cmp(&(b.sequence, b.index)),
+    }
+}
+
+/// Check if the phrase token can match must tokens of the query.
+fn in_must_field(query: &Query, phrase: &PhraseEntry, token_idx: usize) -> bool {
+    query.must_field.is_none_or(|field| phrase.field(token_idx) == field)
+}
+
+/// Should score accumulated for a single phrase.
+#[derive(Debug, Default)]
+struct ShouldScore {
+    score: f32,
+    /// Contribution of each should token. Gathered only with `Query::explain`.
+    breakdown: Vec<(String, f32)>,
+    /// Number of distinct should tokens increasing the score.
+    matched: usize,
+    /// Position of the last counted should token within the query.
+    last_matched: Option<usize>,
+}
+
+impl ShouldScore {
+    /// Add score of a should token identified by the position of its first
+    /// occurrence in the query, so repeated tokens are counted once.
+    /// Penalties (negative scores) are not counted as matches.
+    fn add(&mut self, should_idx: usize, token: &str, score: f32, explain: bool) {
+        self.score += score;
+        /* Tokens are added in the query order */
+        if score > 0.0 && self.last_matched.is_none_or(|last| should_idx > last) {
+            self.matched += 1;
+            self.last_matched = Some(should_idx);
+        }
+        if explain {
+            match self.breakdown.last_mut() {
+                Some((last, last_score)) if last == token => *last_score += score,
+                _ => self.breakdown.push((token.to_string(), score)),
+            }
+        }
+    }
+}
+
+static NO_SHOULD_SCORE: ShouldScore = ShouldScore {
+    score: 0.0,
+    breakdown: Vec::new(),
+    matched: 0,
+    last_matched: None,
+};
+
+type ShouldScores = HashMap<usize, ShouldScore, FastHash>;
+
+/// Query result. Serialized field names match keys of the Python result
+/// dicts.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct SearchResult<'a> {
     /// Original matched phrase before tokenization.
     pub origin: &'a str,
+    /// Lowercased `origin`; only with `IndexerConfig::normalized_origin`.
+    pub normalized_origin: Option<&'a str>,
     /// Returned index, a "value" of dictionary.
     pub index: usize,
     /// Token that matched the must token.
@@ -29,29 +246,391 @@ pub struct SearchResult<'a> {
     pub score: f32,
     /// Bonus score from /should/ tokens.
     pub should_score: f32,
+    /// Should tokens that contributed to the `should_score` with their
+    /// scores. Filled only when `Query::explain` is set.
+    pub should_breakdown: Vec<(String, f32)>,
+    /// Tokens (and their distances) matching additional must tokens of the
+    /// query, in the query order. Empty for single must token queries.
+    pub additional_matches: Vec<(&'a str, usize)>,
+    /// Levenshtein edits transforming the matched `token` into the must
+    /// token. Filled only when `Query::explain` is set and the edits add up
+    /// to the `distance` (eg. not for the Jaro-Winkler metric).
+    pub edit_ops: Vec<utils::EditOp>,
+    /// Trigrams of the must token found in the matched `token`, with their
+    /// scores, best first. Filled only when `Query::explain` is set.
+    pub matched_trigrams: Vec<(String, f32)>,
+    /// Insertion sequence number of the phrase (see
+    /// `Tiebreak::InsertionOrder`).
+    pub sequence: u64,
+    /// Which must alternative matched: 0 for the must token, `i + 1` for the
+    /// i-th `Query::must_any` variant.
+    pub matched_must: usize,
+    /// Must tokens matched within the query `max_distance`. False only for
+    /// the nearest match returned with `Query::fallback_best`.
+    pub within_distance: bool,
+    /// All `matched_trigrams` are pseudo trigrams of 1-2 grapheme tokens or
+    /// edge trigrams of short tokens, so the match is less reliable. Filled
+    /// only when `Query::explain` is set.
+    pub short_token_match: bool,
 }
 
-#[derive(Clone, Default, Debug)]
+impl<'a> SearchResult<'a> {
+    /// Sort key of the default ranking of `Index::search` (`Tiebreak::Origin`
+    /// without a `Query::rank_fn`): by distance, decreasing score and should
+    /// score, then by the origin length, origin and index. Sorting merged
+    /// results of many searches by it orders them like a single search would.
+    pub fn rank_key(&self) -> RankKey<'a> {
+        RankKey {
+            distance: self.distance,
+            score: self.score,
+            should_score: self.should_score,
+            origin: self.origin,
+            index: self.index,
+        }
+    }
+}
+
+/// Ordering of a result returned by `SearchResult::rank_key`; lesser keys
+/// rank higher. Borrows only the index, so it works with `sort_by_key`.
+#[derive(Debug, Clone, Copy)]
+pub struct RankKey<'a> {
+    distance: usize,
+    score: f32,
+    should_score: f32,
+    origin: &'a str,
+    index: usize,
+}
+
+impl PartialEq for RankKey<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankKey<'_> {}
+
+impl PartialOrd for RankKey<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RankKey<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        /* Same as `rank_order` with the `Tiebreak::Origin` */
+        score_order(self.distance, self.score, self.should_score,
+                    other.distance, other.score, other.should_score)
+            .then_with(|| (self.origin.len(), self.origin, self.index)
+                       .cmp(&(other.origin.len(), other.origin, other.index)))
+    }
+}
+
+/// Search result owning its data, so it can be cached or outlive the index
+/// borrow. Serializes the same way as `SearchResult`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OwnedSearchResult {
+    pub origin: String,
+    pub normalized_origin: Option<String>,
+    pub index: usize,
+    pub token: String,
+    pub distance: usize,
+    pub score: f32,
+    pub should_score: f32,
+    pub should_breakdown: Vec<(String, f32)>,
+    pub additional_matches: Vec<(String, usize)>,
+    pub edit_ops: Vec<utils::EditOp>,
+    pub matched_trigrams: Vec<(String, f32)>,
+    pub sequence: u64,
+    pub matched_must: usize,
+    pub within_distance: bool,
+    pub short_token_match: bool,
+}
+
+impl From<&SearchResult<'_>> for OwnedSearchResult {
+    fn from(result: &SearchResult) -> Self {
+        OwnedSearchResult {
+            origin: result.origin.to_string(),
+            normalized_origin: result.normalized_origin.map(|origin| origin.to_string()),
+            index: result.index,
+            token: result.token.to_string(),
+            distance: result.distance,
+            score: result.score,
+            should_score: result.should_score,
+            should_breakdown: result.should_breakdown.clone(),
+            additional_matches: result.additional_matches
+                .iter()
+                .map(|(token, distance)| (token.to_string(), *distance))
+                .collect(),
+            edit_ops: result.edit_ops.clone(),
+            matched_trigrams: result.matched_trigrams.clone(),
+            sequence: result.sequence,
+            matched_must: result.matched_must,
+            within_distance: result.within_distance,
+            short_token_match: result.short_token_match,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CacheStats {
     pub hits: usize,
     pub misses: usize,
     pub inserts: usize,
     /// Current size of the cache, calculated on request.
     pub size: usize,
+    /// Estimated memory of the cached heatmaps and their keys. Not tracked
+    /// for the result cache.
+    pub bytes: usize,
+}
+
+impl std::ops::AddAssign<&CacheStats> for CacheStats {
+    fn add_assign(&mut self, other: &CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.inserts += other.inserts;
+        self.size += other.size;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Sum heatmap cache statistics of many indices for monitoring. Each cache
+/// is locked only for the time of copying its counters.
+pub fn aggregate_cache_stats(indexes: &[&Index]) -> CacheStats {
+    let mut total = CacheStats::default();
+    for index in indexes {
+        total += &index.cache_stats();
+    }
+    total
+}
+
+/// Read-only copy of a trigram entry of the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrigramEntryView {
+    /// Positions of the trigram as (phrase_idx, token_idx) pairs.
+    pub positions: Vec<(usize, u32)>,
+    /// Trigram score; the more unique trigram, the higher score.
+    pub score: f32,
+    /// Some positions were dropped by `ScoringParams::max_positions`.
+    pub capped: bool,
+}
+
+/// Stored data of a single phrase returned by `Index::debug_phrase`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhraseDebug<'a> {
+    /// Original phrase before tokenization.
+    pub origin: &'a str,
+    /// Normalized tokens of the phrase.
+    pub tokens: Vec<&'a str>,
+    /// Trigrams of each token, in the order of `tokens`.
+    pub trigrams: Vec<Vec<String>>,
+    /// Constraints of the phrase, sorted.
+    pub constraints: Vec<usize>,
+}
+
+/// Must token trigram annotated with its presence in the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MustTrigram {
+    pub trigram: String,
+    /// Trigram exists in the index.
+    pub present: bool,
+    /// Trigram score, 0.0 if it's not present.
+    pub score: f32,
+}
+
+/// Statistics of a single search.
+#[derive(Clone, Default, Debug)]
+pub struct SearchStats {
+    /// Number of candidate phrases examined.
+    pub scanned: usize,
+    /// Scanning was stopped by `max_candidates` or `scan_multiplier`
+    /// budget. Results are the best found so far.
+    pub truncated: bool,
+    /// Trigrams of the must token. Filled only when `Query::explain` is set.
+    pub must_trigrams: Vec<MustTrigram>,
+    /// Number of must token distances computed. Exact lookups with
+    /// `max_distance` of 0 compare tokens without computing distances.
+    pub distance_calls: usize,
+}
+
+/// Outcome of warming the cache with a list of tokens.
+#[derive(Clone, Default, Debug)]
+pub struct WarmupStats {
+    /// Heatmaps computed and put into the cache.
+    pub inserted: usize,
+    /// Tokens that were already cached.
+    pub cached: usize,
+    /// Entries pushed out of the cache while warming (includes freshly
+    /// inserted ones if there were more tokens than the cache capacity).
+    pub evicted: usize,
+}
+
+/// `f32` which is `Eq` and `Hash`, comparing by `f32::total_cmp`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OrderedF32(pub f32);
+
+impl PartialEq for OrderedF32 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedF32 {}
+
+impl PartialOrd for OrderedF32 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedF32 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Hash for OrderedF32 {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        /* total_cmp is equal only for equal bits */
+        self.0.to_bits().hash(state);
+    }
+}
+
+/// Key of a cached heatmap: a normalized must token and the
+/// `Query::must_trigram_cutoff` its heatmap was built with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HeatmapKey {
+    pub token: String,
+    pub cutoff: Option<OrderedF32>,
+}
+
+/// Cached heatmap exported with `Index::export_cache`, to be imported into
+/// an index loaded later with `Index::import_cache`. Serializable, eg. with
+/// bincode next to the saved index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapSnapshot {
+    pub heatmap: Heatmap,
+    /// Configuration of the exporting index.
+    config_fingerprint: u64,
+    /// Trigrams of the must token with their position counts, digests of
+    /// the positions and scores, to tell if the importing index would build
+    /// the same heatmap.
+    trigrams: Vec<(String, usize, u64, f32)>,
+}
+
+/// Result ordered for the max-heap of `SearchIter`: the best ranked result
+/// is the greatest. Holds the custom rank (0 without `Query::rank_fn`).
+struct Ranked<'a>(SearchResult<'a>, Tiebreak, f64);
+
+impl PartialEq for Ranked<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked<'_> {}
+
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        custom_rank_order(other.2, self.2)
+            .then_with(|| rank_order(&other.0, &self.0, self.1))
+    }
+}
+
+/// Lazily ranked search results returned by `Index::search_iter`.
+pub struct SearchIter<'a> {
+    index: &'a Index,
+    heap: BinaryHeap<Ranked<'a>>,
+    per_constraint: Option<usize>,
+    counts: HashMap<usize, usize, FastHash>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for SearchIter<'a> {
+    type Item = SearchResult<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        while let Some(Ranked(result, _tiebreak, _rank)) = self.heap.pop() {
+            if let Some(per_constraint) = self.per_constraint {
+                if !self.index.count_constraints(&mut self.counts, &result, per_constraint) {
+                    continue;
+                }
+            }
+            self.remaining -= 1;
+            return Some(result);
+        }
+        None
+    }
 }
 
 struct Cache {
     stats: CacheStats,
-    heatmaps: LruCache<String, Arc<Heatmap>, FastHash>,
+    heatmaps: LruCache<HeatmapKey, Arc<Heatmap>, FastHash>,
+    /// Evict heatmaps when their estimated size exceeds this limit instead
+    /// of limiting their count.
+    max_bytes: Option<usize>,
+    /// Estimated size of the cached heatmaps.
+    bytes: usize,
+}
+
+impl Cache {
+    fn entry_bytes(key: &HeatmapKey, heatmap: &Heatmap) -> usize {
+        std::mem::size_of::<HeatmapKey>() + key.token.len() + heatmap.estimated_bytes()
+    }
+
+    fn put(&mut self, key: HeatmapKey, heatmap: Arc<Heatmap>) {
+        let bytes = Cache::entry_bytes(&key, &heatmap);
+        if self.max_bytes.is_some_and(|max_bytes| bytes > max_bytes) {
+            /* Would push out everything else and still wouldn't fit */
+            return;
+        }
+        /* Returns the evicted or replaced entry */
+        if let Some((old_key, old)) = self.heatmaps.push(key, heatmap) {
+            self.bytes -= Cache::entry_bytes(&old_key, &old);
+        }
+        self.bytes += bytes;
+        self.stats.inserts += 1;
+        if let Some(max_bytes) = self.max_bytes {
+            while self.bytes > max_bytes {
+                match self.heatmaps.pop_lru() {
+                    Some((old_key, old)) => self.bytes -= Cache::entry_bytes(&old_key, &old),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// Second-level cache of complete query results.
+struct ResultCache {
+    stats: CacheStats,
+    results: LruCache<QueryKey, Vec<OwnedSearchResult>, FastHash>,
 }
 
 /// Produced by Index::finish() and can be queried.
+///
+/// Index is `Send + Sync`: searching takes `&self` and only the internal
+/// cache is guarded by a mutex. To serve queries from many threads build it
+/// once and share it as `Arc<Index>`, cloning the `Arc` into each handler.
+/// Results borrow from the index, so they can't outlive the `Arc` clone
+/// they were searched with.
 pub struct Index {
     /// Index prepared for querying.
     pub index: Indexer,
 
-    /// LRU cache of must tokens.
+    /// LRU cache of must tokens, bounded by count or bytes.
     cache: Mutex<Cache>,
+
+    /// Optional LRU cache of whole queries used by `search_cached`.
+    result_cache: Option<Box<Mutex<ResultCache>>>,
 }
 
 impl Index {
@@ -60,97 +639,346 @@ impl Index {
         let cache = Cache {
             stats: CacheStats::default(),
             heatmaps: LruCache::with_hasher(cache_size, FastHash::new()),
+            max_bytes: None,
+            bytes: 0,
         };
         Index {
             index: indexer,
             cache: Mutex::new(cache),
+            result_cache: None,
         }
     }
 
-    /// Create a trigram heatmap for a given token.
-    fn create_heatmap(&self, token: &str) -> Arc<Heatmap> {
-        let index = &self.index;
-        let db = &index.db;
+    /// Maximal number of heatmaps in the cache. Unbounded when the cache is
+    /// bounded by bytes (see `with_byte_cache`).
+    pub fn cache_capacity(&self) -> usize {
+        self.cache.lock().unwrap().heatmaps.cap()
+    }
 
-        /* LRU cache updates position even on get and needs mutable reference */
+    /// Bound the heatmap cache by the estimated size of heatmaps instead of
+    /// their count, evicting least recently used ones above `max_bytes`.
+    /// Heatmaps of common must tokens can be orders of magnitude larger than
+    /// others, so this keeps memory use predictable. Heatmaps larger than the
+    /// limit are not cached at all. Drops already cached heatmaps.
+    pub fn with_byte_cache(self, max_bytes: usize) -> Index {
         {
             let mut cache = self.cache.lock().unwrap();
-            let heatmap = cache.heatmaps.get(token).cloned();
-            if let Some(heatmap) = heatmap {
-                /* We operate on reference-counted heatmaps to eliminate unnecessary copying */
-                cache.stats.hits += 1;
-                return heatmap;
-            }
-            cache.stats.misses += 1;
+            cache.heatmaps = LruCache::unbounded_with_hasher(FastHash::new());
+            cache.max_bytes = Some(max_bytes);
+            cache.bytes = 0;
         }
+        self
+    }
+
+    /// Enable caching of complete results of up to `size` distinct queries
+    /// for `search_cached`. Useful when identical queries repeat often, e.g.
+    /// in autocompletion.
+    pub fn with_result_cache(mut self, size: usize) -> Index {
+        self.result_cache = Some(Box::new(Mutex::new(ResultCache {
+            stats: CacheStats::default(),
+            results: LruCache::with_hasher(size, FastHash::new()),
+        })));
+        self
+    }
+
+    /// Trigram heatmap of a normalized must token (see `Query::must`), taken
+    /// from the cache when possible. Can be reused for many searches with
+    /// `search_with_heatmap`.
+    pub fn heatmap_for(&self, token: &str) -> Arc<Heatmap> {
+        let key = HeatmapKey { token: token.to_string(), cutoff: None };
+        self.cached_heatmap(key, || {
+            let mut heatmap = Heatmap::new();
+            let weights = self.index.config.trigram_weights(token);
+            for (trigram_idx, (_trigram, entry)) in self.must_trigrams(token).enumerate() {
+                if let Some(entry) = entry {
+                    let score = entry.score * weights.get(trigram_idx).copied().unwrap_or(1.0);
+                    for position in entry.positions.iter() {
+                        heatmap.add_phrase(position.phrase_idx, position.token_idx, score);
+                    }
+                }
+            }
+            heatmap
+        })
+    }
 
+    /// Heatmap of arbitrary trigrams (eg. generated by a custom must token
+    /// expansion) instead of the trigrams of a token. Trigrams missing from
+    /// the index are ignored; repeated ones count each time. Not cached, and
+    /// `IndexerConfig::prefix_boost` is not applied.
+    pub fn heatmap_from_trigrams(&self, trigrams: &[String]) -> Heatmap {
         let mut heatmap = Heatmap::new();
+        for entry in trigrams.iter().filter_map(|trigram| self.index.db.get(trigram)) {
+            for position in entry.positions.iter() {
+                heatmap.add_phrase(position.phrase_idx, position.token_idx, entry.score);
+            }
+        }
+        heatmap
+    }
 
-        for trigram in utils::trigramize(token) {
-            if let Some(entry) = db.get(&trigram) {
+    /// Heatmap of the query must token, skipping its trigrams scored below
+    /// `Query::must_trigram_cutoff`. The best scored trigram is always kept.
+    fn must_heatmap(&self, query: &Query) -> Arc<Heatmap> {
+        let cutoff = match query.must_trigram_cutoff {
+            Some(cutoff) => cutoff,
+            None => return self.heatmap_for(&query.must),
+        };
+        let key = HeatmapKey { token: query.must.clone(), cutoff: Some(OrderedF32(cutoff)) };
+        self.cached_heatmap(key, || {
+            let mut heatmap = Heatmap::new();
+            for (_trigram, entry, score) in self.heatmap_trigrams(query) {
                 for position in entry.positions.iter() {
-                    heatmap.add_phrase(position.phrase_idx, position.token_idx, entry.score);
+                    heatmap.add_phrase(position.phrase_idx, position.token_idx, score);
                 }
             }
+            heatmap
+        })
+    }
+
+    /// Present must trigrams building the query heatmap (see `must_heatmap`)
+    /// with their scores weighted by `IndexerConfig::prefix_boost`.
+    fn heatmap_trigrams(&self, query: &Query) -> Vec<(String, &TrigramEntry, f32)> {
+        let weights = self.index.config.trigram_weights(&query.must);
+        let entries: Vec<(String, &TrigramEntry, f32)> = self.must_trigrams(&query.must)
+            .enumerate()
+            .filter_map(|(trigram_idx, (trigram, entry))| entry.map(|entry| {
+                let score = entry.score * weights.get(trigram_idx).copied().unwrap_or(1.0);
+                (trigram, entry, score)
+            }))
+            .collect();
+        let cutoff = match query.must_trigram_cutoff {
+            Some(cutoff) => cutoff,
+            None => return entries,
+        };
+        let best = entries.iter()
+            .map(|(_trigram, entry, _score)| entry.score)
+            .fold(f32::NEG_INFINITY, f32::max);
+        entries.into_iter()
+            .filter(|(_trigram, entry, _score)| entry.score >= cutoff || entry.score >= best)
+            .collect()
+    }
+
+    /// Heatmap trigrams of the query which hit the given phrase token, best
+    /// scored first. Scans trigram positions, so it's used only to explain
+    /// the results.
+    fn matched_trigrams(&self, query: &Query, phrase_idx: usize, token_idx: u32) -> Vec<(String, f32)> {
+        self.heatmap_trigrams(query)
+            .into_iter()
+            .filter(|(_trigram, entry, _score)| {
+                entry.positions
+                    .iter()
+                    .any(|position| position.phrase_idx == phrase_idx && position.token_idx == token_idx)
+            })
+            .map(|(trigram, _entry, score)| (trigram, score))
+            .sorted_by(|(trigram_a, score_a), (trigram_b, score_b)| {
+                score_b.total_cmp(score_a).then(trigram_a.cmp(trigram_b))
+            })
+            .collect()
+    }
+
+    /// Take heatmap from the cache or build and cache it.
+    fn cached_heatmap(&self, key: HeatmapKey, build: impl FnOnce() -> Heatmap) -> Arc<Heatmap> {
+        /* LRU cache updates position even on get and needs mutable reference */
+        {
+            let mut cache = self.cache.lock().unwrap();
+            let heatmap = cache.heatmaps.get(&key).cloned();
+            if let Some(heatmap) = heatmap {
+                /* We operate on reference-counted heatmaps to eliminate unnecessary copying */
+                cache.stats.hits += 1;
+                return heatmap;
+            }
+            cache.stats.misses += 1;
         }
 
-        let heatmap = Arc::new(heatmap);
+        let heatmap = Arc::new(build());
         {
             let mut cache = self.cache.lock().unwrap();
-            cache.heatmaps.put(token.to_string(), heatmap.clone());
-            cache.stats.inserts += 1;
+            cache.put(key, heatmap.clone());
         }
         heatmap
     }
 
-    fn should_scores(&self, heatmap: &Heatmap, should_tokens: &[String],
-                     constraint: Option<usize>)
-                     -> HashMap<usize, f32, FastHash> {
-        let mut map: HashMap<usize, f32, FastHash> = HashMap::with_capacity_and_hasher(
-            heatmap.len_phrases(), FastHash::new()
+    /// Trigrams of a must token with their index entries, if present.
+    fn must_trigrams(&self, token: &str) -> impl Iterator<Item = (String, Option<&TrigramEntry>)> {
+        self.index.trigramize(token)
+            .into_iter()
+            .map(|trigram| {
+                let entry = self.index.db.get(&trigram);
+                (trigram, entry)
+            })
+    }
+
+    /// Total trigram scores of all phrases in the heatmap of a normalized
+    /// must token, ordered by decreasing score (then by phrase index). This
+    /// is the order in which `search` considers the candidates when there
+    /// are no should tokens.
+    pub fn token_heatmap(&self, token: &str) -> Vec<(usize, f32)> {
+        self.heatmap_for(token)
+            .phrases
+            .values()
+            .map(|phrase| (phrase.phrase_idx, phrase.total_score))
+            .sorted_by(|(idx_a, score_a), (idx_b, score_b)| {
+                score_b.total_cmp(score_a).then(idx_a.cmp(idx_b))
+            })
+            .collect()
+    }
+
+    /// Cheap upper bound of the number of candidate phrases a must token
+    /// (normalized like in `query`) pulls into the search: the sum of
+    /// position counts of its trigrams, capped at the number of phrases.
+    /// Phrases sharing many trigrams with the must token are counted many
+    /// times, so the heatmap usually holds fewer. No heatmap is built.
+    pub fn estimate_candidates(&self, must: &str) -> usize {
+        let query = self.query(&[must], &[]);
+        let positions: usize = self.must_trigrams(&query.must)
+            .filter_map(|(_trigram, entry)| entry)
+            .map(|entry| entry.positions.len())
+            .sum();
+        positions.min(self.len())
+    }
+
+    /// Whether any trigram of the token (normalized like in `query`) exists
+    /// in the index. False means the token is out of the vocabulary and no
+    /// search for it can match, whatever the distance. Stops at the first
+    /// found trigram.
+    pub fn has_any_trigram(&self, token: &str) -> bool {
+        let query = self.query(&[token], &[]);
+        self.index.trigramize(&query.must)
+            .iter()
+            .any(|trigram| self.index.db.contains_key(trigram))
+    }
+
+    /// Tell which trigrams of a normalized must token (see `Query::must`)
+    /// exist in the index. A token with no present trigrams is missing from
+    /// the vocabulary rather than too distant.
+    pub fn explain_must(&self, token: &str) -> Vec<MustTrigram> {
+        self.must_trigrams(token)
+            .map(|(trigram, entry)| {
+                MustTrigram {
+                    trigram,
+                    present: entry.is_some(),
+                    score: entry.map_or(0.0, |entry| entry.score),
+                }
+            })
+            .collect()
+    }
+
+    /// Score a phrase would get from a should token identical to its token.
+    fn exact_should_score(&self, token: &str) -> f32 {
+        let db = &self.index.db;
+        let mut trigrams = self.index.trigramize(token);
+        trigrams.truncate(4);
+        trigrams.iter()
+            .filter_map(|trigram| db.get(trigram))
+            .map(|entry| entry.score)
+            .sum()
+    }
+
+    /// Calculate should scores of phrases accepted by `is_candidate`, scaled
+    /// by the query should weights; scores can be negative. Candidates must
+    /// already satisfy the query constraint.
+    fn should_scores(&self, query: &Query, capacity: usize,
+                     is_candidate: impl Fn(usize) -> bool) -> ShouldScores {
+        let mut map: ShouldScores = HashMap::with_capacity_and_hasher(
+            capacity, FastHash::new()
         );
         let db = &self.index.db;
 
-        for token in should_tokens {
-            let mut trigrams = utils::trigramize(token);
+        for (token_position, token) in query.should.iter().enumerate() {
+            let should_idx = query.should.iter().position(|first| first == token).unwrap_or_default();
+            let weight = query.should_token_weight(token_position);
+            let mut trigrams = self.index.trigramize(token);
             /* Use only first 4 trigrams for should scores. This has to effects:
              * - Improves speed for long words.
              * - Reduces impact of should score on ordering during final pass.
              */
             trigrams.truncate(4);
+
+            /* Fuzzy mode treats trigram hits only as candidates (phrase, token) */
+            let mut candidates: HashSet<(usize, u32), FastHash> = HashSet::with_hasher(FastHash::new());
+            let mut overlap: HashMap<usize, f32, FastHash> = HashMap::with_hasher(FastHash::new());
+
             for trigram in trigrams {
                 if let Some(entry) = db.get(&trigram) {
                     for position in entry.positions.iter() {
-                        // Ignore scores from phrases that don't match constraint.
-                        if let Some(constraint_id) = constraint {
-                            let phrase_entry = self.index.phrases.get(&position.phrase_idx).unwrap();
-                            if !phrase_entry.constraints.contains(&constraint_id) {
-                                // Ignore score from this phrase.
+                        /* Cheap heatmap check first; most positions are not candidates */
+                        if !is_candidate(position.phrase_idx) {
+                            continue;
+                        }
+
+                        if let Some(field) = query.should_field {
+                            /* Skip rather than panic if the index is inconsistent */
+                            let in_field = self.index.phrases.get(&position.phrase_idx)
+                                .is_some_and(|phrase| phrase.field(position.token_idx as usize) == field);
+                            if !in_field {
                                 continue;
                             }
                         }
 
-                        if heatmap.has_phrase(position.phrase_idx) {
-                            /* This phrase is within heatmap, we can calculate should score */
-                            let score = map.entry(position.phrase_idx).or_insert(0.0);
-                            *score += entry.score;
+                        /* This phrase is within heatmap, we can calculate should score */
+                        if query.fuzzy_should {
+                            candidates.insert((position.phrase_idx, position.token_idx));
+                            *overlap.entry(position.phrase_idx).or_insert(0.0) += entry.score;
+                        } else {
+                            map.entry(position.phrase_idx)
+                                .or_default()
+                                .add(should_idx, token, entry.score * weight, query.explain);
                         }
                     }
                 }
             }
+
+            if !query.fuzzy_should {
+                continue;
+            }
+
+            /* Tokens within a small distance score as if the should token was
+             * typed correctly. Others keep their plain trigram overlap. */
+            for (phrase_idx, token_idx) in candidates {
+                let phrase = &self.index.phrases[&phrase_idx];
+                let phrase_token = &phrase.tokens[token_idx as usize];
+                if utils::distance(phrase_token, token) <= FUZZY_SHOULD_DISTANCE {
+                    let exact = self.exact_should_score(phrase_token);
+                    let score = overlap.entry(phrase_idx).or_insert(0.0);
+                    if exact > *score {
+                        *score = exact;
+                    }
+                }
+            }
+            for (phrase_idx, token_score) in overlap {
+                map.entry(phrase_idx)
+                    .or_default()
+                    .add(should_idx, token, token_score * weight, query.explain);
+            }
         }
         map
     }
 
-    fn filtered_results(&self, query: &Query, heatmap: &Heatmap,
-                        should_scores: HashMap<usize, f32, FastHash>) -> Vec<SearchResult> {
-        let mut results: Vec<SearchResult> = Vec::with_capacity(query.limit.unwrap_or(3));
-        if let Some(limit) = query.limit {
-            results.reserve(limit);
-        }
+    /// Scan the candidates and return matching phrases in no particular order.
+    fn scanned_results(&self, query: &Query, heatmap: &Heatmap,
+                       should_scores: ShouldScores,
+                       stats: &mut SearchStats) -> Vec<SearchResult<'_>> {
         let index = &self.index;
-        let max_distance: usize = query.max_distance.unwrap_or(usize::MAX);
-        let limit: usize = query.limit.unwrap_or(usize::MAX);
+        let marks = index.config.marks;
+        let limit: usize = query.result_limit().unwrap_or(usize::MAX);
+        /* There can't be more results than candidates */
+        let mut results: Vec<SearchResult> = Vec::with_capacity(
+            std::cmp::min(query.result_limit().unwrap_or(3), heatmap.len_phrases()));
+
+        /* Jaccard similarity needs the number of distinct must trigrams */
+        let must_trigrams = match query.similarity {
+            Similarity::SummedScore => 0,
+            Similarity::Jaccard => index.trigramize(&query.must).iter().unique().count(),
+        };
+        let token_score = |token: &str, token_heat: &TokenHeatmap| -> f32 {
+            match query.similarity {
+                Similarity::SummedScore => token_heat.score,
+                Similarity::Jaccard => {
+                    let token_trigrams = index.trigramize(token).iter().unique().count();
+                    let union = (must_trigrams + token_trigrams).saturating_sub(token_heat.hits as usize);
+                    token_heat.score / union.max(1) as f32
+                }
+            }
+        };
 
         /*
          * Sort phrases by a trigram score. This is an approximation as our
@@ -158,88 +986,200 @@ impl Index {
          * Phrases with higher score have a higher probability of having lower edit distance,
          * but that is not certain.
          */
-        let phrases_by_score = heatmap.phrases
+        let mut phrases_by_score: Vec<_> = heatmap.phrases
             .values()
             .filter_map(|phrase_heatmap| {
                 /* Add phrase data to iterator */
                 let phrase = &index.phrases[&phrase_heatmap.phrase_idx];
-                let should_score = *should_scores.get(&phrase_heatmap.phrase_idx).unwrap_or(&0.0);
-                let extended = (phrase_heatmap,
-                                phrase, should_score);
                 if let Some(constraint) = query.constraint {
                     /* Check constraint from query */
-                    if phrase.constraints.contains(&constraint) {
-                        Some(extended)
-                    } else {
-                        None
+                    if !phrase.constraints.contains(&constraint) {
+                        return None;
                     }
-                } else {
-                    /* No constraint - return all */
-                    Some(extended)
                 }
+                let should_score = should_scores.get(&phrase_heatmap.phrase_idx)
+                    .unwrap_or(&NO_SHOULD_SCORE);
+                if should_score.matched < query.min_should_matches {
+                    return None;
+                }
+                /* Cheap literal lookup, done only when exact matches are preferred */
+                let exact = query.prefer_exact && phrase.tokens
+                    .iter()
+                    .enumerate()
+                    .any(|(token_idx, token)| token == &query.must && in_must_field(query, phrase, token_idx));
+                /* Phrase is as similar as its best token */
+                let score = match query.similarity {
+                    Similarity::SummedScore => phrase_heatmap.total_score,
+                    Similarity::Jaccard => phrase_heatmap.tokens
+                        .iter()
+                        .filter(|(&token_idx, _)| in_must_field(query, phrase, token_idx as usize))
+                        .map(|(&token_idx, token_heat)| token_score(&phrase.tokens[token_idx as usize], token_heat))
+                        .fold(0.0, f32::max),
+                };
+                Some((phrase_heatmap, phrase, should_score, exact, score))
             })
-            .sorted_by(|(heat_a, phrase_a, should_a), (heat_b, phrase_b, should_b)| {
+            .collect();
+        let max_score = match query.similarity {
+            Similarity::SummedScore => heatmap.max_score,
+            Similarity::Jaccard => phrases_by_score
+                .iter()
+                .map(|(_, _, _, _, score)| *score)
+                .fold(0.0, f32::max),
+        };
+        phrases_by_score.sort_by(|(_, phrase_a, should_a, exact_a, score_a), (_, phrase_b, should_b, exact_b, score_b)| {
                 /* The sorted data are scanned and when fuzzdex is happy with the result
                  * will stop scanning. Sorting impacts the behaviour of this early break.
                  *
                  * Sort by a combined score, and prefer shortest solutions if
                  * score is equal. The early break triggers only if the must
-                 * token matches perfectly. With sorting by must-token score
-                 * only, it could miss good solutions.
+                 * token matches well enough (perfectly by default). With sorting by must-token score
+                 * only, it could miss good solutions. Preferred exact matches
+                 * go first.
                  */
-                let side_a = (heat_b.total_score + should_b, phrase_a.origin.len());
-                let side_b = (heat_a.total_score + should_a, phrase_b.origin.len());
-                side_a.partial_cmp(&side_b).expect("Some scores were NaN, and they shouldn't")
+                exact_b.cmp(exact_a)
+                    .then_with(|| decreasing(score_a + should_a.score, score_b + should_b.score))
+                    .then_with(|| phrase_a.origin.len().cmp(&phrase_b.origin.len()))
             });
 
         /* Best distance so far */
         let mut best_distance: usize = usize::MAX;
+        /* Disabled cutoff trades speed for completeness: no early breaks */
+        let exhaustive = query.scan_cutoff <= 0.0;
+        /* Phrases penalized by negative should weights are sorted after
+         * phrases with a worse must score, so early breaks only switch to
+         * checking the remaining penalized phrases by their must score. */
+        let penalized = phrases_by_score.iter().any(|(_, _, should_score, _, _)| should_score.score < 0.0);
+        let mut draining = false;
 
-        for (phrase_heatmap, phrase, should_score) in phrases_by_score {
+        let scan_budget = query.scan_budget();
+        for (phrase_heatmap, phrase, should_score, exact, score) in phrases_by_score {
             /* Iterate over potential phrases */
+            if draining && should_score.score >= 0.0 {
+                continue;
+            }
+
+            /* Degrade gracefully on pathological queries */
+            if let Some(max_candidates) = scan_budget {
+                if stats.scanned >= max_candidates {
+                    stats.truncated = true;
+                    break;
+                }
+            }
+            stats.scanned += 1;
 
             /*
              * Drop scanning if the total score dropped below the cutoff*leader
              * and we already found an entry with low enough distance.
              */
-            if best_distance == 0 && phrase_heatmap.total_score < query.scan_cutoff * heatmap.max_score {
+            if !exhaustive && !exact && best_distance <= query.early_break_distance
+                && score < query.scan_cutoff * max_score {
                 // If the score is too low - it won't grow.
-                break;
+                if !penalized {
+                    break;
+                }
+                draining = true;
+                continue;
             }
 
             /* Iterate over tokens inside this phrase by decreasing trigram
              * score until the first with an acceptable distance is found */
-            let valid_token = phrase_heatmap.tokens
+            let candidate_tokens = phrase_heatmap.tokens
                 .iter()
-                .map(|(&token_idx, &token_score)| {
-                    (token_score, &phrase.tokens[token_idx as usize])
+                .filter(|(&token_idx, token_heat)| {
+                    token_heat.hits as usize >= query.min_trigram_overlap
+                        && in_must_field(query, phrase, token_idx as usize)
+                })
+                .map(|(&token_idx, token_heat)| {
+                    let token = &phrase.tokens[token_idx as usize];
+                    (token_score(token, token_heat), token_idx, token)
                 })
-                .sorted_by(|(score_a, token_a), (score_b, token_b)| {
+                .sorted_by(|(score_a, _, token_a), (score_b, _, token_b)| {
                     /* Prefer shortest for a given score */
                     /* TODO: Maybe score could be divided by token length */
-                    let side_a = (score_a, token_b.len());
-                    let side_b = (score_b, token_a.len());
-                    side_b.partial_cmp(&side_a).expect("Some token score was NaN, it should never be.")
-                })
-                .map(|(token_score, token)| {
-                    let distance = utils::distance(token, &query.must);
-                    (token, token_score, distance)
-                }).find(|(_token, _score, distance)| {
-                    *distance <= max_distance
+                    decreasing(*score_a, *score_b)
+                        .then_with(|| token_a.len().cmp(&token_b.len()))
                 });
+            let mut distances = candidate_tokens
+                .filter_map(|(token_score, token_idx, token)| {
+                    counted_distance(query, marks, token, &query.must, stats)
+                        .map(|distance| (token, token_idx, token_score, distance))
+                });
+            let valid_token = match query.token_preference {
+                TokenPreference::Score => distances.next(),
+                TokenPreference::ClosestLength => {
+                    let must_length = query.must.graphemes(true).count();
+                    /* min_by_key keeps the first (best scored) of equal tokens */
+                    distances.min_by_key(|(token, _, _, distance)| {
+                        (*distance, token.graphemes(true).count().abs_diff(must_length))
+                    })
+                }
+            };
 
-            if let Some((token, token_score, distance)) = valid_token {
+            /* Regular match can only be beaten by joined tokens if not exact */
+            let valid_token = if query.join_adjacent && valid_token.is_none_or(|(.., distance)| distance > 0) {
+                valid_token
+                    .into_iter()
+                    .chain(self.joined_match(query, phrase, phrase_heatmap, stats))
+                    .min_by_key(|(.., distance)| *distance)
+            } else {
+                valid_token
+            };
+
+            if let Some((token, token_idx, token_score, distance)) = valid_token {
                 /* Add result based on best token matching this phrase (lowest
                  * distance, highest score) */
 
+                /* All additional must tokens have to match some token too */
+                let additional_matches: Option<Vec<(&str, usize)>> = query.additional_must
+                    .iter()
+                    .map(|must| {
+                        phrase.tokens
+                            .iter()
+                            .enumerate()
+                            .filter(|(token_idx, _)| in_must_field(query, phrase, *token_idx))
+                            .filter_map(|(_, token)| {
+                                counted_distance(query, marks, token, must, stats)
+                                    .map(|distance| (token.as_str(), distance))
+                            })
+                            .min_by_key(|(_token, distance)| *distance)
+                    })
+                    .collect();
+                let additional_matches = match additional_matches {
+                    Some(matches) => matches,
+                    None => continue,
+                };
+
+                let matched_trigrams = if query.explain {
+                    self.matched_trigrams(query, phrase.idx, token_idx)
+                } else {
+                    Vec::new()
+                };
+                let short_token_match = !matched_trigrams.is_empty() && {
+                    let regular = self.index.config.regular_trigrams(&query.must);
+                    matched_trigrams.iter().all(|(trigram, _score)| !regular.contains(trigram))
+                };
+
                 results.push(
                     SearchResult {
                         origin: &phrase.origin,
+                        normalized_origin: phrase.normalized_origin.as_deref(),
                         index: phrase.idx,
                         score: token_score,
-                        should_score,
+                        should_score: should_score.score,
+                        should_breakdown: should_score.breakdown.clone(),
                         token,
                         distance,
+                        additional_matches,
+                        edit_ops: if query.explain {
+                            edit_ops(query, marks, token, distance)
+                        } else {
+                            Vec::new()
+                        },
+                        matched_trigrams,
+                        sequence: phrase.sequence,
+                        matched_must: 0,
+                        within_distance: true,
+                        short_token_match,
                     });
 
                 best_distance = std::cmp::min(distance, best_distance);
@@ -249,33 +1189,849 @@ impl Index {
                  * - we reached the limit,
                  * - we already have "good enough" result by the distance metric,
                  * - we have considered solution with best must+should score.
+                 * Grouping by constraint can drop results later, so it needs
+                 * a full scan.
                  */
-               if best_distance == 0 && results.len() >= limit {
-                   break;
+               if !exhaustive && best_distance == 0 && results.len() >= limit
+                   && query.limit_per_constraint.is_none() {
+                   if !penalized {
+                       break;
+                   }
+                   draining = true;
                }
             }
         }
 
+        results
+    }
+
+    /// Best match of the must token spanning tokens written apart in the
+    /// phrase or in the query (see `Query::join_adjacent`). Two adjacent
+    /// phrase tokens are reported by the first of them, with their summed
+    /// trigram score. Only tokens and pairs hit by the must trigrams are
+    /// compared.
+    fn joined_match<'p>(&self, query: &Query, phrase: &'p PhraseEntry, phrase_heatmap: &heatmap::PhraseHeatmap,
+                        stats: &mut SearchStats) -> Option<(&'p String, u32, f32, usize)> {
+        let marks = self.index.config.marks;
+        let heat = |token_idx: usize| phrase_heatmap.tokens.get(&(token_idx as u32));
+        let score = |token_idx: usize| heat(token_idx).map_or(0.0, |token_heat| token_heat.score);
+        let mut best: Option<(&String, u32, f32, usize)> = None;
+        let mut consider = |candidate: Option<(&'p String, u32, f32, usize)>| {
+            if let Some(candidate) = candidate {
+                if best.is_none_or(|(.., distance)| candidate.3 < distance) {
+                    best = Some(candidate);
+                }
+            }
+        };
+
+        for second in 1..phrase.tokens.len() {
+            let first = second - 1;
+            if heat(first).is_none() && heat(second).is_none()
+                || !in_must_field(query, phrase, first) || !in_must_field(query, phrase, second) {
+                continue;
+            }
+            let joined = [phrase.tokens[first].as_str(), phrase.tokens[second].as_str()].concat();
+            consider(counted_distance(query, marks, &joined, &query.must, stats).map(|distance| {
+                (&phrase.tokens[first], first as u32, score(first) + score(second), distance)
+            }));
+        }
+        if let Some(must_joined) = &query.must_joined {
+            for (token_idx, token) in phrase.tokens.iter().enumerate() {
+                if heat(token_idx).is_none() || !in_must_field(query, phrase, token_idx) {
+                    continue;
+                }
+                consider(counted_distance(query, marks, token, must_joined, stats).map(|distance| {
+                    (token, token_idx as u32, score(token_idx), distance)
+                }));
+            }
+        }
+        best
+    }
+
+    /// Keep only the best `per_constraint` results of each constraint in the
+    /// sorted results. Phrase with many constraints counts toward each of them.
+    fn limit_per_constraint(&self, results: &mut Vec<SearchResult>, per_constraint: usize) {
+        let mut counts: HashMap<usize, usize, FastHash> = HashMap::with_hasher(FastHash::new());
+        results.retain(|result| self.count_constraints(&mut counts, result, per_constraint));
+    }
+
+    /// Count the result toward its constraints. Returns false if any of them
+    /// already has `per_constraint` results.
+    fn count_constraints(&self, counts: &mut HashMap<usize, usize, FastHash>,
+                         result: &SearchResult, per_constraint: usize) -> bool {
+        let constraints = &self.index.phrases[&result.index].constraints;
+        let full = constraints
+            .iter()
+            .any(|constraint| counts.get(constraint).copied().unwrap_or(0) >= per_constraint);
+        if full {
+            return false;
+        }
+        for constraint in constraints {
+            *counts.entry(*constraint).or_insert(0) += 1;
+        }
+        true
+    }
+
+    /// Return phrases with the query constraint ranked by should score only.
+    fn browse_results(&self, query: &Query, constraint: usize) -> Vec<SearchResult<'_>> {
+        let should_scores = self.should_scores(query, 0, |phrase_idx| {
+            self.index.phrases.get(&phrase_idx)
+                .is_some_and(|phrase| phrase.constraints.contains(&constraint))
+        });
+        let mut results: Vec<SearchResult> = self.index.phrases
+            .values()
+            .filter(|phrase| phrase.constraints.contains(&constraint))
+            .filter(|phrase| {
+                let matched = should_scores.get(&phrase.idx).map_or(0, |should| should.matched);
+                matched >= query.min_should_matches
+            })
+            .map(|phrase| {
+                SearchResult {
+                    origin: &phrase.origin,
+                    normalized_origin: phrase.normalized_origin.as_deref(),
+                    index: phrase.idx,
+                    token: "",
+                    distance: 0,
+                    score: 0.0,
+                    should_score: should_scores.get(&phrase.idx).map_or(0.0, |should| should.score),
+                    should_breakdown: should_scores.get(&phrase.idx)
+                        .map_or_else(Vec::new, |should| should.breakdown.clone()),
+                    additional_matches: Vec::new(),
+                    edit_ops: Vec::new(),
+                    matched_trigrams: Vec::new(),
+                    sequence: phrase.sequence,
+                    matched_must: 0,
+                    within_distance: true,
+                    short_token_match: false,
+                }
+            })
+            .collect();
+
         results.sort_unstable_by(|a, b| {
-            let side_a = (a.distance, -a.score, -a.should_score, a.origin.len(), &a.origin);
-            let side_b = (b.distance, -b.score, -b.should_score, b.origin.len(), &b.origin);
-            side_a.partial_cmp(&side_b).unwrap_or(Ordering::Equal)
+            decreasing(a.should_score, b.should_score)
+                .then_with(|| tiebreak_order(a, b, query.tiebreak))
         });
+        results.truncate(query.result_limit().unwrap_or(usize::MAX));
+        results
+    }
+
+    /// Verify that the query was created for the configuration of this index.
+    pub fn check_query(&self, query: &Query) -> Result<(), ConfigMismatch> {
+        if query.config_fingerprint == self.index.config.fingerprint() {
+            Ok(())
+        } else {
+            Err(ConfigMismatch)
+        }
+    }
+
+    /// The query, with must tokens normalized again if it was created for a
+    /// different index configuration.
+    fn conformed<'q>(&self, query: &'q Query) -> Cow<'q, Query> {
+        match self.check_query(query) {
+            Ok(()) => Cow::Borrowed(query),
+            Err(ConfigMismatch) => Cow::Owned(query.conformed(&self.index.config)),
+        }
+    }
+
+    /// Search the index. An empty must token together with a constraint
+    /// lists phrases having the constraint, ranked by should tokens. Without
+    /// a constraint an empty must returns no results.
+    ///
+    /// Must tokens of a query created for a different index configuration
+    /// are normalized again with the configuration of this index; see
+    /// `try_search` to reject such queries instead.
+    pub fn search(&self, query: &Query) -> Vec<SearchResult<'_>> {
+        let (results, _stats) = self.search_with_stats(query);
+        results
+    }
+
+    /// Search the index, failing if the query was created for a different
+    /// index configuration.
+    pub fn try_search(&self, query: &Query) -> Result<Vec<SearchResult<'_>>, ConfigMismatch> {
+        self.check_query(query)?;
+        Ok(self.search(query))
+    }
+
+    /// Return only the best result, or None if nothing matched. Query limit
+    /// is overridden to stop the scan as early as possible.
+    pub fn search_best(&self, query: &Query) -> Option<SearchResult<'_>> {
+        let query = query.clone().limit(Some(1));
+        self.search(&query).into_iter().next()
+    }
+
+    /// Create a query from free text: the most selective token becomes the
+    /// must and all others should tokens. Selectivity is the average score
+    /// of the token trigrams, so a rare token wins over a longer, but common
+    /// one. Trigrams missing from the index score 0, as they find nothing;
+    /// a misspelled token with a single rare trigram doesn't win. Longer
+    /// tokens win ties. None if the text has no tokens.
+    pub fn phrase_query(&self, phrase: &str) -> Option<Query> {
+        let tokens = self.index.config.tokenize(phrase);
+        let selectivity = |token: &str| {
+            let scores: Vec<f32> = self.must_trigrams(token)
+                .map(|(_trigram, entry)| entry.map_or(0.0, |entry| entry.score))
+                .collect();
+            if scores.is_empty() {
+                0.0
+            } else {
+                scores.iter().sum::<f32>() / scores.len() as f32
+            }
+        };
+        let (must_idx, _) = tokens.iter()
+            .enumerate()
+            .map(|(token_idx, token)| (token_idx, (selectivity(token), token.len())))
+            .max_by(|(_, (selectivity_a, length_a)), (_, (selectivity_b, length_b))| {
+                selectivity_a.total_cmp(selectivity_b).then(length_a.cmp(length_b))
+            })?;
+        let should: Vec<&str> = tokens.iter()
+            .enumerate()
+            .filter(|(token_idx, _)| *token_idx != must_idx)
+            .map(|(_, token)| token.as_str())
+            .collect();
+        Some(self.query(&[&tokens[must_idx]], &should))
+    }
+
+    /// Search using free text split into must and should tokens with
+    /// `phrase_query`. Text without tokens matches nothing.
+    pub fn search_phrase(&self, phrase: &str, limit: Option<usize>) -> Vec<SearchResult<'_>> {
+        match self.phrase_query(phrase) {
+            Some(query) => self.search(&query.limit(limit)),
+            None => Vec::new(),
+        }
+    }
+
+    /// Up to `n` distinct indexed tokens closest to the token by edit
+    /// distance, as (token, distance), eg. to suggest a spelling when a
+    /// search finds nothing. The token is normalized like a must token and
+    /// only tokens sharing some trigram with it are considered. Ties are
+    /// ordered by the shared trigram score and the token.
+    pub fn suggest(&self, token: &str, n: usize) -> Vec<(&str, usize)> {
+        let query = self.query(&[token], &[]);
+        if query.must.is_empty() || n == 0 {
+            return Vec::new();
+        }
+        let heatmap = self.heatmap_for(&query.must);
+
+        /* The same token may appear in many phrases; keep its best score */
+        let mut candidates: HashMap<&str, f32, FastHash> = HashMap::with_hasher(FastHash::new());
+        for phrase_heatmap in heatmap.phrases.values() {
+            let phrase = match self.index.phrases.get(&phrase_heatmap.phrase_idx) {
+                Some(phrase) => phrase,
+                None => continue,
+            };
+            for (&token_idx, token_heat) in phrase_heatmap.tokens.iter() {
+                let score = candidates.entry(&phrase.tokens[token_idx as usize]).or_insert(0.0);
+                *score = score.max(token_heat.score);
+            }
+        }
+
+        let order = |(distance_a, score_a, token_a): &(usize, f32, &str),
+                     (distance_b, score_b, token_b): &(usize, f32, &str)| {
+            distance_a.cmp(distance_b)
+                .then(score_b.total_cmp(score_a))
+                .then(token_a.cmp(token_b))
+        };
+        /* Tokens sharing the best trigrams are likely the closest ones. Once
+         * `n` tokens are found only distances within the worst of them are
+         * calculated, and most candidates are dropped early. */
+        let mut best: Vec<(usize, f32, &str)> = Vec::new();
+        let candidates = candidates.into_iter()
+            .sorted_by(|(_token_a, score_a), (_token_b, score_b)| score_b.total_cmp(score_a));
+        for (token, score) in candidates {
+            let distance = match best.get(n - 1) {
+                Some(&(worst, _score, _token)) => utils::distance_within(token, &query.must, worst),
+                None => Some(utils::distance(token, &query.must)),
+            };
+            if let Some(distance) = distance {
+                let candidate = (distance, score, token);
+                let position = best.partition_point(|found| order(found, &candidate).is_lt());
+                best.insert(position, candidate);
+                best.truncate(n);
+            }
+        }
+        best.into_iter()
+            .map(|(distance, _score, token)| (token, distance))
+            .collect()
+    }
+
+    /// Return every phrase with a must token within the query max distance,
+    /// ignoring `scan_cutoff`, the limits, the candidate budget and the
+    /// trigram filters (`must_trigram_cutoff`, `min_trigram_overlap`). Results
+    /// are ordered like in `search`, so by the distance first. Much slower
+    /// than `search` on common tokens; intended for batch jobs like
+    /// deduplication. Phrases dropped by `ScoringParams::max_positions`
+    /// still can't be found.
+    pub fn search_exhaustive(&self, query: &Query) -> Vec<SearchResult<'_>> {
+        let query = query.clone()
+            .scan_cutoff(0.0)
+            .limit(None)
+            .limit_per_constraint(None)
+            .limit_distinct_index(None)
+            .max_candidates(None)
+            .scan_multiplier(None)
+            .must_trigram_cutoff(None)
+            .min_trigram_overlap(0);
+        self.search(&query)
+    }
+
+    /// Recommend a `Query::scan_cutoff` for this corpus: the highest cutoff
+    /// (in steps of 0.05) for which the top results of every sample must
+    /// token are the same as with an exhaustive search. 0.0 (no cutoff) is
+    /// returned when none is safe or there are no samples.
+    ///
+    /// It's a heuristic meant for offline tuning: samples are searched many
+    /// times with the default query settings, and the recommendation is
+    /// only as good as the samples represent the real queries.
+    pub fn recommend_scan_cutoff(&self, sample_queries: &[&str]) -> f32 {
+        let top = |query: &Query, results: Vec<SearchResult>| -> Vec<(usize, usize)> {
+            results.iter()
+                .take(query.limit.unwrap_or(usize::MAX))
+                .map(|result| (result.index, result.distance))
+                .collect()
+        };
+        let samples: Vec<(Query, Vec<(usize, usize)>)> = sample_queries
+            .iter()
+            .map(|must| {
+                let query = self.query(&[must], &[]).limit(Some(CALIBRATION_TOP));
+                let expected = top(&query, self.search_exhaustive(&query));
+                (query, expected)
+            })
+            .collect();
+        if samples.is_empty() {
+            return 0.0;
+        }
+        (1..20)
+            .rev()
+            .map(|step| step as f32 / 20.0)
+            .find(|&cutoff| {
+                samples.iter().all(|(query, expected)| {
+                    let query = query.clone().scan_cutoff(cutoff);
+                    top(&query, self.search(&query)) == *expected
+                })
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// Search the index and pair each ranked result with a single confidence
+    /// in (0, 1] comparable between queries, eg. to calibrate an acceptance
+    /// threshold. It's calculated from the result as:
+    ///
+    /// ```text
+    /// similarity = 1 / (1 + distance)
+    /// coverage   = min(1, score / must_score)
+    /// should     = should_score / (1 + should_score)
+    /// confidence = similarity * (0.8 + 0.15 * coverage + 0.05 * should)
+    /// ```
+    ///
+    /// where `must_score` is the sum of index scores of all must token
+    /// trigrams (see `explain_must`), or coverage is 0 when it's 0. Any
+    /// result with a lower distance has a higher confidence.
+    pub fn search_scored(&self, query: &Query) -> Vec<(SearchResult<'_>, f32)> {
+        let must_score: f32 = self.explain_must(&query.must)
+            .iter()
+            .map(|must_trigram| must_trigram.score)
+            .sum();
+        self.search(query)
+            .into_iter()
+            .map(|result| {
+                let similarity = 1.0 / (1.0 + result.distance as f32);
+                let coverage = if must_score > 0.0 {
+                    (result.score / must_score).min(1.0)
+                } else {
+                    0.0
+                };
+                let should = result.should_score.max(0.0) / (1.0 + result.should_score.max(0.0));
+                let confidence = similarity * (0.8 + 0.15 * coverage + 0.05 * should);
+                (result, confidence)
+            })
+            .collect()
+    }
+
+    /// Search the index, reusing results of an identical earlier query when
+    /// the result cache is enabled (see `with_result_cache`). Without the
+    /// cache it's `search` returning owned results. Queries with a custom
+    /// `rank_fn` can't be compared and are never cached.
+    ///
+    /// Queries created for a different index configuration are normalized
+    /// again, like in `search`.
+    pub fn search_cached(&self, query: &Query) -> Vec<OwnedSearchResult> {
+        let result_cache = match &self.result_cache {
+            Some(result_cache) if query.rank_fn.is_none() => result_cache,
+            _ => return self.search(query).iter().map(OwnedSearchResult::from).collect(),
+        };
+        let conformed = self.conformed(query);
+        let query = conformed.as_ref();
+        let key = query.key();
+        {
+            let mut cache = result_cache.lock().unwrap();
+            let results = cache.results.get(&key).cloned();
+            if let Some(results) = results {
+                cache.stats.hits += 1;
+                return results;
+            }
+            cache.stats.misses += 1;
+        }
+
+        let results: Vec<OwnedSearchResult> = self.search(query)
+            .iter()
+            .map(OwnedSearchResult::from)
+            .collect();
+        let mut cache = result_cache.lock().unwrap();
+        cache.results.put(key, results.clone());
+        cache.stats.inserts += 1;
+        results
+    }
+
+    /// Statistics of the result cache, None if it's not enabled.
+    pub fn result_cache_stats(&self) -> Option<CacheStats> {
+        self.result_cache.as_ref().map(|result_cache| {
+            let cache = result_cache.lock().unwrap();
+            let mut stats = cache.stats.clone();
+            stats.size = cache.results.len();
+            stats
+        })
+    }
+
+    /// Search the index and report how the scan went.
+    pub fn search_with_stats(&self, query: &Query) -> (Vec<SearchResult<'_>>, SearchStats) {
+        let conformed = self.conformed(query);
+        let query = conformed.as_ref();
+        let mut stats = SearchStats::default();
+        let query = match self.resolve_constraint(query) {
+            Some(query) => query,
+            None => return (Vec::new(), stats),
+        };
+        let query = query.as_ref();
+        if query.must.is_empty() {
+            let results = match query.constraint {
+                Some(constraint) => self.browse_results(query, constraint),
+                None => Vec::new(),
+            };
+            return (results, stats);
+        }
+        if query.explain {
+            stats.must_trigrams = self.explain_must(&query.must);
+        }
+        let results = if query.must_any.is_empty() {
+            self.must_results(query, &mut stats)
+        } else {
+            self.any_must_results(query, &mut stats)
+        };
+        if results.is_empty() && query.fallback_best && query.max_distance.is_some() {
+            return (self.nearest_result(query, &mut stats), stats);
+        }
+        (self.ranked(query, results), stats)
+    }
+
+    /// Search like with `Query::must_any`, but return results grouped by the
+    /// matched must alternative (normalized like must tokens) instead of
+    /// merged. A phrase matching many alternatives is assigned only to its
+    /// best one (smallest distance, then score). Every alternative has an
+    /// entry, possibly empty; ranking and limits apply within each group.
+    ///
+    /// Queries created for a different index configuration are normalized
+    /// again, like in `search`.
+    pub fn search_grouped_by_must(&self, query: &Query) -> HashMap<String, Vec<SearchResult<'_>>> {
+        let conformed = self.conformed(query);
+        let query = conformed.as_ref();
+        let alternatives = query.must_alternatives(&self.index.config);
+        let mut groups: Vec<Vec<SearchResult>> = vec![Vec::new(); alternatives.len()];
+        if let Some(query) = self.resolve_constraint(query) {
+            let query = query.as_ref();
+            let results = if query.must.is_empty() {
+                match query.constraint {
+                    Some(constraint) => self.browse_results(query, constraint),
+                    None => Vec::new(),
+                }
+            } else {
+                self.any_must_results(query, &mut SearchStats::default())
+            };
+            for result in results {
+                groups[result.matched_must].push(result);
+            }
+        }
+        let mut grouped = HashMap::with_capacity(alternatives.len());
+        for (alternative, results) in alternatives.into_iter().zip(groups) {
+            let results = self.ranked(query, results);
+            grouped.entry(alternative).or_insert(results);
+        }
+        grouped
+    }
+
+    /// The closest result regardless of the query max distance, marked as
+    /// not within the distance.
+    fn nearest_result(&self, query: &Query, stats: &mut SearchStats) -> Vec<SearchResult<'_>> {
+        let query = query.clone().max_distance(None).limit(Some(1));
+        let results = self.must_results(&query, stats);
+        let mut results = self.ranked(&query, results);
+        for result in results.iter_mut() {
+            result.within_distance = false;
+        }
+        results
+    }
+
+    /// Search using a heatmap obtained earlier with `heatmap_for`, skipping
+    /// its computation and the cache lookup. The heatmap is expected to be
+    /// built for the query must token; the query must is not used otherwise.
+    ///
+    /// Queries created for a different index configuration are normalized
+    /// again, like in `search`.
+    pub fn search_with_heatmap(&self, query: &Query, heatmap: &Heatmap) -> Vec<SearchResult<'_>> {
+        let conformed = self.conformed(query);
+        let query = conformed.as_ref();
+        let query = match self.resolve_constraint(query) {
+            Some(query) => query,
+            None => return Vec::new(),
+        };
+        let query = query.as_ref();
+        let mut stats = SearchStats::default();
+        let should_scores = self.heatmap_should_scores(query, heatmap);
+        let results = self.scanned_results(query, heatmap, should_scores, &mut stats);
+        self.ranked(query, results)
+    }
+
+    /// Search candidates found by the given trigrams instead of the
+    /// trigrams of the must token (see `heatmap_from_trigrams`). The query
+    /// must token is still the reference for the distances and all other
+    /// query settings apply.
+    ///
+    /// Queries created for a different index configuration are normalized
+    /// again, like in `search`.
+    pub fn search_by_trigrams(&self, trigrams: &[String], query: &Query) -> Vec<SearchResult<'_>> {
+        let heatmap = self.heatmap_from_trigrams(trigrams);
+        self.search_with_heatmap(query, &heatmap)
+    }
+
+    /// Replace the string constraint of the query with its interned id.
+    /// None if the index doesn't know the string, so nothing can match.
+    /// Numeric constraints can't match phrases of an index using string
+    /// constraints either, even if they equal some interned id.
+    fn resolve_constraint<'q>(&self, query: &'q Query) -> Option<Cow<'q, Query>> {
+        match &query.constraint_name {
+            None if query.constraint.is_some() && !self.index.constraint_names.is_empty() => None,
+            None => Some(Cow::Borrowed(query)),
+            Some(name) => {
+                let constraint = self.index.constraint_id(name)?;
+                Some(Cow::Owned(query.clone().constraint(Some(constraint))))
+            }
+        }
+    }
 
-        results.truncate(limit);
+    /// Order scanned results and apply the query limits.
+    fn ranked<'a>(&self, query: &Query, mut results: Vec<SearchResult<'a>>) -> Vec<SearchResult<'a>> {
+        sort_ranked(query, &mut results);
+        if let Some(per_constraint) = query.limit_per_constraint {
+            self.limit_per_constraint(&mut results, per_constraint);
+        }
+        results.truncate(query.result_limit().unwrap_or(usize::MAX));
         results
     }
 
-    pub fn search(&self, query: &Query) -> Vec<SearchResult> {
-        let heatmap = self.create_heatmap(&query.must);
-        let should_scores = self.should_scores(&heatmap, &query.should, query.constraint);
-        self.filtered_results(query, &heatmap, should_scores)
+    /// Search the index and yield results lazily in the same order as
+    /// `search`. Candidates are still scanned up-front, but ranking is done
+    /// with a heap, so only the consumed results are fully ordered.
+    ///
+    /// Queries created for a different index configuration are normalized
+    /// again, like in `search`.
+    pub fn search_iter(&self, query: &Query) -> SearchIter<'_> {
+        let conformed = self.conformed(query);
+        let query = conformed.as_ref();
+        let resolved = self.resolve_constraint(query);
+        let query = resolved.as_deref().unwrap_or(query);
+        let (results, per_constraint) = if resolved.is_none() {
+            (Vec::new(), None)
+        } else if query.must.is_empty() {
+            let results = match query.constraint {
+                Some(constraint) => self.browse_results(query, constraint),
+                None => Vec::new(),
+            };
+            (results, None)
+        } else {
+            let mut stats = SearchStats::default();
+            let results = if query.must_any.is_empty() {
+                self.must_results(query, &mut stats)
+            } else {
+                self.any_must_results(query, &mut stats)
+            };
+            if results.is_empty() && query.fallback_best && query.max_distance.is_some() {
+                (self.nearest_result(query, &mut stats), None)
+            } else {
+                (results, query.limit_per_constraint)
+            }
+        };
+        SearchIter {
+            index: self,
+            heap: results
+                .into_iter()
+                .map(|result| {
+                    let rank = query.rank_fn.as_ref().map_or(0.0, |rank_fn| rank_fn.rank(&result));
+                    Ranked(result, query.tiebreak, rank)
+                })
+                .collect(),
+            per_constraint,
+            counts: HashMap::with_hasher(FastHash::new()),
+            remaining: query.result_limit().unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Unordered results matching the must token of the query.
+    fn must_results(&self, query: &Query, stats: &mut SearchStats) -> Vec<SearchResult<'_>> {
+        let heatmap = self.must_heatmap(query);
+        let should_scores = self.heatmap_should_scores(query, &heatmap);
+        self.scanned_results(query, &heatmap, should_scores, stats)
+    }
+
+    /// Unordered results matching any of the must alternatives, each phrase
+    /// with its best matching alternative.
+    fn any_must_results(&self, query: &Query, stats: &mut SearchStats) -> Vec<SearchResult<'_>> {
+        let mut best: HashMap<usize, SearchResult, FastHash> = HashMap::with_hasher(FastHash::new());
+        for (variant_idx, variant) in query.must_alternatives(&self.index.config).into_iter().enumerate() {
+            /* Each alternative has its own, cached heatmap */
+            let variant_query = Query {
+                must: variant,
+                must_any: Vec::new(),
+                /* Joined form belongs to the must token only */
+                must_joined: query.must_joined.clone().filter(|_| variant_idx == 0),
+                ..query.clone()
+            };
+            for mut result in self.must_results(&variant_query, stats) {
+                result.matched_must = variant_idx;
+                match best.entry(result.index) {
+                    Entry::Occupied(mut occupied) => {
+                        if rank_order(&result, occupied.get(), query.tiebreak) == Ordering::Less {
+                            occupied.insert(result);
+                        }
+                    }
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(result);
+                    }
+                }
+            }
+        }
+        best.into_values().collect()
+    }
+
+    /// Should scores of the heatmap phrases. With a constraint the heatmap
+    /// phrases are filtered once, instead of looking up the phrase of each
+    /// should trigram position.
+    fn heatmap_should_scores(&self, query: &Query, heatmap: &Heatmap) -> ShouldScores {
+        let constraint = match query.constraint {
+            Some(constraint) => constraint,
+            None => return self.should_scores(query, heatmap.len_phrases(),
+                                              |phrase_idx| heatmap.has_phrase(phrase_idx)),
+        };
+        let allowed: HashSet<usize, FastHash> = heatmap.phrases
+            .keys()
+            .copied()
+            .filter(|phrase_idx| {
+                self.index.phrases.get(phrase_idx)
+                    .is_some_and(|phrase| phrase.constraints.contains(&constraint))
+            })
+            .collect();
+        self.should_scores(query, allowed.len(), |phrase_idx| allowed.contains(&phrase_idx))
+    }
+
+    /// Settings used for indexing. Use `query` or `Query::with_config` to
+    /// create queries tokenized in the same way.
+    pub fn config(&self) -> &IndexerConfig {
+        &self.index.config
+    }
+
+    /// Check internal consistency of the index, eg. after loading it from a
+    /// file (see `Indexer::validate`).
+    pub fn validate(&self) -> Result<(), Vec<InconsistencyError>> {
+        self.index.validate()
+    }
+
+    /// Create a Query tokenized with the configuration of this index.
+    pub fn query(&self, must: &[&str], should: &[&str]) -> Query {
+        Query::with_config(&self.index.config, must, should)
+    }
+
+    /// Create a Query tokenized with the configuration of this index, with
+    /// a policy for must tokens split into many tokens.
+    pub fn query_with_must_split(&self, must: &[&str], should: &[&str],
+                                 split: MustSplit) -> Result<Query, MultiTokenMust> {
+        Query::with_must_split(&self.index.config, must, should, split)
+    }
+
+    /// Number of indexed phrases.
+    pub fn len(&self) -> usize {
+        self.index.phrases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.phrases.is_empty()
+    }
+
+    /// Check if the index has a phrase with the index.
+    pub fn contains(&self, phrase_idx: usize) -> bool {
+        self.index.contains(phrase_idx)
+    }
+
+    /// Iterate over indexed phrases as (phrase_idx, origin) in arbitrary order.
+    pub fn phrases(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.index.phrases
+            .values()
+            .map(|phrase| (phrase.idx, phrase.origin.as_str()))
+    }
+
+    /// Normalized tokens of the phrase, in the phrase order. Indices match
+    /// `TrigramEntryView::positions`, eg. to derive features of the matched
+    /// token position for re-ranking.
+    pub fn phrase_tokens(&self, phrase_idx: usize) -> Option<&[String]> {
+        self.index.phrases.get(&phrase_idx).map(|phrase| phrase.tokens.as_slice())
+    }
+
+    /// Constraints the phrase was added with.
+    pub fn phrase_constraints(&self, phrase_idx: usize) -> Option<&HashSet<usize, impl std::hash::BuildHasher>> {
+        self.index.phrases.get(&phrase_idx).map(|phrase| &phrase.constraints)
+    }
+
+    /// Read index data stored for a trigram.
+    pub fn trigram_entry(&self, trigram: &str) -> Option<TrigramEntryView> {
+        self.index.db.get(trigram).map(|entry| {
+            TrigramEntryView {
+                positions: entry.positions
+                    .iter()
+                    .map(|position| (position.phrase_idx, position.token_idx))
+                    .collect(),
+                score: entry.score,
+                capped: entry.capped,
+            }
+        })
+    }
+
+    /// Internal id of a string constraint (see
+    /// `Indexer::add_phrase_str_constraints`).
+    pub fn constraint_id(&self, name: &str) -> Option<usize> {
+        self.index.constraint_id(name)
+    }
+
+    /// Number of phrases having each constraint that occurs in the index.
+    pub fn constraints(&self) -> HashMap<usize, usize, FastHash> {
+        let mut counts: HashMap<usize, usize, FastHash> = HashMap::with_hasher(FastHash::new());
+        for phrase in self.index.phrases.values() {
+            for constraint in phrase.constraints.iter() {
+                *counts.entry(*constraint).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Show how the phrase was tokenized and trigramized, to tell
+    /// tokenization problems from scoring ones.
+    pub fn debug_phrase(&self, phrase_idx: usize) -> Option<PhraseDebug<'_>> {
+        self.index.phrases.get(&phrase_idx).map(|phrase| {
+            PhraseDebug {
+                origin: &phrase.origin,
+                tokens: phrase.tokens.iter().map(|token| token.as_str()).collect(),
+                trigrams: phrase.tokens.iter().map(|token| self.index.trigramize(token)).collect(),
+                constraints: phrase.constraints.iter().copied().sorted().collect(),
+            }
+        })
+    }
+
+    /// Populate the heatmap cache with a list of likely must tokens (eg. taken
+    /// from query logs) so first queries don't pay for cache misses. Tokens
+    /// are normalized like must tokens of `query`.
+    pub fn warm_cache(&self, tokens: &[&str]) -> WarmupStats {
+        let before = self.cache_stats();
+        for token in tokens {
+            let query = self.query(&[token], &[]);
+            if !query.must.is_empty() {
+                self.heatmap_for(&query.must);
+            }
+        }
+        let after = self.cache_stats();
+
+        let inserted = after.inserts - before.inserts;
+        WarmupStats {
+            inserted,
+            cached: after.hits - before.hits,
+            evicted: (before.size + inserted).saturating_sub(after.size),
+        }
+    }
+
+    /// Snapshots of the cached heatmaps keyed like in the cache, most
+    /// recently used first. Restore them with `import_cache` after a
+    /// restart to avoid slow first queries.
+    pub fn export_cache(&self) -> Vec<(HeatmapKey, HeatmapSnapshot)> {
+        let cache = self.cache.lock().unwrap();
+        cache.heatmaps
+            .iter()
+            .map(|(key, heatmap)| {
+                let snapshot = HeatmapSnapshot {
+                    heatmap: Heatmap::clone(heatmap),
+                    config_fingerprint: self.index.config.fingerprint(),
+                    trigrams: self.snapshot_trigrams(&key.token),
+                };
+                (key.clone(), snapshot)
+            })
+            .collect()
+    }
+
+    /// Put heatmaps exported by `export_cache` into the cache without
+    /// computing them, keeping their recency order. Snapshots the index
+    /// would build differently are skipped: of another configuration, with
+    /// changed must trigrams (positions or score) or referencing missing
+    /// phrases. Returns the number of imported heatmaps.
+    pub fn import_cache(&self, snapshots: Vec<(HeatmapKey, HeatmapSnapshot)>) -> usize {
+        let fingerprint = self.index.config.fingerprint();
+        let valid: Vec<(HeatmapKey, Heatmap)> = snapshots
+            .into_iter()
+            .filter(|(key, snapshot)| {
+                snapshot.config_fingerprint == fingerprint
+                    && snapshot.trigrams == self.snapshot_trigrams(&key.token)
+                    && snapshot.heatmap.phrases.values().all(|phrase| {
+                        self.index.phrases.get(&phrase.phrase_idx).is_some_and(|entry| {
+                            phrase.tokens.keys().all(|&token_idx| (token_idx as usize) < entry.tokens.len())
+                        })
+                    })
+            })
+            .map(|(key, snapshot)| (key, snapshot.heatmap))
+            .collect();
+        let imported = valid.len();
+        let mut cache = self.cache.lock().unwrap();
+        /* Least recently used first, so the order is restored */
+        for (key, heatmap) in valid.into_iter().rev() {
+            cache.put(key, Arc::new(heatmap));
+        }
+        imported
+    }
+
+    /// Must trigrams of a cached heatmap token with position counts,
+    /// digests of the positions and scores.
+    fn snapshot_trigrams(&self, token: &str) -> Vec<(String, usize, u64, f32)> {
+        self.must_trigrams(token)
+            .map(|(trigram, entry)| match entry {
+                Some(entry) => {
+                    /* Same phrases and tokens, in whatever order */
+                    let mut hasher = DefaultHasher::new();
+                    entry.positions
+                        .iter()
+                        .map(|position| (position.phrase_idx, position.token_idx))
+                        .sorted_unstable()
+                        .for_each(|position| position.hash(&mut hasher));
+                    (trigram, entry.positions.len(), hasher.finish(), entry.score)
+                }
+                None => (trigram, 0, 0, 0.0),
+            })
+            .collect()
+    }
+
+    /// Zero hit/miss/insert counters while keeping cached heatmaps.
+    pub fn cache_stats_reset(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.stats = CacheStats::default();
     }
 
+    /// Snapshot of the heatmap cache counters.
     pub fn cache_stats(&self) -> CacheStats {
         let cache = self.cache.lock().unwrap();
         let mut stats = cache.stats.clone();
         stats.size = cache.heatmaps.len();
+        stats.bytes = cache.bytes;
         stats
     }
 }
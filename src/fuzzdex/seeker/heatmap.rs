@@ -1,13 +1,24 @@
 use std::collections::HashMap;
+use std::mem::size_of;
+use serde::{Serialize, Deserialize};
 use super::FastHash;
 
+/// Must trigrams found in a single phrase token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenHeatmap {
+    /// Total score of the trigrams
+    pub score: f32,
+    /// Number of the trigrams
+    pub hits: u32,
+}
+
 /* Trigram heatmap is a partial query result */
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PhraseHeatmap {
     /// Phrase Index
     pub phrase_idx: usize,
-    /// Token trigram score: token_idx -> score
-    pub tokens: HashMap<u32, f32, FastHash>,
+    /// Token trigrams: token_idx -> score and hits
+    pub tokens: HashMap<u32, TokenHeatmap, FastHash>,
     /// Total phrase score
     pub total_score: f32,
 }
@@ -22,7 +33,7 @@ impl PhraseHeatmap {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Heatmap {
     /* Trigram score */
     /* phrase_idx -> token_idx -> score */
@@ -44,8 +55,9 @@ impl Heatmap {
             .or_insert_with(|| PhraseHeatmap::new(phrase_idx));
 
         /* Get or create token-level entry */
-        let token_score = phrase_level.tokens.entry(token_idx).or_insert(0.0);
-        *token_score += score;
+        let token_level = phrase_level.tokens.entry(token_idx).or_default();
+        token_level.score += score;
+        token_level.hits += 1;
 
         phrase_level.total_score += score;
         if phrase_level.total_score > self.max_score {
@@ -60,4 +72,22 @@ impl Heatmap {
     pub fn has_phrase(&self, phrase_idx: usize) -> bool {
         self.phrases.contains_key(&phrase_idx)
     }
+
+    /// Approximate memory used by the heatmap, counting the allocated
+    /// capacity of its maps (each entry plus a control byte).
+    pub fn estimated_bytes(&self) -> usize {
+        let phrase_entry = size_of::<(usize, PhraseHeatmap)>() + 1;
+        let token_entry = size_of::<(u32, TokenHeatmap)>() + 1;
+        let tokens: usize = self.phrases
+            .values()
+            .map(|phrase| phrase.tokens.capacity() * token_entry)
+            .sum();
+        size_of::<Heatmap>() + self.phrases.capacity() * phrase_entry + tokens
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,8 +1,18 @@
 use std::collections::HashSet;
+use std::sync::Arc;
+use itertools::Itertools;
 
 use super::FastHash;
-use super::Indexer;
-use super::query::Query;
+use crate::utils::MarkPolicy;
+use super::{Indexer, IndexerConfig, ScoringParams, FieldId, AddError, MergeError, InconsistencyError, MultiTokenMust, Position};
+use super::indexer::{LoadStats, Upsert};
+#[cfg(feature = "parallel")]
+use super::indexer::PhraseItem;
+use super::query::{Query, Metric, MustSplit, SearchMode, Similarity, Tiebreak, TokenPreference};
+use super::seeker::{Index, SearchResult, TrigramEntryView, OwnedSearchResult, CacheStats, HeatmapSnapshot, HeatmapKey,
+                    aggregate_cache_stats};
+use super::persist::PersistError;
+use super::federated::FederatedIndex;
 
 #[test]
 fn it_works() {
@@ -145,7 +155,7 @@ fn it_behaves_with_repeating_patterns() {
     let mut idx = super::Indexer::new();
 
     let repeating_phrase = "abcaBC";
-    idx.add_phrase(&repeating_phrase, 1, None).unwrap();
+    idx.add_phrase(repeating_phrase, 1, None).unwrap();
     let idx = idx.finish();
 
     /* Should generate only three trigrams: abc, bca, cab */
@@ -163,7 +173,7 @@ fn it_behaves_with_repeating_patterns() {
     /* Similar but duplicates in separate tokens */
     let mut idx = super::Indexer::new();
     let repeating_phrase = "abcx uabc";
-    idx.add_phrase(&repeating_phrase, 1, None).unwrap();
+    idx.add_phrase(repeating_phrase, 1, None).unwrap();
     let idx = idx.finish();
 
     let query = Query::new(&["abc"], &[]).limit(Some(3));
@@ -229,3 +239,2704 @@ fn it_detects_duplicate_phrase_idx() {
     let results = idx.search(&query);
     assert_eq!(results.len(), 0);
 }
+
+#[test]
+fn it_warms_the_cache() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    idx.add_phrase("Gdańsk", 3, None).unwrap();
+    let idx = idx.finish_with_cache(2);
+
+    let stats = idx.warm_cache(&["warszawa", "krakow"]);
+    assert_eq!(stats.inserted, 2);
+    assert_eq!(stats.cached, 0);
+    assert_eq!(stats.evicted, 0);
+
+    /* Warmed tokens are hits now */
+    idx.search(&Query::new(&["warszawa"], &[]));
+    assert_eq!(idx.cache_stats().hits, 1);
+
+    /* Third token pushes out the least recently used one */
+    let stats = idx.warm_cache(&["krakow", "gdansk"]);
+    assert_eq!(stats.inserted, 1);
+    assert_eq!(stats.cached, 1);
+    assert_eq!(stats.evicted, 1);
+    assert_eq!(idx.cache_stats().size, 2);
+
+    /* Tokens are normalized like the queries looking them up */
+    let stats = idx.warm_cache(&["Warszawa"]);
+    assert_eq!(stats.inserted, 1);
+    let hits = idx.cache_stats().hits;
+    idx.search(&idx.query(&["Warszawa"], &[]));
+    assert_eq!(idx.cache_stats().hits, hits + 1);
+    assert_eq!(idx.warm_cache(&["WARSZAWA"]).cached, 1);
+}
+
+#[test]
+fn it_resets_cache_stats() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]);
+    idx.search(&query);
+    idx.search(&query);
+    assert_eq!(idx.cache_stats().hits, 1);
+    assert_eq!(idx.cache_stats().misses, 1);
+
+    idx.cache_stats_reset();
+    let stats = idx.cache_stats();
+    assert_eq!(stats.hits, 0);
+    assert_eq!(stats.misses, 0);
+    assert_eq!(stats.inserts, 0);
+    assert_eq!(stats.size, 1);
+
+    /* Heatmap is still cached */
+    idx.search(&query);
+    assert_eq!(idx.cache_stats().hits, 1);
+    assert_eq!(idx.cache_stats().misses, 0);
+}
+
+#[test]
+fn it_boosts_misspelled_should_tokens_in_fuzzy_mode() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Dworzec Centralny", 1, None).unwrap();
+    idx.add_phrase("Dworzec Trasa", 2, None).unwrap();
+    let idx = idx.finish();
+
+    /* Both phrases share only the "tra" trigram with the misspelled token */
+    let query = Query::new(&["dworzec"], &["cnetralny"]).limit(Some(1));
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 2);
+    let plain_score = results[0].should_score;
+
+    let query = Query::new(&["dworzec"], &["cnetralny"]).limit(Some(1)).fuzzy_should(true);
+    let results = idx.search(&query);
+    for result in &results {
+        println!("Got result {:?}", result);
+    }
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert!(results[0].should_score > plain_score);
+}
+
+#[test]
+fn it_browses_by_constraint_without_must() {
+    let mut idx = Indexer::new();
+    let mut region: HashSet<usize, FastHash> = HashSet::with_hasher(FastHash::new());
+    region.insert(7);
+
+    idx.add_phrase("Dworcowa", 1, Some(&region)).unwrap();
+    idx.add_phrase("Rynek Główny", 2, Some(&region)).unwrap();
+    idx.add_phrase("Rynek Starego Miasta", 3, Some(&region)).unwrap();
+    idx.add_phrase("Rynek", 4, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&[""], &["rynek"]).constraint(Some(7));
+    let results = idx.search(&query);
+    for result in &results {
+        println!("Got result {:?}", result);
+    }
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].index, 2);
+    assert_eq!(results[1].index, 3);
+    assert_eq!(results[2].index, 1);
+    assert_eq!(results[2].should_score, 0.0);
+
+    let query = Query::new(&[""], &["rynek"]).constraint(Some(7)).limit(Some(1));
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 2);
+
+    /* Empty must without a constraint matches nothing */
+    let query = Query::new(&[""], &["rynek"]);
+    assert!(idx.search(&query).is_empty());
+}
+
+#[test]
+fn it_lists_phrases() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    let idx = idx.finish();
+
+    assert_eq!(idx.len(), 2);
+    let mut phrases: Vec<(usize, &str)> = idx.phrases().collect();
+    phrases.sort();
+    assert_eq!(phrases, vec![(1, "Warszawa"), (2, "Kraków")]);
+}
+
+#[test]
+fn it_requires_all_must_tokens() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Pałac Kultury i Nauki", 1, None).unwrap();
+    idx.add_phrase("Pałac na Wodzie", 2, None).unwrap();
+    idx.add_phrase("Dom Kultury", 3, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["palac", "kultury"], &[]);
+    assert_eq!(query.must, "palac");
+    assert_eq!(query.additional_must, vec!["kultury".to_string()]);
+    let results = idx.search(&query);
+    for result in &results {
+        println!("Got result {:?}", result);
+    }
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].token, "pałac");
+    assert_eq!(results[0].distance, 1);
+    assert_eq!(results[0].additional_matches, vec![("kultury", 0)]);
+
+    /* Additional must token is typo-tolerant too */
+    let query = Query::new(&["palac", "wodze"], &[]);
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 2);
+    assert_eq!(results[0].additional_matches, vec![("wodzie", 1)]);
+}
+
+#[test]
+fn it_saves_and_loads_index() {
+    let mut idx = Indexer::new();
+    let mut constraints: HashSet<usize, FastHash> = HashSet::with_hasher(FastHash::new());
+    constraints.insert(3);
+    idx.add_phrase("Warszawa", 1, Some(&constraints)).unwrap();
+    idx.add_phrase("Rakszawa", 2, None).unwrap();
+    idx.add_phrase("Warszew", 7, None).unwrap();
+    let idx = idx.finish();
+
+    let path = std::env::temp_dir().join(format!("fuzzdex-test-{}.idx", std::process::id()));
+    idx.save(&path).unwrap();
+    let loaded = Index::load(&path, 10).unwrap();
+    assert_eq!(loaded.cache_capacity(), 10);
+    let sized = Index::load_with_cache_size(&path, None).unwrap();
+    assert_eq!(sized.cache_capacity(), 500);
+
+    let query = Query::new(&["waszawa"], &[]).constraint(Some(3));
+    assert_eq!(idx.search(&query), loaded.search(&query));
+    assert_eq!(loaded.search(&query)[0].index, 1);
+
+    /* Truncated data is detected */
+    let data = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+    assert!(matches!(Index::load(&path, 10), Err(PersistError::Corrupted(_))));
+
+    std::fs::write(&path, b"something else").unwrap();
+    assert!(matches!(Index::load(&path, 10), Err(PersistError::NotAnIndex)));
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(Index::load(&path, 10), Err(PersistError::Io(_))));
+}
+
+#[test]
+fn it_compacts_memory() {
+    let mut idx = Indexer::new();
+    for i in 0..20000 {
+        let phrase = format!("street {} number {}", i * 7919 % 10007, i);
+        idx.add_phrase(&phrase, i, None).unwrap();
+    }
+    let before = idx.heap_size();
+    idx.compact();
+    let after = idx.heap_size();
+    println!("Heap size before compaction: {} after: {} ({:.1}% saved)",
+             before, after, 100.0 * (before - after) as f64 / before as f64);
+    assert!(after < before);
+
+    let idx = idx.finish();
+    let query = Query::new(&["street"], &["1234"]).limit(Some(1));
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].origin.contains("1234"));
+}
+
+#[test]
+fn it_stops_scanning_on_candidate_budget() {
+    let mut idx = Indexer::new();
+    for i in 0..100 {
+        idx.add_phrase(&format!("Warszawska {}", i), i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    /* No exact match - without budget everything is scanned */
+    let query = Query::new(&["warszawsk"], &[]);
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(results.len(), 100);
+    assert_eq!(stats.scanned, 100);
+    assert!(!stats.truncated);
+
+    let query = Query::new(&["warszawsk"], &[]).max_candidates(Some(10));
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(results.len(), 10);
+    assert_eq!(stats.scanned, 10);
+    assert!(stats.truncated);
+}
+
+#[test]
+fn it_matches_with_jaro_winkler() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Martha", 1, None).unwrap();
+    idx.add_phrase("Arthur", 2, None).unwrap();
+    let idx = idx.finish();
+
+    /* Swapped letters cost two Levenshtein edits */
+    let query = Query::new(&["marhta"], &[]).max_distance(Some(1));
+    assert!(idx.search(&query).is_empty());
+
+    let query = Query::new(&["marhta"], &[])
+        .metric(Metric::JaroWinkler)
+        .max_distance(Some(5));
+    let results = idx.search(&query);
+    for result in &results {
+        println!("Got result {:?}", result);
+    }
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].distance, 4);
+}
+
+#[test]
+fn it_reads_trigram_entries() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("abcd", 1, None).unwrap();
+    idx.add_phrase("xx abce", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let entry = idx.trigram_entry("abc").unwrap();
+    let mut positions = entry.positions.clone();
+    positions.sort();
+    assert_eq!(positions, vec![(1, 0), (2, 1)]);
+    assert!(entry.score > 0.0 && entry.score <= 1.0);
+
+    /* Rarer trigram scores higher */
+    assert!(idx.trigram_entry("bcd").unwrap().score > entry.score);
+    assert!(idx.trigram_entry("zzz").is_none());
+}
+
+#[test]
+fn it_uses_custom_scoring_params() {
+    let build = |params: ScoringParams| {
+        let mut idx = Indexer::new();
+        for i in 0..20 {
+            idx.add_phrase(&format!("common{}", i), i, None).unwrap();
+        }
+        idx.add_phrase("rare", 100, None).unwrap();
+        idx.finish_with_params(10, params)
+    };
+
+    let default = build(ScoringParams::default());
+    let steep = build(ScoringParams { steepness: 20.0, ..ScoringParams::default() });
+
+    let separation = |idx: &Index| {
+        idx.trigram_entry("rar").unwrap().score - idx.trigram_entry("com").unwrap().score
+    };
+    assert!(separation(&default) > 0.0);
+    assert!(separation(&steep) > separation(&default));
+
+    /* Defaults are the same as plain finish */
+    let mut idx = Indexer::new();
+    idx.add_phrase("rare", 100, None).unwrap();
+    idx.add_phrase("common", 1, None).unwrap();
+    let plain = idx.finish();
+    let mut idx = Indexer::new();
+    idx.add_phrase("rare", 100, None).unwrap();
+    idx.add_phrase("common", 1, None).unwrap();
+    let params = idx.finish_with_params(10, ScoringParams::default());
+    assert_eq!(plain.trigram_entry("rar"), params.trigram_entry("rar"));
+}
+
+#[test]
+fn it_tolerates_typos_in_longer_tokens_with_extended_tolerance() {
+    let build = |extended: bool| {
+        let config = IndexerConfig::new().extended_tolerance(extended);
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("Opolska", 1, None).unwrap();
+        idx.finish()
+    };
+
+    /* Typo in every regular trigram of the 7-letter token */
+    let idx = build(false);
+    assert!(idx.search(&idx.query(&["opxlxka"], &[])).is_empty());
+    let idx = build(true);
+    let results = idx.search(&idx.query(&["opxlxka"], &[]));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].distance, 2);
+}
+
+#[test]
+fn it_limits_results_per_constraint() {
+    let mut idx = Indexer::new();
+    let region = |ids: &[usize]| -> HashSet<usize, FastHash> {
+        ids.iter().copied().collect()
+    };
+
+    for i in 0..4 {
+        idx.add_phrase(&format!("Main Street {}", i), 10 + i, Some(&region(&[1]))).unwrap();
+        idx.add_phrase(&format!("Main Street {}", i), 20 + i, Some(&region(&[2]))).unwrap();
+        idx.add_phrase(&format!("Main Street {}", i), 30 + i, Some(&region(&[3]))).unwrap();
+    }
+    /* Counts toward both regions */
+    idx.add_phrase("Main", 40, Some(&region(&[1, 2]))).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["main"], &[]).limit_per_constraint(Some(2));
+    let results = idx.search(&query);
+    for result in &results {
+        println!("Got result {:?}", result);
+    }
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0].index, 40);
+    let in_range = |from: usize| results.iter().filter(|r| r.index >= from && r.index < from + 10).count();
+    assert_eq!(in_range(10), 1);
+    assert_eq!(in_range(20), 1);
+    assert_eq!(in_range(30), 2);
+
+    /* Global limit is applied afterwards */
+    let query = Query::new(&["main"], &[]).limit_per_constraint(Some(2)).limit(Some(3));
+    assert_eq!(idx.search(&query).len(), 3);
+}
+
+#[test]
+fn it_uses_index_config_for_queries() {
+    let config = IndexerConfig::new().extended_tolerance(true);
+    let mut idx = Indexer::with_config(config.clone());
+    idx.add_phrase("Opolska", 1, None).unwrap();
+    let idx = idx.finish();
+    assert_eq!(idx.config(), &config);
+
+    /* Must token is normalized like the indexed phrases */
+    let query = idx.query(&["OPXLXKA"], &[]);
+    assert_eq!(query.must, "opxlxka");
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+}
+
+#[test]
+fn it_builds_identical_index_with_capacity_hints() {
+    let phrases: Vec<String> = (0..2000)
+        .map(|i| format!("street {} number {}", i * 7919 % 10007, i))
+        .collect();
+
+    let mut default = Indexer::new();
+    let mut hinted = Indexer::with_capacity(1000, 100000);
+    hinted.reserve(1000);
+    for (i, phrase) in phrases.iter().enumerate() {
+        default.add_phrase(phrase, i, None).unwrap();
+        hinted.add_phrase(phrase, i, None).unwrap();
+    }
+    let default = default.finish();
+    let hinted = hinted.finish();
+
+    assert_eq!(default.len(), hinted.len());
+    assert_eq!(default.index.db.len(), hinted.index.db.len());
+    for trigram in default.index.db.keys() {
+        assert_eq!(default.trigram_entry(trigram), hinted.trigram_entry(trigram));
+    }
+
+    /* Equally ranked phrases can be found in a different order; compare
+     * all results as sets and the limited ones by their ranks only */
+    let key = |results: Vec<super::seeker::SearchResult>| {
+        let mut key: Vec<(usize, usize, u32)> = results
+            .iter()
+            .map(|result| (result.index, result.distance, result.score.to_bits()))
+            .collect();
+        key.sort_unstable();
+        key
+    };
+    let query = Query::new(&["street"], &["1234"]).scan_cutoff(0.0);
+    assert_eq!(key(default.search(&query)), key(hinted.search(&query)));
+
+    let ranks = |results: Vec<super::seeker::SearchResult>| results
+        .iter()
+        .map(|result| (result.distance, result.score, result.should_score))
+        .collect::<Vec<_>>();
+    let query = Query::new(&["street"], &["1234"]).limit(Some(3));
+    assert_eq!(ranks(default.search(&query)), ranks(hinted.search(&query)));
+}
+
+#[test]
+fn it_detects_queries_for_other_config() {
+    let config = IndexerConfig::new().extended_tolerance(true);
+    let mut idx = Indexer::with_config(config.clone());
+    idx.add_phrase("Opolska", 1, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["opolska"], &[]);
+    assert!(idx.check_query(&query).is_err());
+    assert!(idx.try_search(&query).is_err());
+    /* Other entry points normalize the query again */
+    assert_eq!(idx.search(&query).len(), 1);
+
+    let query = Query::with_config(&config, &["opolska"], &[]);
+    assert_eq!(idx.try_search(&query).unwrap().len(), 1);
+
+    let config = IndexerConfig::new().split_digits(true);
+    let mut idx = Indexer::with_config(config.clone());
+    idx.add_phrase("Opolska 12B", 1, None).unwrap();
+    idx.add_phrase("Opolska 14", 2, None).unwrap();
+    let idx = idx.finish();
+    let query = Query::new(&["12B"], &["opolska"]).scan_cutoff(0.0);
+    let expected = idx.search(&Query::with_config(&config, &["12B"], &["opolska"]).scan_cutoff(0.0));
+    assert_eq!(idx.search(&query), expected);
+    assert_eq!(expected[0].index, 1);
+    assert_eq!(idx.search_iter(&query).collect::<Vec<_>>(), expected);
+    assert_eq!(idx.search_grouped_by_must(&query)["12"], expected);
+}
+
+#[test]
+fn it_loads_phrases_from_tsv() {
+    let data = "1\tWarszawa\n2\tKraków\nbroken line\nx\tNo index\n1\tDuplicated\n3\tGdańsk\n";
+    let mut idx = Indexer::new();
+    let stats = idx.add_from_tsv(std::io::Cursor::new(data)).unwrap();
+    assert_eq!(stats, LoadStats { added: 3, skipped: 2, duplicates: 1, empty: 0, unsearchable: 0 });
+
+    let idx = idx.finish();
+    assert_eq!(idx.len(), 3);
+    let results = idx.search(&Query::new(&["krakow"], &[]));
+    assert_eq!(results[0].index, 2);
+    assert!(idx.search(&Query::new(&["duplicated"], &[])).is_empty());
+}
+
+#[test]
+fn it_explains_should_scores() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Dworzec Centralny Warszawa", 1, None).unwrap();
+    idx.add_phrase("Dworzec Wschodni", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["dworzec"], &["centralny", "warszawa", "gdansk"]);
+    let results = idx.search(&query);
+    assert!(results.iter().all(|result| result.should_breakdown.is_empty()));
+
+    let results = idx.search(&query.explain(true));
+    for result in &results {
+        println!("Got result {:?}", result);
+    }
+    assert_eq!(results[0].index, 1);
+    let breakdown = &results[0].should_breakdown;
+    assert_eq!(breakdown.len(), 2);
+    assert_eq!(breakdown[0].0, "centralny");
+    assert_eq!(breakdown[1].0, "warszawa");
+    let total: f32 = breakdown.iter().map(|(_, score)| score).sum();
+    assert!((total - results[0].should_score).abs() < 1e-6);
+    assert!(results[1].should_breakdown.is_empty());
+}
+
+#[test]
+fn it_upserts_phrases() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    assert_eq!(idx.upsert_phrase("Kraków", 3, None), Ok(Upsert::Inserted));
+    assert_eq!(idx.upsert_phrase("Gdańsk", 1, None), Ok(Upsert::Replaced));
+
+    /* Only trigrams of the replaced phrase are gone */
+    assert_eq!(idx.db["war"].positions.len(), 1);
+    assert!(!idx.db.contains_key("awa"));
+
+    assert!(idx.remove_phrase(3));
+    assert!(!idx.remove_phrase(3));
+    assert!(!idx.db.contains_key("kra"));
+
+    let idx = idx.finish();
+    assert_eq!(idx.len(), 2);
+    let results = idx.search(&Query::new(&["warszawa"], &[]));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 2);
+    let results = idx.search(&Query::new(&["gdansk"], &[]));
+    assert_eq!(results[0].index, 1);
+}
+
+#[test]
+fn it_iterates_results_in_search_order() {
+    let mut idx = Indexer::new();
+    for (i, phrase) in ["Warszawa", "Warszawka", "Warszowa", "Warsawa", "Wąwóz",
+                        "Warszawa Zachodnia", "Warta", "Barszawa"].iter().enumerate() {
+        idx.add_phrase(phrase, i, Some(&[i % 3].iter().cloned().collect())).unwrap();
+    }
+    let idx = idx.finish();
+
+    for query in [Query::new(&["warszawa"], &["zachodnia"]).limit(Some(4)),
+                  Query::new(&["warszawa"], &[]).limit(None),
+                  Query::new(&["warszawa"], &[]).limit_per_constraint(Some(1))] {
+        let results = idx.search(&query);
+        let lazy: Vec<_> = idx.search_iter(&query).collect();
+        println!("Got result {:?}", lazy);
+        let key = |results: &[super::seeker::SearchResult]| results
+            .iter()
+            .map(|result| (result.origin.to_string(), result.distance))
+            .collect::<Vec<_>>();
+        assert_eq!(key(&results), key(&lazy));
+    }
+
+    /* Consumer can stop early */
+    let query = Query::new(&["warszawa"], &[]).limit(None);
+    let best = idx.search_iter(&query).next().unwrap();
+    assert_eq!(best.origin, "Warszawa");
+}
+
+#[test]
+fn it_lists_unsearchable_phrases() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("\u{301}", 2, None).unwrap();
+    idx.add_phrase("\u{301}\u{308}", 3, None).unwrap();
+    idx.add_phrase("\u{fffd}\u{fffd}", 4, None).unwrap();
+    assert_eq!(idx.unsearchable_phrases(), vec![2, 3]);
+
+    let stats = idx.add_from_tsv("5\t\u{301}\n6\tKraków\n".as_bytes()).unwrap();
+    assert_eq!(stats.unsearchable, 1);
+    assert_eq!(idx.unsearchable_phrases(), vec![2, 3, 5]);
+
+    /* Replacement characters are ordinary graphemes */
+    let idx = idx.finish();
+    let results = idx.search(&Query::new(&["\u{fffd}\u{fffd}"], &[]));
+    assert_eq!(results[0].index, 4);
+}
+
+#[test]
+fn it_finds_best_result() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let best = idx.search_best(&Query::new(&["warszawka"], &[]).limit(None));
+    assert_eq!(best.map(|result| result.index), Some(2));
+    assert!(idx.search_best(&Query::new(&["gdańsk"], &[])).is_none());
+}
+
+/// Finished index contents comparable between differently built indices.
+fn index_snapshot(idx: &Index) -> Vec<(String, TrigramEntryView)> {
+    idx.index.db
+        .keys()
+        .sorted()
+        .map(|trigram| (trigram.clone(), idx.trigram_entry(trigram).unwrap()))
+        .collect()
+}
+
+#[test]
+fn it_clears_indexer_for_reuse() {
+    let mut idx = Indexer::new();
+    idx.add_phrase_str_constraints("Warszawa", 1, &["mazowieckie"]).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    let capacity = idx.db.capacity();
+    let trigrams = idx.db.len();
+
+    idx.clear();
+    assert!(!idx.contains(1));
+    assert_eq!(idx.db.capacity(), capacity);
+    /* Position vectors are kept for new trigrams */
+    assert_eq!(idx.spare_positions.len(), trigrams);
+    assert!(idx.spare_positions.iter().all(|positions| positions.is_empty() && positions.capacity() > 0));
+    assert_eq!(idx.constraint_id("mazowieckie"), None);
+
+    idx.add_phrase("Gdańsk", 1, None).unwrap();
+    idx.add_phrase("Gdynia", 3, None).unwrap();
+    assert_eq!(idx.spare_positions.len(), trigrams - idx.db.len());
+    let idx = idx.finish();
+    assert!(idx.index.spare_positions.is_empty());
+    assert_eq!(idx.len(), 2);
+    assert!(idx.search(&Query::new(&["warszawa"], &[])).is_empty());
+    let results = idx.search(&Query::new(&["gdansk"], &[]));
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].origin, "Gdańsk");
+}
+
+#[test]
+fn it_merges_indexers() {
+    let phrases = ["Warszawa", "Warszawka", "Kraków", "Wrocław", "Warta"];
+    let mut whole = Indexer::new();
+    let mut first = Indexer::new();
+    let mut second = Indexer::new();
+    for (i, phrase) in phrases.iter().enumerate() {
+        whole.add_phrase(phrase, i, None).unwrap();
+        let shard = if i % 2 == 0 { &mut first } else { &mut second };
+        shard.add_phrase(phrase, i, None).unwrap();
+    }
+    let mut duplicate = Indexer::new();
+    duplicate.add_phrase("Gdańsk", 1, None).unwrap();
+
+    first.merge(second).unwrap();
+    assert_eq!(first.merge(duplicate), Err(MergeError::DuplicateId));
+    let other = Indexer::with_config(IndexerConfig::new().split_digits(true));
+    assert_eq!(first.merge(other), Err(MergeError::ConfigMismatch));
+    assert_eq!(index_snapshot(&first.finish()), index_snapshot(&whole.finish()));
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn it_builds_in_parallel() {
+    let items: Vec<PhraseItem> = (0..500)
+        .map(|i| (i, format!("Warszawska {} Kraków {}", i, i % 7),
+                  Some([i % 5].iter().cloned().collect())))
+        .collect();
+
+    let build = |threads: usize, items: Vec<_>| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| Indexer::build_parallel(items))
+            .unwrap()
+            .finish()
+    };
+    let single = build(1, items.clone());
+    let reversed = build(4, items.iter().rev().cloned().collect());
+    assert_eq!(index_snapshot(&single), index_snapshot(&reversed));
+    assert_eq!(reversed.len(), 500);
+
+    let mut duplicated = items.clone();
+    duplicated.push(items[3].clone());
+    assert!(Indexer::build_parallel(duplicated).is_err());
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn it_scores_in_parallel() {
+    /* Enough trigrams to be split between threads */
+    let build = || {
+        let mut idx = Indexer::new();
+        for i in 0..3000 {
+            let word: String = (0..5)
+                .map(|shift| (b'a' + (((i * 7919) >> (shift * 4)) % 26) as u8) as char)
+                .collect();
+            idx.add_phrase(&format!("Warszawska {} {}", word, i), i, None).unwrap();
+        }
+        idx
+    };
+    let finish = |threads: usize| {
+        let mut steps = Vec::new();
+        let index = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| build().finish_with_progress(0, |processed, total| steps.push((processed, total))));
+        (index_snapshot(&index), steps)
+    };
+    let (single, single_steps) = finish(1);
+    let (multi, multi_steps) = finish(4);
+    assert_eq!(single, multi);
+    assert_eq!(single_steps, multi_steps);
+    assert_eq!(multi_steps, vec![(0, multi.len()), (multi.len(), multi.len())]);
+
+    /* Progress between chunks of large passes */
+    let mut idx = Indexer::new();
+    for i in 0..20000 {
+        /* Distinct letter trigrams, in base 26 */
+        let word: String = [i / 676, i / 26, i]
+            .iter()
+            .map(|digit| (b'a' + (digit % 26) as u8) as char)
+            .collect();
+        idx.add_phrase(&format!("{} {}", word, i), i, None).unwrap();
+    }
+    let trigrams = idx.db.len();
+    assert!(trigrams > 16384);
+    let mut steps = Vec::new();
+    idx.finish_with_progress(0, |processed, total| steps.push((processed, total)));
+    assert_eq!(steps.first(), Some(&(0, trigrams)));
+    assert_eq!(steps.last(), Some(&(trigrams, trigrams)));
+    assert!(steps.len() > 2);
+    assert!(steps.windows(2).all(|pair| pair[0].0 < pair[1].0));
+}
+
+#[test]
+fn it_rejects_short_must_tokens() {
+    let query = Query::new(&["łó"], &[]).min_must_length(3);
+    let err = query.unwrap_err();
+    assert_eq!(err.token, "łó");
+    assert_eq!(err.min_length, 3);
+
+    assert!(Query::new(&["łódź"], &[]).min_must_length(4).is_ok());
+    assert!(Query::new(&["łódź", "pl"], &[]).min_must_length(3).is_err());
+    /* Browsing by constraint has no must token to check */
+    assert!(Query::new(&[""], &[]).min_must_length(3).is_ok());
+}
+
+#[test]
+fn it_shows_phrase_tokenization() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Ul. Wąska", 1, Some(&[3, 1].iter().cloned().collect())).unwrap();
+    let idx = idx.finish();
+
+    let debug = idx.debug_phrase(1).unwrap();
+    println!("Got debug {:?}", debug);
+    assert_eq!(debug.origin, "Ul. Wąska");
+    assert_eq!(debug.tokens, vec!["ul", "wąska"]);
+    assert_eq!(debug.trigrams[0], vec!["ul "]);
+    assert!(debug.trigrams[1].contains(&"was".to_string()));
+    assert_eq!(debug.constraints, vec![1, 3]);
+    assert!(idx.debug_phrase(2).is_none());
+}
+
+#[test]
+fn it_scans_everything_without_cutoff() {
+    let mut idx = Indexer::new();
+    /* Repeated token collects a much higher trigram score */
+    idx.add_phrase("Warszawa Warszawa Warszawa Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawa", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).limit(None);
+    let results = idx.search(&query);
+    println!("Got result {:?}", results);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+
+    let query = query.scan_cutoff(0.0);
+    let (results, stats) = idx.search_with_stats(&query);
+    println!("Got result {:?}", results);
+    assert_eq!(results.len(), 2);
+    assert_eq!(stats.scanned, 2);
+}
+
+#[test]
+fn it_explains_must_trigrams() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    let idx = idx.finish();
+
+    let trigrams = idx.explain_must("warsaw");
+    println!("Got trigrams {:?}", trigrams);
+    let present: Vec<(&str, bool)> = trigrams
+        .iter()
+        .map(|must_trigram| (must_trigram.trigram.as_str(), must_trigram.present))
+        .collect();
+    assert_eq!(present, vec![("war", true), ("ars", true), ("rsa", false), ("saw", false)]);
+    assert!(trigrams[0].score > 0.0);
+    assert_eq!(trigrams[2].score, 0.0);
+
+    let query = Query::new(&["gdansk"], &[]).explain(true);
+    let (results, stats) = idx.search_with_stats(&query);
+    assert!(results.is_empty());
+    assert!(stats.must_trigrams.iter().all(|must_trigram| !must_trigram.present));
+}
+
+#[test]
+fn it_searches_shared_index_from_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Index>();
+
+    let mut idx = Indexer::new();
+    for i in 0..100 {
+        idx.add_phrase(&format!("Warszawska {}", i), i, None).unwrap();
+    }
+    idx.add_phrase("Kraków", 100, None).unwrap();
+    let idx = std::sync::Arc::new(idx.finish_with_cache(4));
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let idx = std::sync::Arc::clone(&idx);
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let must = if thread % 2 == 0 { "krakow" } else { "warszawsk" };
+                    let results = idx.search(&Query::new(&[must], &[]).limit(Some(3)));
+                    assert_eq!(results.len(), if thread % 2 == 0 { 1 } else { 3 });
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(idx.cache_stats().hits + idx.cache_stats().misses, 8 * 50);
+}
+
+#[test]
+fn it_handles_extreme_limits() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Warszówka", 3, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).scan_cutoff(0.0);
+    /* Zero limit means no limit */
+    let query_zero = query.clone().limit(Some(0));
+    assert_eq!(query_zero.limit, None);
+    assert_eq!(idx.search(&query_zero).len(), 3);
+
+    let results = idx.search(&query.clone().limit(Some(1)));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+
+    /* Huge limit returns all candidates without reserving for the limit */
+    assert_eq!(idx.search(&query.clone().limit(Some(usize::MAX))).len(), 3);
+    assert_eq!(idx.search_iter(&query.limit(Some(usize::MAX))).count(), 3);
+}
+
+#[test]
+fn it_caches_whole_results() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    let idx = idx.finish().with_result_cache(1);
+
+    let query = Query::new(&["warszawa"], &[]).limit(Some(1)).scan_cutoff(0.0);
+    let first = idx.search_cached(&query);
+    let second = idx.search_cached(&query);
+    assert_eq!(first, second);
+    assert_eq!(first[0].origin, "Warszawa");
+    let stats = idx.result_cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.inserts, stats.size), (1, 1, 1, 1));
+    /* Heatmap was computed once */
+    assert_eq!(idx.cache_stats().misses, 1);
+
+    /* Different limit is a different query */
+    let results = idx.search_cached(&query.limit(Some(2)));
+    assert_eq!(results.len(), 2);
+    assert_eq!(idx.result_cache_stats().unwrap().misses, 2);
+
+    /* Queries are keyed as normalized for the index */
+    let other = Query::with_config(&IndexerConfig::new().case_folding(true), &["warszawa"], &[])
+        .limit(Some(2))
+        .scan_cutoff(0.0);
+    assert_eq!(idx.search_cached(&other), results);
+    assert_eq!(idx.result_cache_stats().unwrap().hits, 2);
+    idx.search_cached(&other.must_trigram_cutoff(Some(0.0)));
+    assert_eq!(idx.result_cache_stats().unwrap().misses, 3);
+
+    /* Cache is opt-in */
+    let mut plain = Indexer::new();
+    plain.add_phrase("Warszawa", 1, None).unwrap();
+    let plain = plain.finish();
+    assert_eq!(plain.search_cached(&Query::new(&["warszawa"], &[]))[0].index, 1);
+    assert!(plain.result_cache_stats().is_none());
+}
+
+#[test]
+fn it_explains_must_edits() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawka", 1, None).unwrap();
+    let idx = idx.finish();
+
+    let results = idx.search(&Query::new(&["warszawa"], &[]));
+    assert!(results[0].edit_ops.is_empty());
+
+    let results = idx.search(&Query::new(&["warszawa"], &[]).explain(true));
+    println!("Got result {:?}", results);
+    assert_eq!(results[0].edit_ops, vec![crate::utils::EditOp::Delete { position: 7 }]);
+
+    /* Edits always describe the reported distance */
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawka", 1, None).unwrap();
+    idx.add_phrase("Nowy Jork", 2, None).unwrap();
+    idx.add_phrase("Kraków", 3, None).unwrap();
+    idx.add_phrase("Gdańskx", 4, None).unwrap();
+    let idx = idx.finish();
+    let explained = |query: Query| {
+        let result = idx.search_best(&query.explain(true)).unwrap();
+        (result.distance, result.edit_ops)
+    };
+    let query = Query::new(&["krakow"], &[]).fold_accents(true);
+    assert_eq!(explained(query), (0, vec![]));
+    let query = Query::new(&["warszwaka"], &[]).transpositions(true);
+    assert_eq!(explained(query), (1, vec![]));
+    let query = Query::new(&["nowyjork"], &[]).join_adjacent(true);
+    assert_eq!(explained(query), (0, vec![]));
+    let query = Query::new(&["gdansk"], &[]).edge_trim(1).fold_accents(true);
+    assert_eq!(explained(query), (0, vec![]));
+    let query = Query::new(&["warszawa"], &[]).metric(Metric::JaroWinkler).max_distance(None);
+    assert!(explained(query).1.is_empty());
+    let query = Query::new(&["krakaw"], &[]).fold_accents(true);
+    let substitute = crate::utils::EditOp::Substitute { position: 4, grapheme: "a".into() };
+    assert_eq!(explained(query), (1, vec![substitute]));
+}
+
+#[test]
+fn it_explains_matched_trigrams() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawka Warta", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let results = idx.search(&Query::new(&["warszawa"], &[]));
+    assert!(results[0].matched_trigrams.is_empty());
+
+    let results = idx.search(&Query::new(&["warszawa"], &[]).explain(true));
+    let matched: Vec<&str> = results[0].matched_trigrams
+        .iter()
+        .map(|(trigram, _score)| trigram.as_str())
+        .sorted()
+        .collect();
+    /* "awa" is missing from "warszawka" */
+    assert_eq!(matched, vec!["ars", "rsz", "sza", "war", "zaw"]);
+    assert!(results[0].matched_trigrams
+            .windows(2)
+            .all(|pair| pair[0].1 >= pair[1].1));
+}
+
+#[test]
+fn it_prefers_exact_matches() {
+    let mut idx = Indexer::new();
+    /* Long fuzzy tokens collect more trigram score than the short exact one */
+    for i in 0..5 {
+        idx.add_phrase(&format!("Warsawa Warsawy Warsawie {}", i), i, None).unwrap();
+    }
+    idx.add_phrase("Warsaw", 10, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warsaw"], &[]).limit(Some(1)).max_candidates(Some(2));
+    let results = idx.search(&query);
+    println!("Got result {:?}", results);
+    assert_ne!(results[0].index, 10);
+
+    let results = idx.search(&query.prefer_exact(true));
+    println!("Got result {:?}", results);
+    assert_eq!(results[0].index, 10);
+    assert_eq!(results[0].distance, 0);
+}
+
+#[test]
+fn it_counts_constraints() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, Some(&[1, 2].iter().cloned().collect())).unwrap();
+    idx.add_phrase("Kraków", 2, Some(&[2].iter().cloned().collect())).unwrap();
+    idx.add_phrase("Gdańsk", 3, None).unwrap();
+    let idx = idx.finish();
+
+    let counts = idx.constraints();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts[&1], 1);
+    assert_eq!(counts[&2], 2);
+}
+
+#[test]
+fn it_folds_accents_for_distance() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Résumé", 1, None).unwrap();
+    idx.add_phrase("Łódź", 2, None).unwrap();
+    let idx = idx.finish();
+
+    /* Unaccented must, accented phrase */
+    let query = Query::new(&["resume"], &[]).max_distance(Some(0));
+    assert!(idx.search(&query).is_empty());
+    let results = idx.search(&query.fold_accents(true));
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].distance, 0);
+
+    /* Accented must, unaccented phrase */
+    let mut plain = Indexer::new();
+    plain.add_phrase("Lodz", 2, None).unwrap();
+    let plain = plain.finish();
+    let query = Query::new(&["łódź"], &[]).max_distance(Some(0)).fold_accents(true);
+    assert_eq!(plain.search(&query)[0].index, 2);
+    assert_eq!(idx.search(&query)[0].index, 2);
+}
+
+#[test]
+fn it_aggregates_cache_stats() {
+    let build = |phrase: &str| {
+        let mut idx = Indexer::new();
+        idx.add_phrase(phrase, 1, None).unwrap();
+        idx.finish()
+    };
+    let first = build("Warszawa");
+    let second = build("Kraków");
+    first.search(&Query::new(&["warszawa"], &[]));
+    first.search(&Query::new(&["warszawa"], &[]));
+    second.search(&Query::new(&["krakow"], &[]));
+
+    let total = aggregate_cache_stats(&[&first, &second]);
+    assert_eq!((total.hits, total.misses, total.inserts, total.size), (1, 2, 2, 2));
+}
+
+#[test]
+fn it_caps_trigram_fan_out() {
+    let build = |params: ScoringParams| {
+        let mut idx = Indexer::new();
+        for i in 0..50 {
+            idx.add_phrase(&format!("Warszawska {}", i), i, None).unwrap();
+        }
+        idx.add_phrase("Warszawa", 100, None).unwrap();
+        idx.finish_with_params(100, params)
+    };
+    let uncapped = build(ScoringParams::default());
+    let idx = build(ScoringParams { max_positions: Some(10), ..ScoringParams::default() });
+
+    let entry = idx.trigram_entry("war").unwrap();
+    assert!(entry.capped);
+    assert_eq!(entry.positions.len(), 10);
+    /* Shortest token is kept and the score reflects the real popularity */
+    assert!(entry.positions.contains(&(100, 0)));
+    assert_eq!(entry.score, uncapped.trigram_entry("war").unwrap().score);
+    assert!(!idx.trigram_entry("1  ").unwrap().capped);
+
+    /* Common queries still find results */
+    let results = idx.search(&Query::new(&["warszawa"], &[]).limit(Some(5)));
+    assert_eq!(results[0].index, 100);
+    let results = idx.search(&Query::new(&["warszawska"], &[]).limit(Some(5)));
+    assert_eq!(results.len(), 5);
+}
+
+#[test]
+fn it_reports_finish_progress() {
+    let mut idx = Indexer::new();
+    for i in 0..2000 {
+        idx.add_phrase(&format!("Phrase {}", i), i, None).unwrap();
+    }
+    let trigrams = idx.db.len();
+
+    let mut calls: Vec<(usize, usize)> = Vec::new();
+    let idx = idx.finish_with_progress(10, |processed, total| calls.push((processed, total)));
+    assert_eq!(calls.first(), Some(&(0, trigrams)));
+    assert_eq!(calls.last(), Some(&(trigrams, trigrams)));
+    assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(idx.len(), 2000);
+
+    let mut calls = 0;
+    Indexer::new().finish_with_progress(10, |_processed, _total| calls += 1);
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn it_counts_transpositions_as_single_edit() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszwaa"], &[]).max_distance(Some(1));
+    assert!(idx.search(&query).is_empty());
+    let results = idx.search(&query.transpositions(true));
+    assert_eq!(results[0].distance, 1);
+
+    let query = Query::new(&["warszwaa"], &[]).max_distance(None).transpositions(true);
+    assert_eq!(idx.search(&query)[0].distance, 1);
+}
+
+#[test]
+fn it_weights_should_scores() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawa Zachodnia Centralna", 2, None).unwrap();
+    for i in 0..50 {
+        idx.add_phrase(&format!("Kraków {}", i % 10), 10 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    /* Should tokens pull the second phrase ahead of the stronger must match */
+    let query = Query::new(&["warszawa"], &["zachodnia", "centralna"]).limit(Some(1));
+    let results = idx.search(&query);
+    println!("Got result {:?}", results);
+    assert_eq!(results[0].index, 2);
+
+    let results = idx.search(&query.should_weight(0.1));
+    println!("Got result {:?}", results);
+    assert_eq!(results[0].index, 1);
+}
+
+#[test]
+fn it_checks_phrase_membership() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    assert!(idx.contains(1));
+    assert!(!idx.contains(2));
+    let idx = idx.finish();
+    assert!(idx.contains(1));
+    assert!(!idx.contains(2));
+}
+
+#[test]
+fn it_searches_federated_shards() {
+    let mut first = Indexer::new();
+    let mut second = Indexer::new();
+    for (i, phrase) in ["Warszawa", "Warszawka", "Kraków", "Warszowa", "Wrocław"].iter().enumerate() {
+        let shard = if phrase.starts_with('W') && i % 2 == 0 { &mut first } else { &mut second };
+        shard.add_phrase(phrase, i, None).unwrap();
+    }
+    let federated = FederatedIndex::new(vec![Arc::new(first.finish()), Arc::new(second.finish())]);
+    assert_eq!(federated.len(), 5);
+
+    let results = federated.search(&federated.query(&["warszawa"], &[]).limit(Some(2)).scan_cutoff(0.0));
+    println!("Got result {:?}", results);
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].origin, "Warszawa");
+    assert_eq!(results[1].distance, 1);
+
+    let results = federated.search(&federated.query(&["krakow"], &[]));
+    assert_eq!(results[0].index, 2);
+
+    /* Shards without a match don't add their nearest phrases */
+    let flagged = |query: Query| federated.search(&query.fallback_best(true))
+        .iter()
+        .map(|r| (r.index, r.within_distance))
+        .collect::<Vec<_>>();
+    assert_eq!(flagged(federated.query(&["krakow"], &[])), vec![(2, true)]);
+    assert_eq!(flagged(federated.query(&["warsz"], &[]).max_distance(Some(1))), vec![(0, false)]);
+}
+
+#[test]
+fn it_searches_with_prebuilt_heatmap() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Centralna", 1, Some(&[1].iter().cloned().collect())).unwrap();
+    idx.add_phrase("Warszawa Zachodnia", 2, Some(&[2].iter().cloned().collect())).unwrap();
+    idx.add_phrase("Warszawka", 3, Some(&[1].iter().cloned().collect())).unwrap();
+    let idx = idx.finish();
+
+    let heatmap = idx.heatmap_for("warszawa");
+    for (should, constraint) in [(vec!["zachodnia"], None), (vec![], Some(1)), (vec!["centralna"], Some(2))] {
+        let query = Query::new(&["warszawa"], &should).constraint(constraint).scan_cutoff(0.0);
+        let key = |results: Vec<super::seeker::SearchResult>| results
+            .iter()
+            .map(|result| (result.index, result.distance))
+            .collect::<Vec<_>>();
+        assert_eq!(key(idx.search_with_heatmap(&query, &heatmap)), key(idx.search(&query)));
+    }
+    /* Heatmap was computed once */
+    assert_eq!(idx.cache_stats().misses, 1);
+}
+
+#[test]
+fn it_floors_rare_trigram_popularity() {
+    let build = |params: ScoringParams| {
+        let mut idx = Indexer::new();
+        for i in 0..20 {
+            idx.add_phrase(&format!("Warszawa {}", i), i, None).unwrap();
+        }
+        /* Junk with trigrams that occur only once */
+        idx.add_phrase("Szxwy", 100, None).unwrap();
+        idx.finish_with_params(10, params)
+    };
+    let total_score = |idx: &Index, phrase_idx: usize| {
+        idx.heatmap_for("warszxwa").phrases[&phrase_idx].total_score
+    };
+
+    let idx = build(ScoringParams::default());
+    assert!(total_score(&idx, 100) > total_score(&idx, 0));
+
+    let idx = build(ScoringParams { min_occurrences: 20, ..ScoringParams::default() });
+    assert!(total_score(&idx, 100) < total_score(&idx, 0));
+    assert_eq!(idx.trigram_entry("szx").unwrap().score, idx.trigram_entry("war").unwrap().score);
+}
+
+#[test]
+fn it_orders_identical_origins_by_index() {
+    let mut idx = Indexer::new();
+    for phrase_idx in [7, 3, 9, 1, 5] {
+        idx.add_phrase("Warszawa", phrase_idx, Some(&[1].iter().cloned().collect())).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).limit(None).scan_cutoff(0.0);
+    let indices: Vec<usize> = idx.search(&query).iter().map(|result| result.index).collect();
+    assert_eq!(indices, vec![1, 3, 5, 7, 9]);
+
+    let query = Query::new(&["warszawa"], &[]).limit(None).scan_cutoff(0.0);
+    let ranked: Vec<usize> = idx.search_iter(&query).map(|result| result.index).collect();
+    assert_eq!(ranked, indices);
+}
+
+#[test]
+fn it_breaks_early_on_close_enough_match() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Rakszawa", 2, None).unwrap();
+    idx.add_phrase("Waszeta", 3, None).unwrap();
+    idx.add_phrase("Waszki", 4, None).unwrap();
+    idx.add_phrase("Kwaszyn", 5, None).unwrap();
+    idx.add_phrase("Jakszawa", 6, None).unwrap();
+    idx.add_phrase("Warszew", 7, None).unwrap();
+    let idx = idx.finish();
+
+    /* No exact match - everything is scanned by default */
+    let query = Query::new(&["waszawa"], &[]).max_distance(Some(3)).scan_cutoff(0.8);
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(stats.scanned, 6);
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0].index, 1);
+
+    /* Warszawa is close enough: lower scored candidates are skipped */
+    let query = query.early_break_distance(1);
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(stats.scanned, 4);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].index, 1);
+}
+
+#[test]
+fn it_skips_tokens_with_low_trigram_overlap() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Wawer", 3, None).unwrap();
+    idx.add_phrase("Kawa", 4, None).unwrap();
+    idx.add_phrase("Zawady", 5, None).unwrap();
+    idx.add_phrase("Szarawa", 6, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).max_distance(Some(1)).scan_cutoff(0.0);
+    let (all, all_stats) = idx.search_with_stats(&query);
+    let (overlapping, overlapping_stats) = idx.search_with_stats(&query.min_trigram_overlap(3));
+    assert_eq!(all_stats.distance_calls, 6);
+    assert!(overlapping_stats.distance_calls < all_stats.distance_calls);
+    assert_eq!(overlapping, all);
+    assert_eq!(all.iter().map(|result| result.index).collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[test]
+fn it_matches_tokens_of_a_field() {
+    const NAME: FieldId = 1;
+    const CITY: FieldId = 2;
+    let mut idx = Indexer::new();
+    idx.add_phrase_fields(&[(NAME, "Hotel Warszawa"), (CITY, "Kraków")], 1, None).unwrap();
+    idx.add_phrase_fields(&[(NAME, "Hotel Polonia"), (CITY, "Warszawa")], 2, None).unwrap();
+    idx.add_phrase_fields(&[(NAME, "Hostel Kraków"), (CITY, "Warszawa")], 3, None).unwrap();
+    idx.add_phrase("Warszawa Kraków", 4, None).unwrap();
+    let idx = idx.finish();
+    assert_eq!(idx.debug_phrase(1).unwrap().origin, "Hotel Warszawa Kraków");
+
+    let indices = |query: &Query| idx.search(query)
+        .iter()
+        .map(|result| result.index)
+        .sorted()
+        .collect::<Vec<_>>();
+
+    let query = Query::new(&["warszawa"], &[]).limit(None).scan_cutoff(0.0);
+    assert_eq!(indices(&query), vec![1, 2, 3, 4]);
+    assert_eq!(indices(&query.clone().must_field(Some(CITY))), vec![2, 3]);
+    assert_eq!(indices(&query.clone().must_field(Some(NAME))), vec![1]);
+
+    /* Kraków in the hostel name doesn't count as a city */
+    let query = Query::new(&["warszawa"], &["krakow"])
+        .must_field(Some(CITY))
+        .should_field(Some(CITY))
+        .limit(None)
+        .scan_cutoff(0.0);
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|result| result.should_score == 0.0));
+
+    let query = query.must_field(Some(NAME));
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].should_score > 0.0);
+}
+
+#[test]
+fn it_matches_case_folded_tokens() {
+    let build = |config: IndexerConfig| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("İstanbul", 1, None).unwrap();
+        idx.add_phrase("Große Straße", 2, None).unwrap();
+        idx.finish()
+    };
+    let distance = |idx: &Index, token: &str| {
+        idx.search(&idx.query(&[token], &[])).first().map(|result| result.distance)
+    };
+
+    let idx = build(IndexerConfig::new());
+    assert_eq!(distance(&idx, "istanbul"), Some(1));
+    assert_eq!(distance(&idx, "strasse"), Some(2));
+
+    let idx = build(IndexerConfig::new().case_folding(true));
+    assert_eq!(distance(&idx, "istanbul"), Some(0));
+    assert_eq!(distance(&idx, "İSTANBUL"), Some(0));
+    assert_eq!(distance(&idx, "strasse"), Some(0));
+    assert_eq!(distance(&idx, "STRAẞE"), Some(0));
+}
+
+#[test]
+fn it_splits_on_unicode_separators() {
+    let build = |config: IndexerConfig| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("東京都\u{3000}新宿区", 1, None).unwrap();
+        idx.add_phrase("大阪府\u{ff0c}大阪市", 2, None).unwrap();
+        idx.finish()
+    };
+    let found = |idx: &Index, token: &str| {
+        idx.search(&idx.query(&[token], &[]).max_distance(Some(0)))
+            .iter()
+            .map(|result| result.index)
+            .collect::<Vec<_>>()
+    };
+
+    let idx = build(IndexerConfig::new());
+    assert!(found(&idx, "新宿区").is_empty());
+
+    let idx = build(IndexerConfig::new().unicode_separators(true));
+    assert_eq!(found(&idx, "新宿区"), vec![1]);
+    assert_eq!(found(&idx, "大阪市"), vec![2]);
+    /* Query is split the same way */
+    assert_eq!(idx.query(&["大阪府\u{ff0c}大阪市"], &[]).must, "大阪府");
+}
+
+#[test]
+fn it_breaks_ties_by_query_tiebreak() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa B", 3, None).unwrap();
+    idx.add_phrase("Warszawa", 9, None).unwrap();
+    idx.add_phrase("Warszawa A", 2, None).unwrap();
+    let mut later = Indexer::new();
+    later.add_phrase("Warszawa", 1, None).unwrap();
+    idx.merge(later).unwrap();
+    idx.upsert_phrase("Warszawa B", 3, None).unwrap();
+    let idx = idx.finish();
+
+    let ranked = |tiebreak: Tiebreak| {
+        let query = Query::new(&["warszawa"], &[]).scan_cutoff(0.0).tiebreak(tiebreak);
+        let indices: Vec<usize> = idx.search(&query).iter().map(|result| result.index).collect();
+        let lazy: Vec<usize> = idx.search_iter(&query).map(|result| result.index).collect();
+        assert_eq!(indices, lazy);
+        indices
+    };
+    assert_eq!(ranked(Tiebreak::Origin), vec![1, 9, 2, 3]);
+    assert_eq!(ranked(Tiebreak::Index), vec![1, 2, 3, 9]);
+    assert_eq!(ranked(Tiebreak::InsertionOrder), vec![9, 2, 1, 3]);
+}
+
+#[test]
+fn it_searches_free_text_phrases() {
+    let mut idx = Indexer::new();
+    for i in 0..20 {
+        idx.add_phrase(&format!("Warszawska {}", i), i, None).unwrap();
+    }
+    idx.add_phrase("Warszawska Kutno", 100, None).unwrap();
+    idx.add_phrase("Kutno", 101, None).unwrap();
+    let idx = idx.finish();
+
+    let query = idx.phrase_query("Warszawska, Kutno").unwrap();
+    assert_eq!(query.must, "kutno");
+    assert_eq!(query.should, vec!["warszawska"]);
+    assert!(idx.phrase_query(" - , ").is_none());
+
+    let results = idx.search_phrase("warszawska kutno", Some(1));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 100);
+    assert!(idx.search_phrase("", None).is_empty());
+
+    /* Trigrams missing from the index count as not selective, so a typo
+     * sharing a single rare trigram doesn't win over a correct token */
+    let mut idx = Indexer::new();
+    for i in 0..20 {
+        idx.add_phrase(&format!("Warszawska {}", i), i, None).unwrap();
+    }
+    for i in 0..3 {
+        idx.add_phrase(&format!("Kutno {}", i), 100 + i, None).unwrap();
+    }
+    idx.add_phrase("Zgierz", 200, None).unwrap();
+    let idx = idx.finish();
+    let query = idx.phrase_query("Kutno Zgixyqw").unwrap();
+    assert_eq!(query.must, "kutno");
+}
+
+#[test]
+fn it_falls_back_to_the_nearest_phrase() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszewka"], &[]).max_distance(Some(1));
+    assert!(idx.search(&query).is_empty());
+
+    let results = idx.search(&query.clone().fallback_best(true));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].distance, 2);
+    assert!(!results[0].within_distance);
+    let lazy: Vec<_> = idx.search_iter(&query.clone().fallback_best(true)).collect();
+    assert_eq!(lazy, results);
+
+    let results = idx.search(&query.max_distance(Some(2)).fallback_best(true));
+    assert!(results[0].within_distance);
+}
+
+#[test]
+fn it_normalizes_similarity_for_token_length() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawalandia", 1, None).unwrap();
+    idx.add_phrase("Warszaw", 2, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    /* Scan only the best scored candidate */
+    let query = Query::new(&["warszawa"], &[]).max_candidates(Some(1));
+    assert!(idx.search(&query).is_empty());
+
+    let results = idx.search(&query.similarity(Similarity::Jaccard));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 2);
+    assert!(results[0].score > 0.0 && results[0].score <= 1.0);
+}
+
+#[test]
+fn it_matches_any_must_variant() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Muhammad Ali", 1, None).unwrap();
+    idx.add_phrase("Mohammed Salah", 2, None).unwrap();
+    idx.add_phrase("Mahmud Darwish", 3, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["muhammad"], &[]).max_distance(Some(1));
+    assert_eq!(idx.search(&query).iter().map(|result| result.index).collect::<Vec<_>>(), vec![1]);
+
+    let query = query.must_any(&["Mohammed"]);
+    let results = idx.search(&query);
+    let matched: Vec<(usize, usize, &str)> = results
+        .iter()
+        .map(|result| (result.index, result.matched_must, result.token))
+        .sorted()
+        .collect();
+    assert_eq!(matched, vec![(1, 0, "muhammad"), (2, 1, "mohammed")]);
+    assert_eq!(idx.search_iter(&query).count(), 2);
+}
+
+#[test]
+fn it_scores_results_for_calibration() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawa Centralna", 2, None).unwrap();
+    idx.add_phrase("Warszawka", 3, None).unwrap();
+    idx.add_phrase("Warsawa", 4, None).unwrap();
+    for i in 0..20 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &["centralna"]).limit(None).scan_cutoff(0.0);
+    let scored = idx.search_scored(&query);
+    let results = idx.search(&query);
+    assert_eq!(scored.iter().map(|(result, _)| result.index).collect::<Vec<_>>(),
+               results.iter().map(|result| result.index).collect::<Vec<_>>());
+
+    for (result, confidence) in scored.iter() {
+        assert!(*confidence > 0.0 && *confidence <= 1.0);
+        if result.distance == 0 {
+            assert!(*confidence >= 0.8);
+        } else {
+            assert!(*confidence <= 0.5);
+        }
+    }
+    /* Should token raises the confidence of an otherwise equal match */
+    assert_eq!(scored[0].0.index, 2);
+    assert!(scored[0].1 > scored[1].1);
+    assert_eq!(scored[1].0.index, 1);
+}
+
+#[test]
+fn it_scores_degenerate_corpora() {
+    /* Single distinct trigram */
+    let mut idx = Indexer::new();
+    idx.add_phrase("abc", 1, None).unwrap();
+    idx.add_phrase("ABC abc", 2, None).unwrap();
+    let idx = idx.finish();
+    assert_eq!(idx.index.db.len(), 1);
+    assert_eq!(idx.trigram_entry("abc").unwrap().score, 0.5);
+    let results = idx.search(&Query::new(&["abc"], &[]).limit(None));
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|result| result.score == 0.5));
+
+    /* All trigrams equally popular */
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    idx.add_phrase("Gdynia", 3, None).unwrap();
+    let idx = idx.finish();
+    assert!(idx.index.db.values().all(|entry| entry.score == 0.5));
+    let results = idx.search(&Query::new(&["krakow"], &[]));
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 2);
+    assert!(results[0].score.is_finite() && results[0].score > 0.0);
+
+    /* Broken scoring curve doesn't produce NaN scores */
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Warszawka", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    let idx = idx.finish_with_params(10, ScoringParams { steepness: f32::NAN, ..ScoringParams::default() });
+    assert!(idx.index.db.values().all(|entry| entry.score == 0.5));
+    assert_eq!(idx.search(&Query::new(&["warszawa"], &[]))[0].index, 1);
+}
+
+#[test]
+fn it_queries_string_constraints() {
+    let mut idx = Indexer::new();
+    idx.add_phrase_str_constraints("Warszawa", 1, &["PL-14"]).unwrap();
+    idx.add_phrase_str_constraints("Warszawka", 2, &["PL-12", "PL-14"]).unwrap();
+    assert!(idx.add_phrase_str_constraints("Warszawa", 1, &["PL-99"]).is_err());
+    assert_eq!(idx.add_phrase_str_constraints("- . ,", 9, &["PL-98"]), Err(AddError::EmptyPhrase));
+    assert_eq!(idx.constraint_id("PL-99"), None);
+    assert_eq!(idx.constraint_id("PL-98"), None);
+
+    /* Numeric constraints share the ids, so they can't be mixed in */
+    let pl14: HashSet<usize, FastHash> = [0].into_iter().collect();
+    assert_eq!(idx.add_phrase("Warszewa", 5, Some(&pl14)), Err(AddError::MixedConstraints));
+    assert_eq!(idx.upsert_phrase("Warszewa", 5, Some(&pl14)), Err(AddError::MixedConstraints));
+    let mut numeric = Indexer::new();
+    numeric.add_phrase("Warszewa", 5, Some(&pl14)).unwrap();
+    assert_eq!(numeric.add_phrase_str_constraints("Warszawa", 1, &["PL-14"]),
+               Err(AddError::MixedConstraints));
+    assert_eq!(numeric.merge(Indexer::new()), Ok(()));
+    let mut named = Indexer::new();
+    named.add_phrase_str_constraints("Warszawa", 1, &["PL-14"]).unwrap();
+    assert_eq!(numeric.merge(named), Err(MergeError::MixedConstraints));
+
+    /* Other indexer interned the same strings in a different order */
+    let mut other = Indexer::new();
+    other.add_phrase_str_constraints("Warszów", 3, &["PL-12"]).unwrap();
+    other.add_phrase_str_constraints("Warszowa", 4, &["PL-02"]).unwrap();
+    idx.merge(other).unwrap();
+    let idx = idx.finish();
+
+    let indices = |query: Query| idx.search(&query)
+        .iter()
+        .map(|result| result.index)
+        .sorted()
+        .collect::<Vec<_>>();
+    let query = Query::new(&["warszawa"], &[]).max_distance(Some(3)).limit(None).scan_cutoff(0.0);
+    assert_eq!(indices(query.clone().constraint_str(Some("PL-12"))), vec![2, 3]);
+    assert_eq!(indices(query.clone().constraint_str(Some("PL-14"))), vec![1, 2]);
+    assert_eq!(indices(query.clone().constraint_str(Some("PL-02"))), vec![4]);
+    assert_eq!(indices(query.clone().constraint_str(Some("PL-99"))), Vec::<usize>::new());
+    assert_eq!(idx.search_iter(&query.clone().constraint_str(Some("PL-99"))).count(), 0);
+    assert_eq!(indices(query.clone().constraint(idx.constraint_id("PL-14"))), Vec::<usize>::new());
+
+    /* Browsing by constraint only */
+    let query = Query::new(&[""], &[]).constraint_str(Some("PL-14")).limit(None);
+    assert_eq!(indices(query), vec![1, 2]);
+    let pl12 = idx.constraint_id("PL-12").unwrap();
+    assert_eq!(idx.constraints()[&pl12], 2);
+}
+
+#[test]
+fn it_finds_all_matches_exhaustively() {
+    let mut idx = Indexer::new();
+    /* Repeated token collects a much higher trigram score */
+    idx.add_phrase("Warszawa Warszawa Warszawa Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawa", 2, None).unwrap();
+    idx.add_phrase("Warszawka", 3, None).unwrap();
+    idx.add_phrase("Kraków", 4, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).limit(Some(1));
+    assert_eq!(idx.search(&query).len(), 1);
+    assert_eq!(idx.search(&query.clone().limit(None)).len(), 1);
+
+    let results = idx.search_exhaustive(&query);
+    assert_eq!(results.iter().map(|result| result.index).sorted().collect::<Vec<_>>(),
+               vec![1, 2, 3]);
+    assert_eq!(results[2].index, 3);
+    assert_eq!(results[2].distance, 1);
+
+    let query = query.limit_distinct_index(Some(1)).must_trigram_cutoff(Some(f32::MAX)).min_trigram_overlap(100);
+    assert!(idx.search(&query).is_empty());
+    assert_eq!(idx.search_exhaustive(&query), results);
+}
+
+#[test]
+fn it_lists_token_heatmap() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Warta", 3, None).unwrap();
+    idx.add_phrase("Kraków", 4, None).unwrap();
+    let idx = idx.finish();
+
+    let heatmap = idx.token_heatmap("warszawa");
+    assert_eq!(heatmap.iter().map(|(phrase_idx, _)| *phrase_idx).collect::<Vec<_>>(),
+               vec![1, 2, 3]);
+    assert!(heatmap.windows(2).all(|pair| pair[0].1 > pair[1].1));
+    assert_eq!(heatmap[0].1, idx.heatmap_for("warszawa").max_score);
+    assert!(idx.token_heatmap("xyz").is_empty());
+}
+
+#[test]
+fn it_rejects_empty_phrases() {
+    let mut idx = Indexer::new();
+    assert_eq!(idx.add_phrase("- . ,", 1, None), Err(AddError::EmptyPhrase));
+    assert_eq!(idx.add_phrase("", 1, None), Err(AddError::EmptyPhrase));
+    assert!(!idx.contains(1));
+
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    assert_eq!(idx.add_phrase("- . ,", 1, None), Err(AddError::DuplicateId));
+    assert_eq!(idx.upsert_phrase("- . ,", 1, None), Err(AddError::EmptyPhrase));
+    assert_eq!(idx.add_phrase_fields(&[(1, " - "), (2, ".")], 2, None), Err(AddError::EmptyPhrase));
+
+    let stats = idx.add_from_tsv("2\t- . ,\n3\tKraków\n".as_bytes()).unwrap();
+    assert_eq!(stats, LoadStats { added: 1, skipped: 0, duplicates: 0, empty: 1, unsearchable: 0 });
+
+    let idx = idx.finish();
+    assert_eq!(idx.len(), 2);
+    assert_eq!(idx.search(&Query::new(&["warszawa"], &[]))[0].index, 1);
+}
+
+#[test]
+fn it_skips_common_must_trigrams() {
+    let mut idx = Indexer::new();
+    for i in 0..100u8 {
+        let phrase = format!("The{}{}", (b'a' + i / 10) as char, (b'a' + i % 10) as char);
+        idx.add_phrase(&phrase, i as usize, None).unwrap();
+    }
+    idx.add_phrase("Thermos", 1000, None).unwrap();
+    idx.add_phrase("Hermosa", 1001, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["thermos"], &[]).limit(None).scan_cutoff(0.0);
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(stats.scanned, 102);
+    assert_eq!(results[0].index, 1000);
+
+    let query = query.must_trigram_cutoff(Some(0.5));
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(stats.scanned, 2);
+    assert_eq!(results[0].index, 1000);
+    /* Heatmaps with and without the cutoff are cached separately */
+    assert_eq!(idx.cache_stats().misses, 2);
+    idx.search(&query);
+    assert_eq!(idx.cache_stats().hits, 1);
+    /* Tokens looking like a cutoff key are cached separately too */
+    idx.heatmap_for("thermos >0.5");
+    assert_eq!(idx.cache_stats().hits, 1);
+    assert_eq!(idx.cache_stats().misses, 3);
+
+    /* Only common trigrams - the best one is still used */
+    let query = Query::new(&["the"], &[]).limit(None).scan_cutoff(0.0).must_trigram_cutoff(Some(0.99));
+    let (results, stats) = idx.search_with_stats(&query);
+    assert_eq!(stats.scanned, 101);
+    assert!(!results.is_empty());
+}
+
+#[test]
+fn it_serializes_results() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawka Centralna", 1, None).unwrap();
+    idx.add_phrase("Kraków Główny", 2, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa", "centralny"], &["krakow"]).explain(true);
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert!(!results[0].edit_ops.is_empty());
+    assert!(!results[0].additional_matches.is_empty());
+
+    let owned: Vec<OwnedSearchResult> = results.iter().map(OwnedSearchResult::from).collect();
+    let serialized = bincode::serialize(&owned).unwrap();
+    /* Borrowed results serialize the same way */
+    assert_eq!(bincode::serialize(&results).unwrap(), serialized);
+    let deserialized: Vec<OwnedSearchResult> = bincode::deserialize(&serialized).unwrap();
+    assert_eq!(deserialized, owned);
+
+    let stats = idx.cache_stats();
+    let deserialized: CacheStats = bincode::deserialize(&bincode::serialize(&stats).unwrap()).unwrap();
+    assert_eq!(deserialized, stats);
+}
+
+#[test]
+fn it_rescores_after_editing() {
+    let scores = |idx: &Indexer| idx.db
+        .iter()
+        .map(|(trigram, entry)| (trigram.clone(), entry.score))
+        .sorted_by(|a, b| a.0.cmp(&b.0))
+        .collect::<Vec<_>>();
+    let phrases = ["Warszawa", "Warszawka", "Kraków", "Wrocław", "Warta", "Zawiercie"];
+
+    let mut edited = Indexer::new();
+    for (i, phrase) in phrases.iter().enumerate() {
+        edited.add_phrase(phrase, i, None).unwrap();
+    }
+    edited.rescore();
+    let before = scores(&edited);
+    assert!(before.iter().all(|(_, score)| *score > 0.0 && *score <= 1.0));
+
+    edited.remove_phrase(1);
+    edited.remove_phrase(4);
+    edited.rescore();
+    assert_ne!(scores(&edited), before);
+
+    let mut fresh = Indexer::new();
+    for (i, phrase) in phrases.iter().enumerate().filter(|(i, _)| *i != 1 && *i != 4) {
+        fresh.add_phrase(phrase, i, None).unwrap();
+    }
+    fresh.rescore();
+    assert_eq!(scores(&edited), scores(&fresh));
+
+    /* Rescoring doesn't change the finished index */
+    assert_eq!(index_snapshot(&edited.finish()), index_snapshot(&fresh.finish()));
+}
+
+#[test]
+fn it_drops_tokens_shorter_than_min_length() {
+    let build = |config: IndexerConfig| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("Aleja 3 Maja", 1, None).unwrap();
+        idx.add_phrase("Aleja Trzeciego Maja", 2, None).unwrap();
+        idx.finish()
+    };
+
+    let idx = build(IndexerConfig::new());
+    assert_eq!(idx.config().min_token_length, 1);
+    assert_eq!(idx.config().tokenize("Aleja 3 Maja"), ["aleja", "3", "maja"]);
+    let results = idx.search(&idx.query(&["3"], &[]).scan_cutoff(0.0));
+    assert_eq!(results[0].index, 1);
+
+    let idx = build(IndexerConfig::new().min_token_length(2));
+    assert_eq!(idx.config().min_token_length, 2);
+    assert_eq!(idx.config().tokenize("Aleja 3 Maja"), ["aleja", "maja"]);
+    /* Queries are tokenized with the index setting */
+    let query = idx.query(&["Maja 3"], &[]).scan_cutoff(0.0);
+    assert_eq!(query.should, Vec::<String>::new());
+    let results = idx.search(&query);
+    assert_eq!(results.iter().map(|r| r.index).sorted().collect::<Vec<_>>(), [1, 2]);
+
+    /* Length is counted in graphemes, not bytes */
+    assert_eq!(IndexerConfig::new().min_token_length(2).tokenize("ł ó żółw"), ["żółw"]);
+    assert_eq!(IndexerConfig::new().min_token_length(0).min_token_length, 1);
+}
+
+#[test]
+fn it_prefers_token_of_closest_length() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Mart Marka", 1, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    /* Both tokens are within distance 1; "mart" shares more trigrams */
+    let query = Query::new(&["marta"], &[]).max_distance(Some(1));
+    let results = idx.search(&query);
+    assert_eq!((results[0].token, results[0].distance), ("mart", 1));
+
+    let results = idx.search(&query.token_preference(TokenPreference::ClosestLength));
+    assert_eq!((results[0].token, results[0].distance), ("marka", 1));
+}
+
+#[test]
+fn it_bounds_heatmap_cache_by_bytes() {
+    let build = || {
+        let mut idx = Indexer::new();
+        for i in 0..200 {
+            idx.add_phrase(&format!("Warszawa {}", i), i, None).unwrap();
+        }
+        idx.add_phrase("Kraków", 1000, None).unwrap();
+        idx.add_phrase("Gdańsk", 1001, None).unwrap();
+        idx
+    };
+
+    let idx = build().finish();
+    idx.warm_cache(&["krakow", "gdansk"]);
+    let small = idx.cache_stats().bytes;
+    idx.warm_cache(&["warszawa"]);
+    let large = idx.cache_stats().bytes - small;
+    assert!(small > 0);
+    assert!(large > small);
+
+    /* Fits both small heatmaps, but not the large one */
+    let idx = build().finish_with_byte_cache(small + large / 2);
+    idx.warm_cache(&["krakow", "gdansk", "warszawa"]);
+    let stats = idx.cache_stats();
+    assert_eq!((stats.size, stats.bytes, stats.inserts), (2, small, 2));
+
+    /* Least recently used heatmaps are evicted to stay within the limit */
+    let idx = build().finish_with_byte_cache(small);
+    idx.warm_cache(&["krakow", "gdansk"]);
+    assert_eq!(idx.cache_stats().size, 2);
+    let warmup = idx.warm_cache(&["gdanks"]);
+    assert_eq!((warmup.inserted, warmup.evicted), (1, 1));
+    assert!(idx.cache_stats().bytes <= small);
+    assert_eq!(idx.warm_cache(&["gdansk"]).cached, 1);
+}
+
+#[test]
+fn it_suggests_closest_tokens() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Centralna", 1, None).unwrap();
+    idx.add_phrase("Warszawa Zachodnia", 2, None).unwrap();
+    idx.add_phrase("Warszawka", 3, None).unwrap();
+    idx.add_phrase("Kraków Główny", 4, None).unwrap();
+    let idx = idx.finish();
+
+    let suggestions = idx.suggest("Warszewa", 2);
+    assert_eq!(suggestions, vec![("warszawa", 1), ("warszawka", 2)]);
+    assert_eq!(idx.suggest("krakow", 1), vec![("kraków", 1)]);
+    assert!(idx.suggest("xyz", 5).is_empty());
+    assert!(idx.suggest(" ", 5).is_empty());
+    assert!(idx.suggest("warszawa", 0).is_empty());
+
+    /* Distances bounded by the found tokens give the same suggestions */
+    let mut idx = Indexer::new();
+    for i in 0..300 {
+        let suffix: String = [i / 26, i].iter().map(|digit| (b'a' + (digit % 26) as u8) as char).collect();
+        idx.add_phrase(&format!("Warsz{}awa Wars{}", suffix, suffix), i, None).unwrap();
+    }
+    let idx = idx.finish();
+    for token in ["warszawa", "warszaba", "wars"] {
+        let all = idx.suggest(token, usize::MAX);
+        assert!(all.len() > 10);
+        for n in [1, 3, 10] {
+            assert_eq!(idx.suggest(token, n), all[..n]);
+        }
+    }
+}
+
+#[test]
+fn it_ranks_with_custom_function() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Warszawianka", 3, None).unwrap();
+    let idx = idx.finish().with_result_cache(10);
+
+    let query = Query::new(&["warszawa"], &[]).max_distance(Some(4)).scan_cutoff(0.0);
+    let indices = |results: &[SearchResult]| results.iter().map(|r| r.index).collect::<Vec<_>>();
+    assert_eq!(indices(&idx.search(&query)), vec![1, 2, 3]);
+
+    /* Prefer longer tokens, regardless of the distance */
+    let query = query.rank_fn(|result| result.token.len() as f64);
+    assert_eq!(indices(&idx.search(&query)), vec![3, 2, 1]);
+    let lazy: Vec<usize> = idx.search_iter(&query).map(|result| result.index).collect();
+    assert_eq!(lazy, vec![3, 2, 1]);
+    assert_eq!(indices(&idx.search(&query.clone().limit(Some(1)))), vec![3]);
+
+    /* Equal ranks keep the default order; custom ranked queries aren't cached */
+    let query = query.rank_fn(|_result| 0.0);
+    assert_eq!(indices(&idx.search(&query)), vec![1, 2, 3]);
+    idx.search_cached(&query);
+    assert_eq!(idx.result_cache_stats().unwrap().inserts, 0);
+}
+
+#[test]
+fn it_flags_short_token_matches() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("1 Maja", 1, None).unwrap();
+    idx.add_phrase("2 Maja", 2, None).unwrap();
+    idx.add_phrase("Kosk", 3, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["1"], &[]).explain(true);
+    let results = idx.search(&query);
+    assert_eq!(results[0].index, 1);
+    assert!(results[0].short_token_match);
+
+    /* Only an edge trigram of a 4 letter token matches */
+    let results = idx.search(&Query::new(&["kotk"], &[]).explain(true));
+    assert_eq!((results[0].index, results[0].distance), (3, 1));
+    assert!(results[0].short_token_match);
+
+    let results = idx.search(&Query::new(&["maja"], &[]).explain(true));
+    assert!(results.iter().all(|result| !result.short_token_match));
+
+    /* Opt-in */
+    let results = idx.search(&Query::new(&["1"], &[]));
+    assert!(!results[0].short_token_match);
+}
+
+#[test]
+fn it_requires_minimal_should_matches() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Mokotów Puławska", 1, None).unwrap();
+    idx.add_phrase("Warszawa Mokotów", 2, None).unwrap();
+    idx.add_phrase("Warszawa Wola Puławska Mokotów", 3, None).unwrap();
+    idx.add_phrase("Warszawa", 4, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &["mokotów", "puławska", "wola"]).scan_cutoff(0.0);
+    let indices = |query: &Query| idx.search(query).iter().map(|r| r.index).sorted().collect::<Vec<_>>();
+    assert_eq!(indices(&query), vec![1, 2, 3, 4]);
+    assert_eq!(indices(&query.clone().min_should_matches(1)), vec![1, 2, 3]);
+    assert_eq!(indices(&query.clone().min_should_matches(2)), vec![1, 3]);
+    assert_eq!(indices(&query.clone().min_should_matches(3)), vec![3]);
+
+    /* Repeated should tokens count once */
+    let query = Query::new(&["warszawa"], &["mokotów", "mokotów"]).scan_cutoff(0.0);
+    assert!(indices(&query.min_should_matches(2)).is_empty());
+}
+
+#[test]
+fn it_validates_index_consistency() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków Główny", 2, None).unwrap();
+    assert_eq!(idx.validate(), Ok(()));
+    let mut idx = idx.finish();
+    assert_eq!(idx.validate(), Ok(()));
+
+    let entry = idx.index.db.get_mut("war").unwrap();
+    entry.positions.push(Position { phrase_idx: 7, token_idx: 0 });
+    entry.positions.push(Position { phrase_idx: 2, token_idx: 2 });
+    idx.index.db.get_mut("kra").unwrap().score = f32::NAN;
+
+    let errors = idx.validate().unwrap_err();
+    assert_eq!(errors.len(), 3);
+    assert!(matches!(&errors[0], InconsistencyError::InvalidScore { trigram, .. } if trigram == "kra"));
+    assert_eq!(errors[1], InconsistencyError::MissingPhrase { trigram: "war".to_string(), phrase_idx: 7 });
+    assert_eq!(errors[2], InconsistencyError::TokenOutOfBounds {
+        trigram: "war".to_string(), phrase_idx: 2, token_idx: 2, tokens: 2,
+    });
+    assert_eq!(errors[1].to_string(), "Trigram \"war\" points to missing phrase 7");
+}
+
+#[test]
+fn it_splits_must_by_policy() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("New York", 1, None).unwrap();
+    idx.add_phrase("York", 2, None).unwrap();
+    let idx = idx.finish();
+    let indices = |query: &Query| idx.search(query).iter().map(|r| r.index).collect::<Vec<_>>();
+
+    let query = idx.query(&["new york"], &[]);
+    assert_eq!((query.must.as_str(), query.should.clone()), ("york", vec!["new".to_string()]));
+    assert_eq!(query.must_split, MustSplit::LongestAsMust);
+    assert_eq!(indices(&query), vec![1, 2]);
+
+    let query = idx.query_with_must_split(&["new york"], &[], MustSplit::AllMust).unwrap();
+    assert_eq!((query.must.as_str(), query.additional_must.clone()), ("york", vec!["new".to_string()]));
+    assert!(query.should.is_empty());
+    assert_eq!(indices(&query), vec![1]);
+
+    let error = idx.query_with_must_split(&["new york"], &[], MustSplit::Error).unwrap_err();
+    assert_eq!(error, MultiTokenMust {
+        token: "new york".to_string(),
+        tokens: vec!["new".to_string(), "york".to_string()],
+    });
+    assert!(idx.query_with_must_split(&["york"], &[], MustSplit::Error).is_ok());
+}
+
+#[test]
+fn it_reads_phrase_tokens_and_constraints() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Kraków Główny", 1, Some(&[3, 5].into_iter().collect())).unwrap();
+    let idx = idx.finish();
+
+    assert_eq!(idx.phrase_tokens(1), Some(&["kraków".to_string(), "główny".to_string()][..]));
+    let constraints = idx.phrase_constraints(1).unwrap();
+    assert_eq!(constraints.iter().copied().sorted().collect::<Vec<_>>(), vec![3, 5]);
+    assert!(constraints.contains(&3));
+    assert_eq!(idx.phrase_tokens(2), None);
+    assert!(idx.phrase_constraints(2).is_none());
+
+    /* Token indices match trigram positions */
+    let result = &idx.search(&Query::new(&["glowny"], &[]))[0];
+    let (_, token_idx) = idx.trigram_entry("wny").unwrap().positions[0];
+    assert_eq!(idx.phrase_tokens(result.index).unwrap()[token_idx as usize], result.token);
+}
+
+#[test]
+fn it_caps_scan_proportionally_to_limit() {
+    let mut idx = Indexer::new();
+    let streets = ["Warszawska", "Wrocławska", "Krakowska", "Poznańska", "Gdańska"];
+    for i in 0..2000 {
+        idx.add_phrase(&format!("{} {}", streets[i % streets.len()], i), i, None).unwrap();
+    }
+    for (i, city) in ["Warszawa", "Wrocław", "Kraków", "Poznań", "Gdańsk"].iter().enumerate() {
+        idx.add_phrase(city, 10_000 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    for typo in ["warszwa", "wroclaw", "krakw", "poznan", "gdansk"] {
+        let query = Query::new(&[typo], &[]).limit(Some(5));
+        let (full, full_stats) = idx.search_with_stats(&query);
+        let (capped, stats) = idx.search_with_stats(&query.scan_multiplier(Some(4.0)));
+        assert!(full_stats.scanned > 100, "{}", typo);
+        assert!(stats.scanned <= 20);
+        assert!(stats.truncated);
+        assert_eq!(capped[0].index, full[0].index, "{}", typo);
+    }
+
+    /* Without a limit the multiplier is ignored; the lower budget applies */
+    let query = Query::new(&["warszwa"], &[]).scan_multiplier(Some(1.0));
+    assert_eq!(query.scan_budget(), None);
+    let query = query.limit(Some(10)).max_candidates(Some(5));
+    assert_eq!(query.scan_budget(), Some(5));
+    assert_eq!(query.max_candidates(Some(50)).scan_budget(), Some(10));
+}
+
+#[test]
+fn it_removes_phrases_by_constraint() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, Some(&[1].into_iter().collect())).unwrap();
+    idx.add_phrase("Warszawka", 2, Some(&[1, 2].into_iter().collect())).unwrap();
+    idx.add_phrase("Warszewa", 3, Some(&[2].into_iter().collect())).unwrap();
+    idx.add_phrase("Kraków", 4, Some(&[1].into_iter().collect())).unwrap();
+
+    assert_eq!(idx.remove_by_constraint(1), 3);
+    assert_eq!(idx.remove_by_constraint(1), 0);
+    assert!(!idx.contains(1) && !idx.contains(2) && !idx.contains(4));
+    assert_eq!(idx.validate(), Ok(()));
+    /* Trigrams of removed phrases only are purged */
+    assert!(!idx.db.contains_key("kra"));
+
+    let idx = idx.finish();
+    let query = Query::new(&["warszawa"], &[]).scan_cutoff(0.0);
+    assert_eq!(idx.search(&query).iter().map(|r| r.index).collect::<Vec<_>>(), vec![3]);
+    assert!(idx.search(&query.constraint(Some(1))).is_empty());
+    assert!(idx.search(&Query::new(&["krakow"], &[])).is_empty());
+}
+
+#[test]
+fn it_boosts_prefix_trigrams() {
+    let build = |config: IndexerConfig| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("Abcxyz", 1, None).unwrap();
+        idx.add_phrase("Xyzdef", 2, None).unwrap();
+        /* Make the prefix trigram a bit more common */
+        idx.add_phrase("Abcklm", 3, None).unwrap();
+        for i in 0..40 {
+            idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+        }
+        idx.finish()
+    };
+    let ranked = |idx: &Index| {
+        let query = idx.query(&["abcdef"], &[]).max_distance(Some(3)).scan_cutoff(0.0);
+        idx.search(&query).iter().map(|r| r.index).filter(|index| *index < 3).collect::<Vec<_>>()
+    };
+
+    /* Both match by a single trigram at the same distance; the rarer wins */
+    assert_eq!(ranked(&build(IndexerConfig::new())), vec![2, 1]);
+    assert_eq!(ranked(&build(IndexerConfig::new().prefix_boost(100))), vec![1, 2]);
+
+    let weights = IndexerConfig::new().prefix_boost(100).trigram_weights("abcde");
+    assert_eq!(weights, vec![2.0, 1.5, 1.0 + 1.0 / 3.0]);
+    assert!(IndexerConfig::new().trigram_weights("abcde").is_empty());
+
+    /* Queries are the same, so they can be created without the index */
+    let boosted = build(IndexerConfig::new().prefix_boost(100));
+    let query = Query::new(&["abcdef"], &[]).max_distance(Some(3));
+    let expected = boosted.search(&boosted.query(&["abcdef"], &[]).max_distance(Some(3)));
+    assert_eq!(boosted.try_search(&query).unwrap(), expected);
+}
+
+#[test]
+fn it_estimates_candidates() {
+    let mut idx = Indexer::new();
+    for i in 0..30 {
+        idx.add_phrase(&format!("Warszawska {}", i), i, None).unwrap();
+    }
+    idx.add_phrase("Kraków", 100, None).unwrap();
+    idx.add_phrase("Krakowska", 101, None).unwrap();
+    let idx = idx.finish();
+
+    let exact = |must: &str| idx.token_heatmap(&idx.query(&[must], &[]).must).len();
+    for must in ["Kraków", "krakow", "warszawa", "zzz"] {
+        assert!(idx.estimate_candidates(must) >= exact(must), "{}", must);
+    }
+    assert_eq!(idx.estimate_candidates("zzz"), 0);
+    assert!(idx.estimate_candidates("kraków") < idx.estimate_candidates("warszawa"));
+    assert_eq!(idx.estimate_candidates("warszawska"), idx.len());
+}
+
+#[test]
+fn it_splits_digits() {
+    let off = IndexerConfig::new();
+    let on = IndexerConfig::new().split_digits(true);
+    assert_eq!(off.tokenize("A1"), vec!["a1"]);
+    assert_eq!(on.tokenize("A1"), vec!["a", "1"]);
+    assert_eq!(off.tokenize("12B"), vec!["12b"]);
+    assert_eq!(on.tokenize("12B"), vec!["12", "b"]);
+    assert_eq!(off.tokenize("1 May"), vec!["1", "may"]);
+    assert_eq!(on.tokenize("1 May"), vec!["1", "may"]);
+    assert_eq!(on.clone().min_token_length(2).tokenize("Ulica 12B"), vec!["ulica", "12"]);
+    assert_ne!(off.fingerprint(), on.fingerprint());
+
+    /* Queries are split the same way as phrases */
+    let mut idx = Indexer::with_config(on);
+    idx.add_phrase("Warszawska 12B", 1, None).unwrap();
+    idx.add_phrase("Warszawska 14", 2, None).unwrap();
+    let idx = idx.finish();
+    let query = idx.query(&["12B"], &["warszawska"]).scan_cutoff(0.0);
+    assert_eq!(query.must, "12");
+    assert_eq!(query.should, vec!["warszawska", "b"]);
+    let results = idx.search(&query);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert_eq!(idx.phrase_tokens(1).unwrap(), ["warszawska", "12", "b"]);
+}
+
+#[test]
+fn it_groups_results_by_must_variant() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Muhammad Ali", 1, None).unwrap();
+    idx.add_phrase("Mohammed Salah", 2, None).unwrap();
+    idx.add_phrase("Mohamed Hadid", 3, None).unwrap();
+    idx.add_phrase("Mohammad Reza", 4, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["muhammad"], &[])
+        .must_any(&["Mohammed", "mahmud"])
+        .max_distance(Some(1))
+        .scan_cutoff(0.0);
+    let grouped = idx.search_grouped_by_must(&query);
+    let indices = |variant: &str| -> Vec<usize> {
+        grouped[variant].iter().map(|result| result.index).sorted().collect()
+    };
+    assert_eq!(grouped.len(), 3);
+    assert!(indices("mahmud").is_empty());
+    assert!(indices("muhammad").contains(&1));
+    assert_eq!(indices("mohammed"), vec![2, 3]);
+
+    /* "mohammad" is within the distance of both, but is assigned only once */
+    let assigned = grouped.values().flatten().filter(|result| result.index == 4).count();
+    assert_eq!(assigned, 1);
+    let mut merged: Vec<usize> = grouped.values().flatten().map(|result| result.index).collect();
+    merged.sort_unstable();
+    assert_eq!(merged, idx.search(&query).iter().map(|result| result.index).sorted().collect::<Vec<_>>());
+}
+
+#[test]
+fn it_adds_phrases_with_owned_constraints() {
+    let constraints: HashSet<usize, FastHash> = (0..500).collect();
+    let mut borrowed = Indexer::new();
+    borrowed.add_phrase("Warszawa", 1, Some(&constraints)).unwrap();
+    let mut owned = Indexer::new();
+    owned.add_phrase_owned("Warszawa", 1, constraints.clone()).unwrap();
+    assert_eq!(owned.add_phrase_owned("Warszawka", 1, HashSet::default()), Err(AddError::DuplicateId));
+    owned.add_phrase_owned("Kraków", 2, HashSet::default()).unwrap();
+
+    let owned = owned.finish();
+    let borrowed = borrowed.finish();
+    assert_eq!(owned.phrase_constraints(1).unwrap(), borrowed.phrase_constraints(1).unwrap());
+    assert!(owned.phrase_constraints(2).unwrap().is_empty());
+    let query = Query::new(&["warszawa"], &[]).constraint(Some(499));
+    assert_eq!(owned.search(&query).len(), 1);
+    assert_eq!(owned.search(&query.constraint(Some(500))).len(), 0);
+}
+
+#[test]
+fn it_tells_if_token_has_any_trigram() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    let idx = idx.finish();
+
+    assert!(idx.has_any_trigram("warszawa"));
+    assert!(idx.has_any_trigram("KRAKOW"));
+    /* Too distant to match, but not unknown */
+    assert!(idx.has_any_trigram("warxxxxx"));
+    assert!(idx.search(&idx.query(&["warxxxxx"], &[])).is_empty());
+    assert!(!idx.has_any_trigram("zzzqqq"));
+    assert!(!idx.has_any_trigram(""));
+}
+
+#[test]
+fn it_recommends_scan_cutoff() {
+    let mut idx = Indexer::new();
+    for (i, phrase) in ["Warszawa", "Warszewa", "Warszawka", "Warsawa", "Wawrzyszew"].iter().enumerate() {
+        idx.add_phrase(phrase, i, None).unwrap();
+    }
+    idx.add_phrase("Gdańsk", 10, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    assert_eq!(idx.recommend_scan_cutoff(&[]), 0.0);
+    /* Nothing similar to lose */
+    assert_eq!(idx.recommend_scan_cutoff(&["gdansk"]), 0.95);
+
+    let cutoff = idx.recommend_scan_cutoff(&["warszawa", "gdansk"]);
+    assert!(cutoff < 0.95, "{}", cutoff);
+    let query = idx.query(&["warszawa"], &[]).limit(Some(10));
+    let indices = |results: Vec<SearchResult>| results.iter().map(|r| r.index).take(10).collect::<Vec<_>>();
+    assert_eq!(indices(idx.search(&query.clone().scan_cutoff(cutoff))),
+               indices(idx.search_exhaustive(&query)));
+    assert_ne!(indices(idx.search(&query.clone().scan_cutoff(0.95))),
+               indices(idx.search_exhaustive(&query)));
+}
+
+#[test]
+fn it_matches_parts_of_compound_tokens() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Hauptbahnhof", 1, None).unwrap();
+    idx.add_phrase("Bahnhofstraße", 2, None).unwrap();
+    idx.add_phrase("Ostbahnhof Berlin", 3, None).unwrap();
+    idx.add_phrase("Bahnhof", 4, None).unwrap();
+    idx.add_phrase("Beethovenstraat", 5, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+    let found = |query: Query| -> Vec<(usize, usize)> {
+        idx.search(&query.scan_cutoff(0.0))
+            .iter()
+            .map(|result| (result.index, result.distance))
+            .sorted()
+            .collect()
+    };
+
+    let query = Query::new(&["bahnhof"], &[]).max_distance(Some(0));
+    assert_eq!(found(query.clone()), vec![(4, 0)]);
+    assert_eq!(found(query.mode(SearchMode::SubToken)), vec![(1, 0), (2, 0), (3, 0), (4, 0)]);
+
+    /* Best window distance is reported */
+    let query = Query::new(&["banhof"], &[]).max_distance(Some(1)).mode(SearchMode::SubToken);
+    assert_eq!(found(query), vec![(1, 1), (2, 1), (3, 1), (4, 1)]);
+    let query = Query::new(&["straat"], &[]).max_distance(Some(0)).mode(SearchMode::SubToken);
+    assert_eq!(found(query), vec![(5, 0)]);
+}
+
+#[test]
+fn it_exports_and_imports_cache() {
+    let build = |config: IndexerConfig, extra: Option<&str>| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("Warszawa", 1, None).unwrap();
+        idx.add_phrase("Warszawka", 2, None).unwrap();
+        idx.add_phrase("Kraków", 3, None).unwrap();
+        idx.add_phrase("Gdańsk", 4, None).unwrap();
+        if let Some(extra) = extra {
+            idx.add_phrase(extra, 5, None).unwrap();
+        }
+        idx.finish_with_cache(10)
+    };
+    let source = build(IndexerConfig::new(), None);
+    source.warm_cache(&["warszawa", "krakow", "gdansk"]);
+    let exported = source.export_cache();
+    assert_eq!(exported.len(), 3);
+    /* Most recently used first */
+    assert_eq!(exported[0].0, HeatmapKey { token: "gdansk".to_string(), cutoff: None });
+
+    let bytes = bincode::serialize(&exported).unwrap();
+    let restored: Vec<(HeatmapKey, HeatmapSnapshot)> = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(restored, exported);
+
+    let idx = build(IndexerConfig::new(), None);
+    assert_eq!(idx.import_cache(restored.clone()), 3);
+    assert_eq!(idx.cache_stats().size, 3);
+    let fresh = build(IndexerConfig::new(), None);
+    for must in ["warszawa", "krakow", "gdansk"] {
+        let query = Query::new(&[must], &[]);
+        assert_eq!(idx.search(&query), fresh.search(&query));
+    }
+    assert_eq!(idx.cache_stats().misses, 0);
+
+    /* Heatmaps of changed trigrams are skipped; trigram scores depend on
+     * the number of phrases, so here all of them */
+    let changed = build(IndexerConfig::new(), Some("Warszawa Wschodnia"));
+    assert_eq!(changed.import_cache(restored.clone()), 0);
+    let query = Query::new(&["warszawa"], &[]).scan_cutoff(0.0);
+    assert_eq!(changed.search(&query).len(), 3);
+
+    let other = build(IndexerConfig::new().case_folding(true), None);
+    assert_eq!(other.import_cache(restored), 0);
+
+    /* Swapped phrase ids keep the trigram statistics, but not positions */
+    let build = |phrases: &[(&str, usize)]| {
+        let mut idx = Indexer::new();
+        for (phrase, phrase_idx) in phrases.iter() {
+            idx.add_phrase(phrase, *phrase_idx, None).unwrap();
+        }
+        idx.finish_with_cache(10)
+    };
+    let source = build(&[("Warszawa", 1), ("Kraków", 2)]);
+    source.warm_cache(&["warszawa"]);
+    let swapped = build(&[("Kraków", 1), ("Warszawa", 2)]);
+    assert_eq!(swapped.import_cache(source.export_cache()), 0);
+    let results = swapped.search(&Query::new(&["warszawa"], &[]));
+    assert_eq!(results.iter().map(|r| (r.index, r.token)).collect::<Vec<_>>(), vec![(2, "warszawa")]);
+}
+
+#[test]
+fn it_keeps_significant_marks() {
+    /* "kul" (family) and "kal" (tomorrow) differ only by a nonspacing vowel sign */
+    let (kul, kal) = ("कुल", "कल");
+    let strip = IndexerConfig::new();
+    let keep = IndexerConfig::new().marks(MarkPolicy::Keep);
+    assert_eq!(strip.trigramize(kul), strip.trigramize(kal));
+    assert_ne!(keep.trigramize(kul), keep.trigramize(kal));
+    assert_eq!(strip.trigramize("y̆es"), vec!["yes"]);
+    assert_eq!(keep.trigramize("y̆es"), vec!["y̆es"]);
+    /* Spacing vowel signs are kept unless all marks are stripped */
+    assert_ne!(strip.trigramize("किताब"), strip.trigramize("कतब"));
+    let strip_all = IndexerConfig::new().marks(MarkPolicy::StripAll);
+    assert_eq!(strip_all.trigramize("किताब"), strip_all.trigramize("कतब"));
+
+    let build = |config: IndexerConfig| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase(kul, 1, None).unwrap();
+        idx.add_phrase("Kraków", 2, None).unwrap();
+        idx.finish()
+    };
+    let (stripped, kept) = (build(strip), build(keep));
+    assert!(stripped.has_any_trigram(kal));
+    assert!(!kept.has_any_trigram(kal));
+    let query = kept.query(&[kul], &[]).max_distance(Some(0));
+    assert_eq!(kept.search(&query).len(), 1);
+    /* Folded accents follow the policy too */
+    let build = |config: IndexerConfig| {
+        let mut idx = Indexer::with_config(config);
+        idx.add_phrase("कुलपति", 1, None).unwrap();
+        idx.finish()
+    };
+    for (config, distance) in [(IndexerConfig::new(), 0), (IndexerConfig::new().marks(MarkPolicy::Keep), 1)] {
+        let idx = build(config);
+        let query = idx.query(&["कलपति"], &[]).fold_accents(true).scan_cutoff(0.0);
+        assert_eq!(idx.search_best(&query).unwrap().distance, distance);
+    }
+    /* Tokens keep their marks under both policies */
+    assert_eq!(stripped.phrase_tokens(1).unwrap(), [kul]);
+}
+
+#[test]
+fn it_reports_add_failure_modes() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    assert_eq!(idx.check_phrase("Kraków", 1), Err(AddError::DuplicateId));
+    assert_eq!(idx.check_phrase(" - ", 2), Err(AddError::EmptyPhrase));
+    assert_eq!(idx.check_phrase("\u{301}", 2), Err(AddError::NoTrigrams));
+    assert_eq!(idx.check_phrase("Kraków", 2), Ok(()));
+
+    assert_eq!(idx.add_phrase_searchable("\u{301}", 2, None), Err(AddError::NoTrigrams));
+    assert!(!idx.contains(2));
+    idx.add_phrase_searchable("Kraków", 2, None).unwrap();
+    assert_eq!(idx.add_phrase_searchable("Gdańsk", 2, None), Err(AddError::DuplicateId));
+    /* Lenient add keeps accepting unsearchable phrases */
+    idx.add_phrase("\u{301}", 3, None).unwrap();
+    assert_eq!(idx.unsearchable_phrases(), vec![3]);
+
+    let messages: Vec<String> = [AddError::DuplicateId, AddError::EmptyPhrase, AddError::NoTrigrams]
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(messages.iter().unique().count(), 3);
+}
+
+#[test]
+fn it_searches_by_raw_trigrams() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Waszawa Centralna", 3, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = idx.query(&["waszawa"], &[]).scan_cutoff(0.0);
+    let trigrams = idx.index.config.trigramize("waszawa");
+    assert_eq!(idx.search_by_trigrams(&trigrams, &query), idx.search(&query));
+
+    /* Unknown trigrams find nothing; the must token is the distance reference */
+    let unknown = vec!["zzz".to_string()];
+    assert!(idx.search_by_trigrams(&unknown, &query).is_empty());
+    let expanded = idx.index.config.trigramize("warszawa");
+    let results = idx.search_by_trigrams(&expanded, &query.max_distance(Some(1)));
+    let found: Vec<(usize, usize)> = results.iter().map(|r| (r.index, r.distance)).sorted().collect();
+    assert_eq!(found, vec![(1, 1), (3, 0)]);
+}
+
+#[test]
+fn it_looks_up_exact_tokens_without_distances() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Warsawa", 3, None).unwrap();
+    idx.add_phrase("Warszawa Centralna", 4, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).scan_cutoff(0.0);
+    let (exact, exact_stats) = idx.search_with_stats(&query.clone().max_distance(Some(0)));
+    let (fuzzy, fuzzy_stats) = idx.search_with_stats(&query.clone().max_distance(Some(1)));
+    assert_eq!(exact.iter().map(|r| r.index).sorted().collect::<Vec<_>>(), vec![1, 4]);
+    assert!(exact.iter().all(|r| r.distance == 0 && r.token == "warszawa"));
+    assert_eq!(exact_stats.distance_calls, 0);
+    assert!(fuzzy_stats.distance_calls > 0);
+    assert_eq!(fuzzy.len(), 4);
+
+    /* Accent folding still needs the distance */
+    let folded = Query::new(&["warszawą"], &[]).max_distance(Some(0)).fold_accents(true);
+    let (results, stats) = idx.search_with_stats(&folded);
+    assert_eq!(results.len(), 2);
+    assert!(stats.distance_calls > 0);
+}
+
+#[test]
+fn it_sizes_cache_by_distinct_tokens() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków", 2, None).unwrap();
+    assert_eq!(idx.default_cache_size(), 500);
+    let idx = idx.finish();
+    assert!(idx.cache_capacity() < 2000);
+
+    let mut idx = Indexer::new();
+    for i in 0..30_000 {
+        idx.add_phrase(&format!("Ulica {}x", i), i, None).unwrap();
+    }
+    assert_eq!(idx.default_cache_size(), 3000);
+    assert_eq!(idx.finish_with_cache(7).cache_capacity(), 7);
+}
+
+#[test]
+fn it_joins_adjacent_tokens() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("New York", 1, None).unwrap();
+    idx.add_phrase("Newyork Street", 2, None).unwrap();
+    idx.add_phrase("New Haven", 3, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["newyork"], &[]).scan_cutoff(0.0).max_distance(Some(1));
+    let found: Vec<usize> = idx.search(&query).iter().map(|r| r.index).collect();
+    assert_eq!(found, vec![2]);
+    let results = idx.search(&query.join_adjacent(true));
+    let found: Vec<(usize, &str, usize)> = results.iter().map(|r| (r.index, r.token, r.distance)).collect();
+    assert_eq!(found, vec![(2, "newyork", 0), (1, "new", 0)]);
+
+    let query = Query::new(&["new york"], &[]).scan_cutoff(0.0).max_distance(Some(1));
+    assert_eq!(query.must_joined.as_deref(), Some("newyork"));
+    let found: Vec<usize> = idx.search(&query).iter().map(|r| r.index).collect();
+    assert_eq!(found, vec![1]);
+    let results = idx.search(&query.join_adjacent(true));
+    let found: Vec<(usize, &str, usize)> = results.iter().map(|r| (r.index, r.token, r.distance)).collect();
+    assert_eq!(found, vec![(1, "york", 0), (2, "newyork", 0)]);
+}
+
+#[test]
+fn it_sorts_merged_results_by_rank_key() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Warszawka", 2, None).unwrap();
+    idx.add_phrase("Warsawa", 3, None).unwrap();
+    idx.add_phrase("Warszawa Centralna", 4, None).unwrap();
+    idx.add_phrase("Warszawa", 5, None).unwrap();
+    idx.add_phrase("Wola Warszawska", 6, None).unwrap();
+    idx.add_phrase("Marszawa", 7, None).unwrap();
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &["centralna"]).scan_cutoff(0.0).max_distance(None);
+    let expected: Vec<usize> = idx.search(&query).iter().map(|r| r.index).collect();
+    assert_eq!(expected.len(), 7);
+
+    let mut shuffled = idx.search(&query);
+    shuffled.reverse();
+    shuffled.swap(1, 4);
+    shuffled.sort_by_key(|result| result.rank_key());
+    let sorted: Vec<usize> = shuffled.iter().map(|r| r.index).collect();
+    assert_eq!(sorted, expected);
+}
+
+#[test]
+fn it_limits_distinct_indices() {
+    /* Records 1 and 2 are indexed in both shards under different names */
+    let mut first = Indexer::new();
+    first.add_phrase("Warszawa", 1, None).unwrap();
+    first.add_phrase("Warszawa Centralna", 2, None).unwrap();
+    first.add_phrase("Warszowa", 3, None).unwrap();
+    let mut second = Indexer::new();
+    second.add_phrase("Warszawa Stolica", 1, None).unwrap();
+    second.add_phrase("Warszawa Główna", 2, None).unwrap();
+    second.add_phrase("Warszawka", 4, None).unwrap();
+    let federated = FederatedIndex::new(vec![Arc::new(first.finish()), Arc::new(second.finish())]);
+
+    let query = federated.query(&["warszawa"], &[]).scan_cutoff(0.0);
+    let indices = |results: &[SearchResult]| results.iter().map(|r| r.index).collect::<Vec<_>>();
+    assert_eq!(indices(&federated.search(&query.clone().limit(Some(2)))), vec![1, 2]);
+
+    let results = federated.search(&query.clone().limit_distinct_index(Some(2)));
+    assert_eq!(results.len(), 4);
+    assert_eq!(indices(&results).into_iter().sorted().dedup().collect::<Vec<_>>(), vec![1, 2]);
+    assert!(results.iter().all(|r| r.distance == 0));
+
+    /* Phrase limit still applies on top */
+    let results = federated.search(&query.clone().limit_distinct_index(Some(2)).limit(Some(3)));
+    assert_eq!(results.len(), 3);
+
+    /* Within a single index every phrase is a distinct index */
+    let results = federated.shards[0].search(&query.limit_distinct_index(Some(2)));
+    assert_eq!(indices(&results), vec![1, 2]);
+}
+
+#[test]
+fn it_stores_normalized_origin() {
+    let phrases = ["ŁÓDŹ Kaliska", "Warszawa-Śródmieście", "AL. JEROZOLIMSKIE 12"];
+    let mut idx = Indexer::with_config(IndexerConfig::new().normalized_origin(true));
+    for (phrase_idx, phrase) in phrases.iter().enumerate() {
+        idx.add_phrase(phrase, phrase_idx, None).unwrap();
+    }
+    let idx = idx.finish();
+    for token in ["łódź", "śródmieście", "jerozolimskie"] {
+        let result = idx.search_best(&idx.query(&[token], &[])).unwrap();
+        assert_eq!(result.normalized_origin, Some(result.origin.to_lowercase().as_str()));
+        /* Matched token can be found in the normalized origin */
+        assert!(result.normalized_origin.unwrap().contains(result.token));
+    }
+    /* Queries don't depend on the setting */
+    let result = idx.search_best(&Query::new(&["łódź"], &[])).unwrap();
+    assert_eq!(result.normalized_origin, Some("łódź kaliska"));
+
+    let mut idx = Indexer::new();
+    idx.add_phrase(phrases[0], 0, None).unwrap();
+    let idx = idx.finish();
+    assert_eq!(idx.search_best(&idx.query(&["łódź"], &[])).unwrap().normalized_origin, None);
+}
+
+#[test]
+fn it_penalizes_negatively_weighted_should_tokens() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Old", 1, None).unwrap();
+    idx.add_phrase("Warszawa Nowa", 2, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &["old", "nowa"]).scan_cutoff(0.0);
+    let indices = |results: Vec<SearchResult>| results.iter().map(|r| r.index).collect::<Vec<_>>();
+    assert_eq!(indices(idx.search(&query.clone().should_weights(&[0.0, 0.0]))), vec![1, 2]);
+
+    let penalized = query.clone().should_weights(&[-1.0, 0.0]);
+    let results = idx.search(&penalized);
+    assert_eq!(indices(results.clone()), vec![2, 1]);
+    assert!(results[1].should_score < 0.0);
+    assert_eq!(results[0].should_score, 0.0);
+    assert_eq!(indices(idx.search(&penalized.clone().limit(Some(1)))), vec![2]);
+
+    /* Penalty doesn't satisfy the required should matches */
+    assert_eq!(indices(idx.search(&penalized.min_should_matches(1))), Vec::<usize>::new());
+    let rewarded = query.should_weights(&[-1.0, 0.5]).min_should_matches(1);
+    assert_eq!(indices(idx.search(&rewarded)), vec![2]);
+
+    /* Early breaks don't drop penalized phrases scanned late */
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Old", 1, None).unwrap();
+    idx.add_phrase("Warszawa", 2, None).unwrap();
+    idx.add_phrase("Warszawka", 3, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+    let query = Query::new(&["warszawa"], &["old"]).should_weights(&[-10.0]);
+    assert_eq!(indices(idx.search(&query)), vec![2, 1, 3]);
+    assert_eq!(indices(idx.search(&query.clone().limit(Some(2)))), vec![2, 1]);
+    assert!(std::panic::catch_unwind(|| query.should_weights(&[f32::NAN])).is_err());
+}
+
+#[test]
+fn it_exports_and_imports_jsonl() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Centralna", 1, Some(&[7, 8].iter().cloned().collect())).unwrap();
+    idx.add_phrase("Kraków \"Główny\"\tPKP", 2, None).unwrap();
+    idx.add_phrase("Warszawka", 3, Some(&[7].iter().cloned().collect())).unwrap();
+    idx.add_phrase_fields(&[(1, "Warszawa"), (2, "Mazowieckie")], 4, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let original = idx.finish();
+
+    let mut exported = Vec::new();
+    original.export_jsonl(&mut exported).unwrap();
+    let text = String::from_utf8(exported.clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 44);
+    assert_eq!(lines[0], r#"{"idx":1,"origin":"Warszawa Centralna","tokens":["warszawa","centralna"],"constraints":[7,8]}"#);
+    assert_eq!(lines[1], r#"{"idx":2,"origin":"Kraków \"Główny\"\tPKP","tokens":["kraków","główny","pkp"],"constraints":[]}"#);
+    assert_eq!(lines[3], r#"{"idx":4,"origin":"Warszawa Mazowieckie","tokens":["warszawa","mazowieckie"],"fields":[1,2],"constraints":[]}"#);
+
+    let mut idx = Indexer::new();
+    let stats = idx.import_jsonl(exported.as_slice()).unwrap();
+    assert_eq!(stats, LoadStats { added: 44, ..LoadStats::default() });
+    let imported = idx.finish();
+
+    let key = |results: Vec<SearchResult>| results
+        .iter()
+        .map(|r| (r.index, r.token.to_string(), r.distance, r.score, r.sequence))
+        .collect::<Vec<_>>();
+    for (must, should, constraint, field) in [("warszawa", vec![], None, None), ("krakow", vec!["glowny"], None, None),
+                                               ("warszawa", vec![], Some(7), None), ("warszawa", vec![], None, Some(1))] {
+        let query = Query::new(&[must], &should).constraint(constraint).must_field(field).scan_cutoff(0.0);
+        assert_eq!(key(imported.search(&query)), key(original.search(&query)));
+    }
+
+    /* Phrases generated by other tools; string constraints are interned */
+    let data = concat!(
+        "{\"idx\": 5, \"origin\": \"Gda\\u0144sk\", \"constraints\": [\"PL-22\"]}\n",
+        "{\"idx\": 6, \"origin\": \"Gdynia\", \"constraints\": [\"PL-22\", 3]}\n",
+        "{\"idx\": 5, \"origin\": \"Duplicate\"}\n",
+        "{\"idx\": \"7\", \"origin\": \"Sopot\"}\n",
+        "{\"idx\": 8, \"origin\": \"Hel\", \"tokens\": [\"hel\"], \"fields\": [1, 2]}\n",
+        "not json\n",
+        "{\"idx\": 9, \"origin\": \"- . ,\"}\n",
+    );
+    let mut idx = Indexer::new();
+    let stats = idx.import_jsonl(data.as_bytes()).unwrap();
+    /* Gdynia mixes numeric and string constraints */
+    assert_eq!(stats, LoadStats { added: 1, skipped: 4, duplicates: 1, empty: 1, unsearchable: 0 });
+    let idx = idx.finish();
+    let results = idx.search(&idx.query(&["gdansk"], &[]).constraint_str(Some("PL-22")));
+    assert_eq!(results[0].index, 5);
+    assert_eq!(results[0].origin, "Gdańsk");
+
+    /* Deeply nested lines are skipped without overflowing the stack */
+    let nested = format!("{{\"idx\": 10, \"origin\": \"Puck\", \"x\": {}}}\n",
+                         "[".repeat(1_000_000));
+    let mut idx = Indexer::new();
+    let stats = idx.import_jsonl(nested.as_bytes()).unwrap();
+    assert_eq!(stats, LoadStats { added: 0, skipped: 1, duplicates: 0, empty: 0, unsearchable: 0 });
+    let nested = "{\"idx\": 10, \"origin\": \"Puck\", \"x\": [[[{\"y\": []}]]]}\n";
+    assert_eq!(idx.import_jsonl(nested.as_bytes()).unwrap().added, 1);
+}
+
+#[test]
+fn it_orders_nan_scores_without_panicking() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa Old", 1, None).unwrap();
+    idx.add_phrase("Warszawa Nowa", 2, None).unwrap();
+    idx.add_phrase("Warszawa", 3, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &["old"]).should_weight(f32::NAN);
+    for query in [query.clone().scan_cutoff(0.0), query.clone(), query.clone().limit(Some(1))] {
+        let results = idx.search(&query);
+        /* NaN never outranks valid scores */
+        assert!(!results[0].should_score.is_nan());
+        assert_ne!(results[0].index, 1);
+        /* The same order every time */
+        let indices: Vec<usize> = results.iter().map(|r| r.index).collect();
+        assert_eq!(indices, idx.search(&query).iter().map(|r| r.index).collect::<Vec<_>>());
+        let mut sorted = results.clone();
+        sorted.sort_by_key(|result| result.rank_key());
+        assert_eq!(sorted.iter().map(|r| r.index).collect::<Vec<_>>(), indices);
+    }
+    let results = idx.search(&query.clone().scan_cutoff(0.0));
+    assert_eq!(results.iter().map(|r| r.index).collect::<Vec<_>>(), vec![3, 2, 1]);
+    assert!(results[2].should_score.is_nan());
+
+    /* Custom ranks too */
+    let query = query.scan_cutoff(0.0).rank_fn(|r| if r.index == 3 { f64::NAN } else { 1.0 });
+    let results = idx.search(&query);
+    assert_eq!(results.iter().map(|r| r.index).collect::<Vec<_>>(), vec![2, 1, 3]);
+}
+
+#[test]
+fn it_trims_junk_at_token_edges() {
+    let mut idx = Indexer::new();
+    idx.add_phrase("Warszawa", 1, None).unwrap();
+    idx.add_phrase("Kraków Główny", 2, None).unwrap();
+    idx.add_phrase("QGdańskz", 3, None).unwrap();
+    idx.add_phrase("XXWarszawa", 4, None).unwrap();
+    for i in 0..40 {
+        idx.add_phrase(&format!("Kraków {}", i), 100 + i, None).unwrap();
+    }
+    let idx = idx.finish();
+
+    let query = Query::new(&["warszawa"], &[]).scan_cutoff(0.0).max_distance(Some(0));
+    let indices = |results: Vec<SearchResult>| results.iter().map(|r| r.index).sorted().collect::<Vec<_>>();
+    assert_eq!(indices(idx.search(&query)), vec![1]);
+    assert_eq!(indices(idx.search(&query.clone().edge_trim(1))), vec![1]);
+    let results = idx.search(&query.clone().edge_trim(2));
+    assert_eq!(indices(results.clone()), vec![1, 4]);
+    assert!(results.iter().all(|r| r.distance == 0));
+
+    /* The must is compared whole */
+    let query = Query::new(&["krakowa"], &[]).scan_cutoff(0.0).max_distance(Some(0))
+        .fold_accents(true)
+        .edge_trim(1);
+    assert!(idx.search(&query).is_empty());
+
+    /* Junk on both edges */
+    let query = Query::new(&["gdansk"], &[]).scan_cutoff(0.0).max_distance(Some(1)).fold_accents(true);
+    assert!(idx.search(&query).is_empty());
+    let results = idx.search(&query.edge_trim(2));
+    assert_eq!(results.iter().map(|r| (r.index, r.distance)).collect::<Vec<_>>(), vec![(3, 0)]);
+}
@@ -2,20 +2,22 @@ extern crate pyo3;
 pub mod utils;
 pub mod fuzzdex;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use itertools::Itertools;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::types::{PyBool, PyDict, PyList, PyType};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 
-use crate::fuzzdex::{seeker, query};
-
-type FastHash = ahash::RandomState;
+use crate::fuzzdex::{seeker, FastHash};
+use crate::utils::EditOp;
 
 pub enum FuzzDex {
     /// While being build.
     Indexer(fuzzdex::Indexer),
     /// When finished and queryable.
-    Index(seeker::Index)
+    Index(seeker::Index),
+    /// After `close`, when the memory was released.
+    Closed,
 }
 
 #[pyclass(name="FuzzDex")]
@@ -30,61 +32,453 @@ impl PyFuzzDex {
             FuzzDex::Indexer(_) =>
                 Err(PyErr::new::<PyRuntimeError, _>("Index is not yet finished.")),
             FuzzDex::Index(index) =>
-                Ok(index)
+                Ok(index),
+            FuzzDex::Closed =>
+                Err(closed_error()),
+        }
+    }
+
+    /// Helper to get settings of the indexer or index.
+    fn get_config(&self) -> Result<&fuzzdex::IndexerConfig, PyErr> {
+        match &self.index {
+            FuzzDex::Indexer(indexer) => Ok(indexer.config()),
+            FuzzDex::Index(index) => Ok(index.config()),
+            FuzzDex::Closed => Err(closed_error()),
         }
     }
+
+    /// Helper to get indexer of unfinished index or create an exception.
+    fn get_indexer(&mut self) -> Result<&mut fuzzdex::Indexer, PyErr> {
+        match &mut self.index {
+            FuzzDex::Indexer(indexer) =>
+                Ok(indexer),
+            FuzzDex::Index(_) =>
+                Err(PyErr::new::<PyRuntimeError, _>("Index is already finished.")),
+            FuzzDex::Closed =>
+                Err(closed_error()),
+        }
+    }
+}
+
+fn closed_error() -> PyErr {
+    PyErr::new::<PyRuntimeError, _>("Index is closed.")
+}
+
+/// Exception for a phrase that couldn't be added.
+fn add_error(err: fuzzdex::AddError) -> PyErr {
+    match err {
+        fuzzdex::AddError::DuplicateId =>
+            PyErr::new::<PyRuntimeError, _>("Duplicate phrase index."),
+        fuzzdex::AddError::EmptyPhrase =>
+            PyErr::new::<PyRuntimeError, _>("Phrase contains no tokens."),
+        fuzzdex::AddError::NoTrigrams =>
+            PyErr::new::<PyRuntimeError, _>("Phrase tokens produce no trigrams and can't be found."),
+        fuzzdex::AddError::MixedConstraints =>
+            PyErr::new::<PyRuntimeError, _>("Index can't mix integer and string constraints."),
+    }
+}
+
+/// Phrase constraints given from Python: integers or interned strings.
+#[derive(FromPyObject)]
+enum PyConstraints {
+    Ids(HashSet<usize, FastHash>),
+    Names(HashSet<String>),
+}
+
+/// Convert search result into a Python dict.
+fn result_to_py<'py>(py: Python<'py>, result: &seeker::SearchResult,
+                     multiple_must: bool, explain: bool) -> PyResult<&'py PyDict> {
+    let pyresult = PyDict::new(py);
+    pyresult.set_item("origin", result.origin)?;
+    pyresult.set_item("index", result.index)?;
+    if multiple_must {
+        let tokens: Vec<&str> = std::iter::once(result.token)
+            .chain(result.additional_matches.iter().map(|(token, _)| *token))
+            .collect();
+        let distances: Vec<usize> = std::iter::once(result.distance)
+            .chain(result.additional_matches.iter().map(|(_, distance)| *distance))
+            .collect();
+        pyresult.set_item("token", tokens)?;
+        pyresult.set_item("distance", distances)?;
+    } else {
+        pyresult.set_item("token", result.token)?;
+        pyresult.set_item("distance", result.distance)?;
+    }
+    pyresult.set_item("score", result.score)?;
+    pyresult.set_item("should_score", result.should_score)?;
+    pyresult.set_item("within_distance", result.within_distance)?;
+    if let Some(normalized_origin) = result.normalized_origin {
+        pyresult.set_item("normalized_origin", normalized_origin)?;
+    }
+    if explain {
+        pyresult.set_item("should_breakdown", result.should_breakdown.clone())?;
+        let edit_ops: Vec<(&str, usize, Option<&str>)> = result.edit_ops
+            .iter()
+            .map(|op| match op {
+                EditOp::Delete { position } => ("delete", *position, None),
+                EditOp::Insert { position, grapheme } => ("insert", *position, Some(grapheme.as_str())),
+                EditOp::Substitute { position, grapheme } => ("substitute", *position, Some(grapheme.as_str())),
+            })
+            .collect();
+        pyresult.set_item("edit_ops", edit_ops)?;
+        pyresult.set_item("matched_trigrams", result.matched_trigrams.clone())?;
+        pyresult.set_item("short_token_match", result.short_token_match)?;
+    }
+    Ok(pyresult)
 }
 
 /// Python wrapper for fuzzdex proper.
 #[pymethods]
 impl PyFuzzDex {
+    /// Create an empty index. Tokens shorter than `min_token_length`
+    /// graphemes are dropped from phrases and queries. With `split_digits`
+    /// runs of digits and letters are split into separate tokens ("A1" into
+    /// "a" and "1"). With `normalized_origin` phrases are also stored
+    /// lowercased and returned as `normalized_origin` of the results.
     #[new]
-    fn new() -> PyResult<Self> {
+    #[args(min_token_length = "1", split_digits = "false", normalized_origin = "false")]
+    fn new(min_token_length: usize, split_digits: bool, normalized_origin: bool) -> PyResult<Self> {
+        if min_token_length == 0 {
+            return Err(PyErr::new::<PyValueError, _>("Minimal token length must be at least 1."));
+        }
+        let config = fuzzdex::IndexerConfig::new()
+            .min_token_length(min_token_length)
+            .split_digits(split_digits)
+            .normalized_origin(normalized_origin);
         let fuzzdex = PyFuzzDex {
-            index: FuzzDex::Indexer(fuzzdex::Indexer::new())
+            index: FuzzDex::Indexer(fuzzdex::Indexer::with_config(config))
         };
         Ok(fuzzdex)
     }
 
+    /// Minimal length of tokens used by this index.
+    #[getter]
+    fn min_token_length(&self) -> PyResult<usize> {
+        Ok(self.get_config()?.min_token_length)
+    }
+
+    /// Check internal consistency of the index (finished or not). Returns a
+    /// list of found problems, empty for a sound index.
+    fn validate(&self) -> PyResult<Vec<String>> {
+        let result = match &self.index {
+            FuzzDex::Indexer(indexer) => indexer.validate(),
+            FuzzDex::Index(index) => index.validate(),
+            FuzzDex::Closed => return Err(closed_error()),
+        };
+        Ok(result.err()
+           .unwrap_or_default()
+           .iter()
+           .map(|error| error.to_string())
+           .collect())
+    }
+
+    /// Split phrase into tokens the way this index does.
+    fn tokenize(&self, phrase: &str) -> PyResult<Vec<String>> {
+        Ok(self.get_config()?.tokenize(phrase))
+    }
+
+    /// Add a phrase. Constraints are a set of integers or of strings. With
+    /// `reject_unsearchable` phrases that could never be found (tokens
+    /// without trigrams) raise RuntimeError instead of being added.
+    #[args(reject_unsearchable = "false")]
     fn add_phrase(&mut self, phrase: &str, phrase_idx: usize,
-                  constraints: HashSet<usize, FastHash>) -> PyResult<()> {
+                  constraints: PyConstraints, reject_unsearchable: bool) -> PyResult<()> {
+        let indexer = self.get_indexer()?;
+        if reject_unsearchable {
+            indexer.check_phrase(phrase, phrase_idx).map_err(add_error)?;
+        }
+        let added = match constraints {
+            PyConstraints::Ids(constraints) =>
+                indexer.add_phrase_owned(phrase, phrase_idx, constraints),
+            PyConstraints::Names(names) => {
+                let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+                indexer.add_phrase_str_constraints(phrase, phrase_idx, &names)
+            }
+        };
+        added.map_err(add_error)
+    }
+
+    /// Add a phrase or replace a phrase with the same index. Returns True if
+    /// phrase was replaced.
+    fn upsert_phrase(&mut self, phrase: &str, phrase_idx: usize,
+                     constraints: HashSet<usize, FastHash>) -> PyResult<bool> {
         let constraints: Option<&HashSet<usize, FastHash>> = if constraints.is_empty() {
             None
         } else {
             Some(&constraints)
         };
 
-        match &mut self.index {
-            FuzzDex::Indexer(indexer) => {
-                indexer.add_phrase(phrase, phrase_idx, constraints)
-                    .map_err(|_|
-                             PyErr::new::<PyRuntimeError, _>("Duplicate phrase index."))
-            }
-            FuzzDex::Index(_) => {
-                Err(PyErr::new::<PyRuntimeError, _>("Index is already finished."))
-            }
+        let upsert = self.get_indexer()?
+            .upsert_phrase(phrase, phrase_idx, constraints)
+            .map_err(add_error)?;
+        Ok(upsert == fuzzdex::indexer::Upsert::Replaced)
+    }
+
+    /// Remove a phrase. Returns False if it didn't exist.
+    fn remove_phrase(&mut self, phrase_idx: usize) -> PyResult<bool> {
+        Ok(self.get_indexer()?.remove_phrase(phrase_idx))
+    }
+
+    /// Remove all phrases with the constraint, or with the string constraint
+    /// `constraint_str`. Returns the number of removed phrases.
+    fn remove_by_constraint(&mut self, constraint: Option<usize>,
+                            constraint_str: Option<&str>) -> PyResult<usize> {
+        let indexer = self.get_indexer()?;
+        let constraint = match (constraint, constraint_str) {
+            (_, Some(name)) => match indexer.constraint_id(name) {
+                Some(constraint) => constraint,
+                None => return Ok(0),
+            },
+            (Some(constraint), None) => constraint,
+            (None, None) => return Err(PyErr::new::<PyValueError, _>(
+                "Either constraint or constraint_str is required.")),
+        };
+        Ok(indexer.remove_by_constraint(constraint))
+    }
+
+    /// Add phrases from a file with `phrase_idx<TAB>phrase` lines. Returns
+    /// counts of added, skipped (unparsable), duplicated and empty lines and
+    /// of added phrases that are unsearchable.
+    fn add_from_tsv(&mut self, py: Python, path: &str) -> PyResult<PyObject> {
+        let indexer = self.get_indexer()?;
+        let file = std::fs::File::open(path)
+            .map_err(|err| PyErr::new::<PyRuntimeError, _>(
+                format!("Unable to open {}: {}", path, err)))?;
+        let stats = indexer.add_from_tsv(std::io::BufReader::new(file))
+            .map_err(|err| PyErr::new::<PyRuntimeError, _>(
+                format!("Unable to read {}: {}", path, err)))?;
+        let pystats = PyDict::new(py);
+        pystats.set_item("added", stats.added)?;
+        pystats.set_item("skipped", stats.skipped)?;
+        pystats.set_item("duplicates", stats.duplicates)?;
+        pystats.set_item("empty", stats.empty)?;
+        pystats.set_item("unsearchable", stats.unsearchable)?;
+        Ok(pystats.into())
+    }
+
+    /// Finish indexing and move into searchable index with a given internal
+    /// cache size, by default proportional to the number of distinct tokens
+    /// (500-20000 entries). `max_positions` caps the number of positions kept for each
+    /// trigram, trading recall for speed of very common tokens. `progress`
+    /// callable is called with (processed, total) trigrams while scoring.
+    /// Trigrams occurring less than `min_occurrences` times are scored as if
+    /// they occurred that many times.
+    fn finish(&mut self, py: Python, cache_size: Option<usize>, max_positions: Option<usize>,
+              progress: Option<PyObject>, min_occurrences: Option<usize>) -> PyResult<()> {
+        if cache_size == Some(0) {
+            return Err(PyErr::new::<PyRuntimeError, _>("Cache size must be at least 1"))
         }
+        let indexer = std::mem::take(self.get_indexer()?);
+        let cache_size = cache_size.unwrap_or_else(|| indexer.default_cache_size());
+        let params = fuzzdex::ScoringParams {
+            max_positions,
+            min_occurrences: min_occurrences.unwrap_or(1),
+            ..Default::default()
+        };
+        /* Index is finished anyway; first callback error is raised afterwards */
+        let mut error: Option<PyErr> = None;
+        let index = indexer.finish_scoring(cache_size, params, |processed, total| {
+            if let (Some(progress), None) = (&progress, &error) {
+                if let Err(err) = progress.call1(py, (processed, total)) {
+                    error = Some(err);
+                }
+            }
+        });
+        self.index = FuzzDex::Index(index);
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Save finished index to a file.
+    fn save(&self, py: Python, path: &str) -> PyResult<()> {
+        let index = self.get_index()?;
+        py.allow_threads(move || index.save(path))
+            .map_err(|err| PyErr::new::<PyRuntimeError, _>(
+                format!("Unable to save index: {}", err)))
     }
 
-    /// Finish indexing and move into searchable index with a given internal cache size.
-    fn finish(&mut self, cache_size: Option<usize>) -> PyResult<()> {
-        let cache_size = cache_size.unwrap_or(2000);
-        if cache_size == 0 {
+    /// Load an index saved with `save`. It's finished and ready for queries.
+    /// Cache size defaults like in `finish`.
+    #[classmethod]
+    fn load(_cls: &PyType, py: Python, path: &str, cache_size: Option<usize>) -> PyResult<Self> {
+        if cache_size == Some(0) {
             return Err(PyErr::new::<PyRuntimeError, _>("Cache size must be at least 1"))
         }
-        match &mut self.index {
-            FuzzDex::Indexer(indexer) => {
-                let indexer = std::mem::take(indexer);
-                self.index = FuzzDex::Index(indexer.finish_with_cache(cache_size));
-                Ok(())
+        let index = py.allow_threads(move || seeker::Index::load_with_cache_size(path, cache_size))
+            .map_err(|err| PyErr::new::<PyRuntimeError, _>(
+                format!("Unable to load index: {}", err)))?;
+        Ok(PyFuzzDex {
+            index: FuzzDex::Index(index)
+        })
+    }
+
+    /// True when the index was finished (or loaded) and can be queried.
+    #[getter]
+    fn finished(&self) -> bool {
+        matches!(self.index, FuzzDex::Index(_))
+    }
+
+    /// Drop the index and free its memory. Any later use raises
+    /// RuntimeError. Closing twice is allowed.
+    fn close(&mut self) {
+        self.index = FuzzDex::Closed;
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __exit__(&mut self, _exc_type: Option<PyObject>, _exc_value: Option<PyObject>,
+                _traceback: Option<PyObject>) -> bool {
+        self.close();
+        false
+    }
+
+    fn __len__(&self) -> PyResult<usize> {
+        let index = self.get_index()?;
+        Ok(index.len())
+    }
+
+    /// List (phrase_idx, origin) tuples of all indexed phrases ordered by idx.
+    fn iter_phrases(&self) -> PyResult<Vec<(usize, String)>> {
+        let index = self.get_index()?;
+        let phrases = index.phrases()
+            .sorted_by_key(|(phrase_idx, _)| *phrase_idx)
+            .map(|(phrase_idx, origin)| (phrase_idx, origin.to_string()))
+            .collect();
+        Ok(phrases)
+    }
+
+    /// Check if a phrase with the index exists, while indexing or after.
+    fn contains(&self, phrase_idx: usize) -> PyResult<bool> {
+        match &self.index {
+            FuzzDex::Indexer(indexer) => Ok(indexer.contains(phrase_idx)),
+            FuzzDex::Index(index) => Ok(index.contains(phrase_idx)),
+            FuzzDex::Closed => Err(closed_error()),
+        }
+    }
+
+    /// List indices of phrases that produced no trigrams and can't be found.
+    fn unsearchable_phrases(&self) -> PyResult<Vec<usize>> {
+        match &self.index {
+            FuzzDex::Indexer(indexer) => Ok(indexer.unsearchable_phrases()),
+            FuzzDex::Index(index) => Ok(index.index.unsearchable_phrases()),
+            FuzzDex::Closed => Err(closed_error()),
+        }
+    }
+
+    /// Map each constraint occurring in the index to its number of phrases.
+    fn constraints(&self) -> PyResult<HashMap<usize, usize, FastHash>> {
+        let index = self.get_index()?;
+        Ok(index.constraints())
+    }
+
+    /// Read trigram score and positions as (phrase_idx, token_idx) tuples.
+    fn trigram_entry(&self, py: Python, trigram: &str) -> PyResult<Option<PyObject>> {
+        let index = self.get_index()?;
+        match index.trigram_entry(trigram) {
+            Some(entry) => {
+                let pyentry = PyDict::new(py);
+                pyentry.set_item("score", entry.score)?;
+                pyentry.set_item("positions", entry.positions)?;
+                pyentry.set_item("capped", entry.capped)?;
+                Ok(Some(pyentry.into()))
             }
-            FuzzDex::Index(_) => {
-                Err(PyErr::new::<PyRuntimeError, _>("Index is already finished."))
+            None => Ok(None)
+        }
+    }
+
+    /// Normalized tokens of the phrase or None if it doesn't exist.
+    fn phrase_tokens(&self, phrase_idx: usize) -> PyResult<Option<Vec<String>>> {
+        let index = self.get_index()?;
+        Ok(index.phrase_tokens(phrase_idx).map(|tokens| tokens.to_vec()))
+    }
+
+    /// Sorted list of constraints of the phrase or None if it doesn't exist.
+    fn phrase_constraints(&self, phrase_idx: usize) -> PyResult<Option<Vec<usize>>> {
+        let index = self.get_index()?;
+        Ok(index.phrase_constraints(phrase_idx)
+           .map(|constraints| constraints.iter().copied().sorted().collect()))
+    }
+
+    /// Show stored tokens, their trigrams and constraints of a phrase, or
+    /// None if there's no such phrase.
+    fn debug_phrase(&self, py: Python, phrase_idx: usize) -> PyResult<Option<PyObject>> {
+        let index = self.get_index()?;
+        match index.debug_phrase(phrase_idx) {
+            Some(debug) => {
+                let pydebug = PyDict::new(py);
+                pydebug.set_item("origin", debug.origin)?;
+                pydebug.set_item("tokens", debug.tokens)?;
+                pydebug.set_item("trigrams", debug.trigrams)?;
+                pydebug.set_item("constraints", debug.constraints)?;
+                Ok(Some(pydebug.into()))
             }
+            None => Ok(None)
         }
     }
 
-    /// Query index using given criterions.
+    /// List trigrams of the must token as it would be searched, each as a
+    /// dict with `trigram`, `present` and `score` keys.
+    fn explain_must<'py>(&self, py: Python<'py>, token: &str) -> PyResult<Vec<&'py PyDict>> {
+        let index = self.get_index()?;
+        let query = index.query(&[token], &[]);
+        index.explain_must(&query.must)
+            .into_iter()
+            .map(|must_trigram| {
+                let pytrigram = PyDict::new(py);
+                pytrigram.set_item("trigram", must_trigram.trigram)?;
+                pytrigram.set_item("present", must_trigram.present)?;
+                pytrigram.set_item("score", must_trigram.score)?;
+                Ok(pytrigram)
+            })
+            .collect()
+    }
+
+    /// List (phrase_idx, score) tuples of phrases sharing trigrams with the
+    /// must token, by decreasing trigram score.
+    fn token_heatmap(&self, py: Python, must: &str) -> PyResult<Vec<(usize, f32)>> {
+        let index = self.get_index()?;
+        let query = index.query(&[must], &[]);
+        Ok(py.allow_threads(move || index.token_heatmap(&query.must)))
+    }
+
+    /// Upper bound of the number of candidate phrases of the must token,
+    /// much cheaper than searching. Not the exact candidate count.
+    fn estimate_candidates(&self, must: &str) -> PyResult<usize> {
+        Ok(self.get_index()?.estimate_candidates(must))
+    }
+
+    /// True if any trigram of the token exists in the index. False means
+    /// the token is unknown, rather than too distant from indexed tokens.
+    fn has_any_trigram(&self, token: &str) -> PyResult<bool> {
+        Ok(self.get_index()?.has_any_trigram(token))
+    }
+
+    /// Recommend a `scan_cutoff` for the corpus: the highest one keeping the
+    /// top results of the sample must tokens the same as an exhaustive
+    /// search. A heuristic; only as good as the samples are representative.
+    fn recommend_scan_cutoff(&self, py: Python, sample_queries: Vec<&str>) -> PyResult<f32> {
+        let index = self.get_index()?;
+        Ok(py.allow_threads(move || index.recommend_scan_cutoff(&sample_queries)))
+    }
+
+    /// Precompute heatmaps for likely must tokens, normalized like in
+    /// `search`. Returns counts of inserted, already cached and evicted
+    /// entries.
+    fn warm_cache(&self, py: Python, tokens: Vec<&str>) -> PyResult<PyObject> {
+        let index = self.get_index()?;
+        let stats = py.allow_threads(
+            move || {
+                index.warm_cache(&tokens)
+            });
+        let pystats = PyDict::new(py);
+        pystats.set_item("inserted", stats.inserted)?;
+        pystats.set_item("cached", stats.cached)?;
+        pystats.set_item("evicted", stats.evicted)?;
+        Ok(pystats.into())
+    }
+
+    /// Read cache statistics.
     fn cache_stats(&self, py: Python) -> PyResult<PyObject> {
         let index = self.get_index()?;
         let stats = index.cache_stats();
@@ -93,51 +487,341 @@ impl PyFuzzDex {
         pystats.set_item("misses", stats.misses)?;
         pystats.set_item("inserts", stats.inserts)?;
         pystats.set_item("size", stats.size)?;
+        pystats.set_item("bytes", stats.bytes)?;
         Ok(pystats.into())
     }
 
-    /// Query index using given criterions.
+    /// Zero cache counters without dropping the cached heatmaps.
+    fn cache_stats_reset(&self) -> PyResult<()> {
+        let index = self.get_index()?;
+        index.cache_stats_reset();
+        Ok(())
+    }
+
+    /// Query index using given criterions or a prebuilt `Query` passed
+    /// instead of the must tokens (other criterions are then ignored).
+    /// With more than one must token
+    /// `token` and `distance` of results are lists with entry for each must
+    /// token, otherwise they are scalars. With `with_stats` a tuple of
+    /// results and search statistics is returned. With `explain` results
+    /// contain additional `should_breakdown` list of (token, score) and
+    /// `edit_ops` list of (kind, position, grapheme) transforming the matched
+    /// token into the must token and `matched_trigrams` list of (trigram,
+    /// score) of the must token found in the matched token and
+    /// `short_token_match` flag set when all of them are pseudo or edge
+    /// trigrams of short tokens. Must
+    /// tokens shorter than `min_must_length` raise ValueError. `scan_cutoff`
+    /// of 0.0 disables early breaks and scans all candidates. `limit` of 0
+    /// means no limit. `prefer_exact` scans phrases containing the must token
+    /// literally first. `fold_accents` ignores accents when calculating
+    /// the distance. With `transpositions` swapped adjacent letters count as
+    /// a single edit. `should_weight` scales the should scores and
+    /// `should_weights` lists weights of individual should tokens; negative
+    /// ones penalize phrases containing the token.
+    /// `constraint_str` requires a string constraint instead of `constraint`;
+    /// unknown strings match nothing. With `fallback_best` the nearest phrase
+    /// is returned with `within_distance` set to False when nothing matches
+    /// within `max_distance`.
+    ///
+    /// With a `callback` results are not collected into a list; instead the
+    /// callback is called with each result dict in the final ranked order
+    /// (the same as the returned list would have) and the number of passed
+    /// results is returned. Candidates are scanned with the GIL released
+    /// and results are built only as they are passed. Returning False from
+    /// the callback stops the search early. `with_stats` is ignored.
     #[allow(clippy::too_many_arguments)]
     fn search<'py>(&self, py: Python<'py>,
-                   must: Vec<&str>, should: Vec<&str>,
+                   must: &PyAny, should: Option<Vec<String>>,
                    constraint: Option<usize>, limit: Option<usize>,
                    max_distance: Option<usize>,
-                   scan_cutoff: Option<f32>) -> PyResult<PyObject> {
+                   scan_cutoff: Option<f32>,
+                   max_candidates: Option<usize>,
+                   with_stats: Option<bool>,
+                   explain: Option<bool>,
+                   min_must_length: Option<usize>,
+                   prefer_exact: Option<bool>,
+                   fold_accents: Option<bool>,
+                   transpositions: Option<bool>,
+                   should_weight: Option<f32>,
+                   constraint_str: Option<String>,
+                   fallback_best: Option<bool>,
+                   callback: Option<&PyAny>,
+                   should_weights: Option<Vec<f32>>) -> PyResult<PyObject> {
         let index = self.get_index()?;
-        if must.len() != 1 {
-            return Err(PyErr::new::<PyRuntimeError, _>("Exactly one `must token` is supported."));
+        let pyquery = match must.extract::<PyQuery>() {
+            Ok(pyquery) => pyquery,
+            Err(_) => PyQuery {
+                must: must.extract()?,
+                should: should.unwrap_or_default(),
+                constraint,
+                constraint_str,
+                limit,
+                max_distance,
+                scan_cutoff: scan_cutoff.unwrap_or(0.3),
+                max_candidates,
+                explain: explain.unwrap_or(false),
+                min_must_length: min_must_length.unwrap_or(1),
+                prefer_exact: prefer_exact.unwrap_or(false),
+                fold_accents: fold_accents.unwrap_or(false),
+                transpositions: transpositions.unwrap_or(false),
+                should_weight: should_weight.unwrap_or(1.0),
+                should_weights: should_weights.unwrap_or_default(),
+                fallback_best: fallback_best.unwrap_or(false),
+            }
+        };
+        if pyquery.must.is_empty() {
+            return Err(PyErr::new::<PyRuntimeError, _>("At least one `must token` is required."));
         }
-        let query = query::Query::new(&must, &should)
-            .constraint(constraint)
-            .max_distance(max_distance)
-            .limit(limit)
-            .scan_cutoff(scan_cutoff.unwrap_or(0.3));
+        let query = pyquery.to_query(index)?;
+        let multiple_must = pyquery.must.len() > 1;
 
-        let search_results = py.allow_threads(
+        if let Some(callback) = callback {
+            let results = py.allow_threads(|| index.search_iter(&query));
+            let mut passed = 0;
+            for result in results {
+                let pyresult = result_to_py(py, &result, multiple_must, pyquery.explain)?;
+                passed += 1;
+                let response = callback.call1((pyresult,))?;
+                if response.is(PyBool::new(py, false)) {
+                    break;
+                }
+            }
+            return Ok(passed.into_py(py));
+        }
+
+        let (search_results, stats) = py.allow_threads(
             move || {
-                index.search(&query)
+                index.search_with_stats(&query)
             });
         let pyresults = search_results.iter()
-            .map(|result| {
-                let pyresult = PyDict::new(py);
-                pyresult.set_item("origin", result.origin).unwrap();
-                pyresult.set_item("index", result.index).unwrap();
-                pyresult.set_item("token", result.token).unwrap();
-                pyresult.set_item("distance", result.distance).unwrap();
-                pyresult.set_item("score", result.score).unwrap();
-                pyresult.set_item("should_score", result.should_score).unwrap();
-                pyresult
-            });
+            .map(|result| result_to_py(py, result, multiple_must, pyquery.explain))
+            .collect::<PyResult<Vec<&PyDict>>>()?;
 
         let list = PyList::new(py, pyresults);
-        Ok(list.into())
+        if with_stats.unwrap_or(false) {
+            let pystats = PyDict::new(py);
+            pystats.set_item("scanned", stats.scanned)?;
+            pystats.set_item("truncated", stats.truncated)?;
+            Ok((list, pystats).into_py(py))
+        } else {
+            Ok(list.into())
+        }
+    }
+
+    /// Query index with free text. The most selective token (with the
+    /// rarest trigrams) is used as the must token and others as should
+    /// tokens. Returns a list of dicts like `search`.
+    fn search_phrase<'py>(&self, py: Python<'py>, text: &str,
+                          limit: Option<usize>) -> PyResult<Vec<&'py PyDict>> {
+        let index = self.get_index()?;
+        let results = py.allow_threads(move || index.search_phrase(text, limit));
+        results.iter()
+            .map(|result| result_to_py(py, result, false, false))
+            .collect()
+    }
+
+    /// Up to `n` distinct indexed tokens closest to the token, as a list of
+    /// (token, distance) tuples ordered by the distance. Useful as "did you
+    /// mean" suggestions when a search finds nothing.
+    fn suggest(&self, py: Python, token: &str, n: usize) -> PyResult<Vec<(String, usize)>> {
+        let index = self.get_index()?;
+        let suggestions = py.allow_threads(move || index.suggest(token, n));
+        Ok(suggestions.into_iter()
+           .map(|(token, distance)| (token.to_string(), distance))
+           .collect())
+    }
+
+    /// Query index for the single best result. Returns a dict like `search`
+    /// or None if nothing matched.
+    #[allow(clippy::too_many_arguments)]
+    fn search_best<'py>(&self, py: Python<'py>,
+                        must: Vec<&str>, should: Vec<&str>,
+                        constraint: Option<usize>,
+                        max_distance: Option<usize>,
+                        scan_cutoff: Option<f32>,
+                        max_candidates: Option<usize>,
+                        explain: Option<bool>,
+                        constraint_str: Option<&str>) -> PyResult<Option<&'py PyDict>> {
+        let index = self.get_index()?;
+        if must.is_empty() {
+            return Err(PyErr::new::<PyRuntimeError, _>("At least one `must token` is required."));
+        }
+        let explain = explain.unwrap_or(false);
+        let query = index.query(&must, &should)
+            .constraint(constraint)
+            .constraint_str(constraint_str)
+            .max_distance(max_distance)
+            .scan_cutoff(scan_cutoff.unwrap_or(0.3))
+            .max_candidates(max_candidates)
+            .explain(explain);
+
+        let best = py.allow_threads(move || index.search_best(&query));
+        best.map(|result| result_to_py(py, &result, must.len() > 1, explain))
+            .transpose()
+    }
+
+    /// Query index for the must token and its alternative spellings
+    /// `variants`, returning a dict mapping each (normalized) spelling to the
+    /// list of its results, ranked like in `search`. A phrase matching many
+    /// spellings is listed only under the closest one; `limit` applies to
+    /// each list.
+    #[allow(clippy::too_many_arguments)]
+    fn search_grouped_by_must<'py>(&self, py: Python<'py>,
+                                   must: &str, variants: Vec<&str>,
+                                   should: Option<Vec<&str>>,
+                                   constraint: Option<usize>,
+                                   limit: Option<usize>,
+                                   max_distance: Option<usize>,
+                                   scan_cutoff: Option<f32>,
+                                   constraint_str: Option<&str>)
+                                   -> PyResult<HashMap<String, Vec<&'py PyDict>>> {
+        let index = self.get_index()?;
+        let query = index.query(&[must], &should.unwrap_or_default())
+            .must_any(&variants)
+            .constraint(constraint)
+            .constraint_str(constraint_str)
+            .limit(limit)
+            .max_distance(max_distance)
+            .scan_cutoff(scan_cutoff.unwrap_or(0.3));
+
+        let grouped = py.allow_threads(move || index.search_grouped_by_must(&query));
+        grouped.iter()
+            .map(|(variant, results)| {
+                let results = results.iter()
+                    .map(|result| result_to_py(py, result, false, false))
+                    .collect::<PyResult<Vec<&PyDict>>>()?;
+                Ok((variant.clone(), results))
+            })
+            .collect()
+    }
+}
+
+/// Reusable search criterions, with the same meaning and defaults as the
+/// Rust `Query`. Must tokens are normalized for the searched index at search
+/// time.
+#[pyclass(name="Query")]
+#[derive(Clone)]
+pub struct PyQuery {
+    #[pyo3(get)]
+    must: Vec<String>,
+    #[pyo3(get)]
+    should: Vec<String>,
+    #[pyo3(get)]
+    constraint: Option<usize>,
+    #[pyo3(get)]
+    constraint_str: Option<String>,
+    #[pyo3(get)]
+    limit: Option<usize>,
+    #[pyo3(get)]
+    max_distance: Option<usize>,
+    #[pyo3(get)]
+    scan_cutoff: f32,
+    #[pyo3(get)]
+    max_candidates: Option<usize>,
+    #[pyo3(get)]
+    explain: bool,
+    #[pyo3(get)]
+    min_must_length: usize,
+    #[pyo3(get)]
+    prefer_exact: bool,
+    #[pyo3(get)]
+    fold_accents: bool,
+    #[pyo3(get)]
+    transpositions: bool,
+    #[pyo3(get)]
+    should_weight: f32,
+    #[pyo3(get)]
+    should_weights: Vec<f32>,
+    #[pyo3(get)]
+    fallback_best: bool,
+}
+
+impl PyQuery {
+    /// Create a query tokenized for the index.
+    fn to_query(&self, index: &seeker::Index) -> PyResult<fuzzdex::query::Query> {
+        let must: Vec<&str> = self.must.iter().map(|token| token.as_str()).collect();
+        let should: Vec<&str> = self.should.iter().map(|token| token.as_str()).collect();
+        if !self.should_weights.iter().all(|weight| weight.is_finite()) {
+            return Err(PyErr::new::<PyValueError, _>("Should weights must be finite."));
+        }
+        index.query(&must, &should)
+            .constraint(self.constraint)
+            .constraint_str(self.constraint_str.as_deref())
+            .max_distance(self.max_distance)
+            .limit(self.limit)
+            .scan_cutoff(self.scan_cutoff)
+            .max_candidates(self.max_candidates)
+            .explain(self.explain)
+            .prefer_exact(self.prefer_exact)
+            .fold_accents(self.fold_accents)
+            .transpositions(self.transpositions)
+            .should_weight(self.should_weight)
+            .should_weights(&self.should_weights)
+            .fallback_best(self.fallback_best)
+            .min_must_length(self.min_must_length)
+            .map_err(|err| PyErr::new::<PyValueError, _>(err.to_string()))
+    }
+}
+
+#[pymethods]
+impl PyQuery {
+    /// Create a query; arguments are like in `FuzzDex.search`, but
+    /// `max_distance` defaults to 2. Raises ValueError on empty must or
+    /// `scan_cutoff` outside of 0-1.
+    #[new]
+    #[args(should = "Vec::new()", max_distance = "2", scan_cutoff = "0.3",
+           explain = "false", min_must_length = "1", prefer_exact = "false",
+           fold_accents = "false", transpositions = "false", should_weight = "1.0",
+           fallback_best = "false", should_weights = "Vec::new()")]
+    #[allow(clippy::too_many_arguments)]
+    fn new(must: Vec<String>, should: Vec<String>,
+           constraint: Option<usize>, constraint_str: Option<String>,
+           limit: Option<usize>, max_distance: Option<usize>,
+           scan_cutoff: f32, max_candidates: Option<usize>,
+           explain: bool, min_must_length: usize,
+           prefer_exact: bool, fold_accents: bool, transpositions: bool,
+           should_weight: f32, fallback_best: bool, should_weights: Vec<f32>) -> PyResult<Self> {
+        if must.is_empty() {
+            return Err(PyErr::new::<PyValueError, _>("At least one `must token` is required."));
+        }
+        if !(0.0..=1.0).contains(&scan_cutoff) {
+            return Err(PyErr::new::<PyValueError, _>("Scan cutoff must be within 0-1."));
+        }
+        Ok(PyQuery {
+            must, should, constraint, constraint_str, limit, max_distance,
+            scan_cutoff, max_candidates, explain, min_must_length,
+            prefer_exact, fold_accents, transpositions, should_weight,
+            fallback_best, should_weights,
+        })
     }
 }
 
 /// Helper to calculate levenshtein distance from Python without additional libs.
+/// In the default "grapheme" mode a letter with combining marks ("y̆") is a
+/// single unit, like in the index. "char" mode counts code points, matching
+/// numbers of e.g. python-Levenshtein.
+#[pyfunction]
+fn distance(side_a: &str, side_b: &str, mode: Option<&str>) -> PyResult<usize> {
+    match mode.unwrap_or("grapheme") {
+        "grapheme" => Ok(utils::distance(side_a, side_b)),
+        "char" => Ok(utils::distance_chars(side_a, side_b)),
+        other => Err(PyErr::new::<PyValueError, _>(
+            format!("Unknown distance mode: {}", other))),
+    }
+}
+
+/// Distances of many (side_a, side_b) pairs, like `distance` called for each
+/// pair but without the per-call overhead. Computed with the GIL released
+/// (and in parallel with the `parallel` feature).
 #[pyfunction]
-fn distance(side_a: &str, side_b: &str) -> PyResult<usize> {
-    Ok(utils::distance(side_a, side_b))
+fn distances(py: Python, pairs: Vec<(&str, &str)>, mode: Option<&str>) -> PyResult<Vec<usize>> {
+    let metric = match mode.unwrap_or("grapheme") {
+        "grapheme" => utils::distances,
+        "char" => utils::distances_chars,
+        other => return Err(PyErr::new::<PyValueError, _>(
+            format!("Unknown distance mode: {}", other))),
+    };
+    Ok(py.allow_threads(|| metric(&pairs)))
 }
 
 /// Python access to internal trigramizer.
@@ -146,10 +830,11 @@ fn trigramize(token: &str) -> PyResult<Vec<String>> {
     Ok(utils::trigramize(token))
 }
 
-/// Python access to the internal tokenizer.
+/// Python access to the internal tokenizer. `min_length` defaults to 1 like
+/// in the index; use `FuzzDex.tokenize` to tokenize exactly like an index.
 #[pyfunction]
 fn tokenize(phrase: &str, min_length: Option<usize>) -> PyResult<Vec<String>> {
-    let min_length = min_length.unwrap_or(2);
+    let min_length = min_length.unwrap_or(1);
     Ok(utils::tokenize(phrase, min_length))
 }
 
@@ -158,7 +843,9 @@ fn tokenize(phrase: &str, min_length: Option<usize>) -> PyResult<Vec<String>> {
 fn pyfuzzdex(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add("__doc__", "FUZZy inDEX in Rust")?;
     m.add_class::<PyFuzzDex>()?;
+    m.add_class::<PyQuery>()?;
     m.add_function(wrap_pyfunction!(distance, m)?)?;
+    m.add_function(wrap_pyfunction!(distances, m)?)?;
     m.add_function(wrap_pyfunction!(trigramize, m)?)?;
     m.add_function(wrap_pyfunction!(tokenize, m)?)?;
     Ok(())
@@ -1,40 +1,101 @@
 use std::iter::FromIterator;
 use regex::Regex;
 use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
 
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_normalization::UnicodeNormalization;
 use unicode_categories::UnicodeCategories;
 
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
 lazy_static! {
     /* NOTE: Maybe detect a unicode group for interpunction chars */
     static ref SEPARATOR: Regex = Regex::new("[- \t\n'’`„\"_.,;:=]+").expect("invalid regexp");
 }
 
+/// Which combining marks are removed from tokens before trigramization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum MarkPolicy {
+    /// Remove nonspacing marks (Mn), eg. accents, so "y̆es" matches "yes".
+    #[default]
+    StripNonspacing,
+    /// Remove all marks: nonspacing, spacing combining (Mc) and enclosing
+    /// (Me), eg. also vowel signs of Indic scripts.
+    StripAll,
+    /// Keep all marks, for scripts where marks tell words apart (eg. "कुल"
+    /// and "कल" in Devanagari).
+    Keep,
+}
+
+impl MarkPolicy {
+    /// Whether the policy removes the character.
+    pub fn strips(self, ch: char) -> bool {
+        match self {
+            MarkPolicy::StripNonspacing => ch.is_mark_nonspacing(),
+            MarkPolicy::StripAll => ch.is_mark(),
+            MarkPolicy::Keep => false,
+        }
+    }
+}
+
 pub fn trigramize(token: &str) -> Vec<String> {
-    /* NOTE: Maybe accent removal should be done during tokenization? That makes
-     * edit distance ignore accents though */
+    trigramize_with_tolerance(token, false)
+}
 
-    /* Normalize accents as separate unicode characters and filter them out */
-    let mut token: String = token.nfd().filter(|ch| !ch.is_mark_nonspacing()).collect();
+/// Trigrams of consecutive graphemes of the token, without the pseudo and
+/// edge trigrams added by `trigramize_with_tolerance` for short tokens.
+pub fn regular_trigrams(token: &str) -> Vec<String> {
+    regular_trigrams_with_marks(token, MarkPolicy::default())
+}
 
-    /* NOTE: Various language-specific letters. It's not required, but can
-     * handle certain human errors better */
-    for (ch_from, ch_to) in [("ł", "l"), ("ß", "ss")] {
-        token = token.replace(ch_from, ch_to);
+/// Regular trigrams (see `regular_trigrams`) with marks removed according
+/// to the policy.
+pub fn regular_trigrams_with_marks(token: &str, marks: MarkPolicy) -> Vec<String> {
+    let token = fold_marks(token, marks);
+    window_trigrams(&token.graphemes(true).collect::<Vec<&str>>())
+}
+
+fn window_trigrams(graphemes: &[&str]) -> Vec<String> {
+    if graphemes.len() < 3 {
+        return Vec::new();
     }
+    Vec::from_iter(
+        (0..graphemes.len() - 2).map(|i| &graphemes[i..i + 3]).map(|s| s.join(""))
+    )
+}
+
+/// Trigramize token. Nonspacing marks are dropped, so a token made of
+/// combining marks only produces no trigrams at all. Other characters,
+/// including the U+FFFD replacement character left by lossy decoding, are
+/// regular graphemes. (Rust strings can't hold lone surrogates; Python
+/// bindings reject them before reaching here.)
+///
+/// With `extended` tolerance, additional edge trigrams are generated for all
+/// tokens up to 9 graphemes instead of only for 4 and 5, so the
+/// typo-tolerance degrades smoothly with the length of the token. This
+/// increases the size of the index.
+pub fn trigramize_with_tolerance(token: &str, extended: bool) -> Vec<String> {
+    trigramize_with_marks(token, extended, MarkPolicy::default())
+}
+
+/// Trigramize token like `trigramize_with_tolerance`, removing marks
+/// according to the policy instead of only the nonspacing ones.
+pub fn trigramize_with_marks(token: &str, extended: bool, marks: MarkPolicy) -> Vec<String> {
+    /* NOTE: Accents are not removed during tokenization, as that would make
+     * the edit distance ignore them. See `Query::fold_accents`. */
+    let token = fold_marks(token, marks);
 
     /* Unicode characters start at various byte boundaries */
     let graphemes: Vec<&str> = token.graphemes(true).collect::<Vec<&str>>();
     let cnt = graphemes.len();
 
-    let mut trigrams: Vec<String> = if cnt >= 3 {
-        Vec::from_iter(
-            (0..graphemes.len() - 2).map(|i| &graphemes[i..i + 3]).map(|s| s.join(""))
-        )
-    } else {
-        Vec::new()
-    };
+    let mut trigrams: Vec<String> = window_trigrams(&graphemes);
+
+    /* Edge trigrams survive a typo in the middle of the token */
+    let first_edge = |graphemes: &[&str]| graphemes[0].to_string() + graphemes[1] + graphemes[cnt - 1];
+    let last_edge = |graphemes: &[&str]| graphemes[0].to_string() + graphemes[cnt - 2] + graphemes[cnt - 1];
 
     match cnt {
         /* Generate pseudo trigrams for 1 and 2 letter words. No typo-tolerance. */
@@ -44,24 +105,122 @@ pub fn trigramize(token: &str) -> Vec<String> {
         2 => {
             trigrams.push(graphemes[0].to_string() + graphemes[1] + " ");
         }
+        /* Tolerate a typo in the middle letter */
+        3 if extended => {
+            trigrams.push(graphemes[0].to_string() + " " + graphemes[2]);
+        }
         /* Increase typo-tolerance on short strings */
         4 | 5 => {
-            trigrams.push(graphemes[0].to_string() + graphemes[1] + graphemes[cnt - 1]);
-            trigrams.push(graphemes[0].to_string() + graphemes[cnt - 2] + graphemes[cnt - 1]);
+            trigrams.push(first_edge(&graphemes));
+            trigrams.push(last_edge(&graphemes));
+        }
+        6 | 7 if extended => {
+            trigrams.push(first_edge(&graphemes));
+            trigrams.push(last_edge(&graphemes));
+        }
+        8 | 9 if extended => {
+            trigrams.push(first_edge(&graphemes));
         }
         _ => {}
     }
     trigrams
 }
 
+/// Strip accents the way trigrams are generated: nonspacing marks are
+/// removed and some language-specific letters are replaced.
+pub fn fold_accents(token: &str) -> String {
+    fold_marks(token, MarkPolicy::default())
+}
+
+/// Strip accents like `fold_accents`, removing marks according to the
+/// policy. Language-specific letters are replaced regardless of it.
+pub fn fold_marks(token: &str, marks: MarkPolicy) -> String {
+    /* Normalize accents as separate unicode characters and filter them out */
+    let mut token: String = token.nfd().filter(|ch| !marks.strips(*ch)).collect();
+
+    /* NOTE: Various language-specific letters. It's not required, but can
+     * handle certain human errors better */
+    for (ch_from, ch_to) in [("ł", "l"), ("ß", "ss")] {
+        token = token.replace(ch_from, ch_to);
+    }
+    token
+}
+
 /* Should this be Vec, or maybe hashset? What about non-unique tokens? */
+/// Split phrase into lowercase tokens of at least `min_length` graphemes.
 pub fn tokenize(phrase: &str, min_length: usize) -> Vec<String> {
-    let tokens = SEPARATOR.split(phrase)
-        .into_iter()
-        .map(|t| t.trim().to_lowercase())
-        .filter(|t| t.len() >= min_length)
-        .collect();
-    tokens
+    tokenize_with_folding(phrase, min_length, false)
+}
+
+/// Tokenize phrase, lowercasing tokens with `case_fold` instead of
+/// `str::to_lowercase` when `fold_case` is set.
+pub fn tokenize_with_folding(phrase: &str, min_length: usize, fold_case: bool) -> Vec<String> {
+    normalize_tokens(SEPARATOR.split(phrase), min_length, fold_case)
+}
+
+/// Tokenize phrase splitting on any Unicode whitespace or punctuation
+/// character (eg. ideographic space or full-width comma) instead of the fixed
+/// separator list. Symbols like "=" or "`" are not punctuation and stay
+/// within tokens.
+pub fn tokenize_unicode(phrase: &str, min_length: usize, fold_case: bool) -> Vec<String> {
+    let parts = phrase.split(|ch: char| ch.is_whitespace() || ch.is_punctuation());
+    normalize_tokens(parts, min_length, fold_case)
+}
+
+/// Split a token into runs of digits and of other characters, eg. "12b" into
+/// "12" and "b". Combining marks stay with the preceding character.
+pub fn split_digit_runs(token: &str) -> Vec<&str> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut digits: Option<bool> = None;
+    for (offset, grapheme) in token.grapheme_indices(true) {
+        let is_digit = grapheme.chars().next().is_some_and(|ch| ch.is_numeric());
+        if digits.is_some_and(|digits| digits != is_digit) {
+            runs.push(&token[start..offset]);
+            start = offset;
+        }
+        digits = Some(is_digit);
+    }
+    if start < token.len() {
+        runs.push(&token[start..]);
+    }
+    runs
+}
+
+fn normalize_tokens<'a>(parts: impl Iterator<Item = &'a str>,
+                        min_length: usize, fold_case: bool) -> Vec<String> {
+    parts
+        .map(|t| if fold_case { case_fold(t.trim()) } else { t.trim().to_lowercase() })
+        /* Byte length is an upper bound of the grapheme count */
+        .filter(|t| t.len() >= min_length && (min_length <= 1 || t.graphemes(true).count() >= min_length))
+        .collect()
+}
+
+/// Caseless form of a token: `str::to_lowercase` with a small table of the
+/// Unicode full case foldings common in place names. "ß" becomes "ss", Latin
+/// "ﬀ"-"ﬆ" ligatures their letters and final sigma a regular one. Dotted
+/// capital "İ" folds to a plain "i" (Turkic folding), so "İstanbul" matches
+/// "istanbul" instead of keeping a combining dot. It's not full case
+/// folding: other letters which fold differently than they lowercase (eg.
+/// "ŉ", "ǰ", "ſ", "µ", Armenian ligatures or Greek letters with
+/// ypogegrammeni) are only lowercased.
+pub fn case_fold(token: &str) -> String {
+    let mut folded = String::with_capacity(token.len());
+    for ch in token.chars() {
+        match ch {
+            'ß' | 'ẞ' => folded.push_str("ss"),
+            'İ' => folded.push('i'),
+            'ς' => folded.push('σ'),
+            'ﬀ' => folded.push_str("ff"),
+            'ﬁ' => folded.push_str("fi"),
+            'ﬂ' => folded.push_str("fl"),
+            'ﬃ' => folded.push_str("ffi"),
+            'ﬄ' => folded.push_str("ffl"),
+            'ﬅ' | 'ﬆ' => folded.push_str("st"),
+            _ => folded.extend(ch.to_lowercase()),
+        }
+    }
+    folded
 }
 
 /** Compare first 500 graphemes of strings and return a Levenshtein distance */
@@ -78,6 +237,314 @@ pub fn distance(side_a: &str, side_b: &str) -> usize {
     distance
 }
 
+/** Distances of many pairs of strings (see `distance`) computed in one call;
+ * in parallel with the `parallel` feature. Order matches the pairs. */
+pub fn distances(pairs: &[(&str, &str)]) -> Vec<usize> {
+    pairwise(pairs, distance)
+}
+
+/** Distances of many pairs counted in chars (see `distance_chars`). */
+pub fn distances_chars(pairs: &[(&str, &str)]) -> Vec<usize> {
+    pairwise(pairs, distance_chars)
+}
+
+fn pairwise(pairs: &[(&str, &str)], metric: fn(&str, &str) -> usize) -> Vec<usize> {
+    #[cfg(feature = "parallel")]
+    let pairs = pairs.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let pairs = pairs.iter();
+
+    pairs.map(|(side_a, side_b)| metric(side_a, side_b)).collect()
+}
+
+/** Compare first 500 chars (Unicode scalar values) of strings and return a
+ * Levenshtein distance. Unlike `distance` a letter with a combining mark like
+ * "y̆" counts as two units, which matches numbers of most other libraries. */
+pub fn distance_chars(side_a: &str, side_b: &str) -> usize {
+    let chars_a = side_a.chars().take(500).collect::<Vec<char>>();
+    let chars_b = side_b.chars().take(500).collect::<Vec<char>>();
+    let (distance, _) = levenshtein_diff::levenshtein_tabulation(&chars_a, &chars_b);
+    distance
+}
+
+/** Levenshtein distance of first 500 graphemes of strings if it's at most
+ * `max`. Only a band of `2*max+1` diagonals of the DP matrix is calculated
+ * and the calculation stops as soon as the distance is known to exceed `max`,
+ * so it's much faster than `distance` for small `max`. */
+pub fn distance_within(side_a: &str, side_b: &str, max: usize) -> Option<usize> {
+    let graphemes_a = side_a.graphemes(true).take(500).collect::<Vec<&str>>();
+    let graphemes_b = side_b.graphemes(true).take(500).collect::<Vec<&str>>();
+    let (len_a, len_b) = (graphemes_a.len(), graphemes_b.len());
+    if len_a.abs_diff(len_b) > max {
+        return None;
+    }
+
+    /* Values above max are all equivalent; cap them to avoid overflows */
+    let over = max + 1;
+    let mut prev: Vec<usize> = (0..=len_b).map(|j| std::cmp::min(j, over)).collect();
+    let mut cur: Vec<usize> = vec![over; len_b + 1];
+
+    for i in 1..=len_a {
+        let low = std::cmp::max(1, i.saturating_sub(max));
+        let high = std::cmp::min(len_b, i + max);
+
+        cur[0] = std::cmp::min(i, over);
+        cur[low - 1] = if low == 1 { cur[0] } else { over };
+        let mut row_min = cur[low - 1];
+        for j in low..=high {
+            let substitution = prev[j - 1] + usize::from(graphemes_a[i - 1] != graphemes_b[j - 1]);
+            let deletion = prev[j] + 1;
+            let insertion = cur[j - 1] + 1;
+            let value = std::cmp::min(std::cmp::min(substitution, deletion),
+                                      std::cmp::min(insertion, over));
+            cur[j] = value;
+            row_min = std::cmp::min(row_min, value);
+        }
+        if high < len_b {
+            cur[high + 1] = over;
+        }
+        if row_min > max {
+            return None;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    let distance = prev[len_b];
+    if distance <= max {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+/** Damerau-Levenshtein (optimal string alignment) distance of first 500
+ * graphemes of strings: like `distance`, but swapping two adjacent
+ * graphemes costs a single edit. */
+pub fn damerau_distance(side_a: &str, side_b: &str) -> usize {
+    let graphemes_a = side_a.graphemes(true).take(500).collect::<Vec<&str>>();
+    let graphemes_b = side_b.graphemes(true).take(500).collect::<Vec<&str>>();
+    let len_b = graphemes_b.len();
+
+    let mut prev2: Vec<usize> = vec![0; len_b + 1];
+    let mut prev: Vec<usize> = (0..=len_b).collect();
+    let mut cur: Vec<usize> = vec![0; len_b + 1];
+    for i in 1..=graphemes_a.len() {
+        cur[0] = i;
+        for j in 1..=len_b {
+            let substitution = prev[j - 1] + usize::from(graphemes_a[i - 1] != graphemes_b[j - 1]);
+            let mut value = std::cmp::min(substitution, std::cmp::min(prev[j], cur[j - 1]) + 1);
+            if i > 1 && j > 1 && graphemes_a[i - 1] == graphemes_b[j - 2]
+                && graphemes_a[i - 2] == graphemes_b[j - 1] {
+                value = std::cmp::min(value, prev2[j - 2] + 1);
+            }
+            cur[j] = value;
+        }
+        std::mem::swap(&mut prev2, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[len_b]
+}
+
+/** Damerau-Levenshtein (optimal string alignment) distance of first 500
+ * graphemes if it's at most `max`. Uses the same banded calculation with an
+ * early exit as `distance_within`. */
+pub fn damerau_within(side_a: &str, side_b: &str, max: usize) -> Option<usize> {
+    let graphemes_a = side_a.graphemes(true).take(500).collect::<Vec<&str>>();
+    let graphemes_b = side_b.graphemes(true).take(500).collect::<Vec<&str>>();
+    let (len_a, len_b) = (graphemes_a.len(), graphemes_b.len());
+    if len_a.abs_diff(len_b) > max {
+        return None;
+    }
+
+    /* Values above max are all equivalent; cap them to avoid overflows */
+    let over = max + 1;
+    let mut prev2: Vec<usize> = vec![over; len_b + 1];
+    let mut prev: Vec<usize> = (0..=len_b).map(|j| std::cmp::min(j, over)).collect();
+    let mut cur: Vec<usize> = vec![over; len_b + 1];
+
+    for i in 1..=len_a {
+        let low = std::cmp::max(1, i.saturating_sub(max));
+        let high = std::cmp::min(len_b, i + max);
+
+        cur[0] = std::cmp::min(i, over);
+        cur[low - 1] = if low == 1 { cur[0] } else { over };
+        let mut row_min = cur[low - 1];
+        for j in low..=high {
+            let substitution = prev[j - 1] + usize::from(graphemes_a[i - 1] != graphemes_b[j - 1]);
+            let mut value = std::cmp::min(substitution, std::cmp::min(prev[j], cur[j - 1]) + 1);
+            /* Transposition reads the row before previous, which lies within its band */
+            if i > 1 && j > 1 && graphemes_a[i - 1] == graphemes_b[j - 2]
+                && graphemes_a[i - 2] == graphemes_b[j - 1] {
+                value = std::cmp::min(value, prev2[j - 2] + 1);
+            }
+            let value = std::cmp::min(value, over);
+            cur[j] = value;
+            row_min = std::cmp::min(row_min, value);
+        }
+        if high < len_b {
+            cur[high + 1] = over;
+        }
+        /* A transposition costs at least as much as the substitution before
+         * it, so the next rows can't go back below the bound */
+        if row_min > max {
+            return None;
+        }
+        std::mem::swap(&mut prev2, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    let distance = prev[len_b];
+    if distance <= max {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+/// Single edit transforming one string into another. Positions are grapheme
+/// indices in the source string.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditOp {
+    /// Source grapheme at the position is removed.
+    Delete { position: usize },
+    /// Grapheme is inserted before the source position.
+    Insert { position: usize, grapheme: String },
+    /// Source grapheme at the position is replaced.
+    Substitute { position: usize, grapheme: String },
+}
+
+/// Non-empty parts of the token left after removing graphemes from its
+/// edges: `leading` from the start and `trailing` from the end for all
+/// 1 <= leading + trailing <= `max_trim`. Eg. "abc" with 1 gives "bc" and
+/// "ab".
+pub fn edge_trims(token: &str, max_trim: usize) -> Vec<&str> {
+    let offsets: Vec<usize> = token.grapheme_indices(true)
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(token.len()))
+        .collect();
+    let graphemes = offsets.len() - 1;
+    let mut trims = Vec::new();
+    for leading in 0..=max_trim.min(graphemes) {
+        for trailing in 0..=(max_trim - leading).min(graphemes - leading) {
+            if leading + trailing == 0 || leading + trailing == graphemes {
+                continue;
+            }
+            trims.push(&token[offsets[leading]..offsets[graphemes - trailing]]);
+        }
+    }
+    trims
+}
+
+/** Smallest Levenshtein distance of the `needle` to any contiguous window
+ * of the `token` (first 500 graphemes of both), eg. 0 for "straat" in
+ * "beethovenstraat". Skipped graphemes before and after the window are
+ * free. */
+pub fn window_distance(token: &str, needle: &str) -> usize {
+    let graphemes_token = token.graphemes(true).take(500).collect::<Vec<&str>>();
+    let graphemes_needle = needle.graphemes(true).take(500).collect::<Vec<&str>>();
+    let len_token = graphemes_token.len();
+
+    /* Needle graphemes are rows; a window can start at any column for free */
+    let mut prev: Vec<usize> = vec![0; len_token + 1];
+    let mut cur: Vec<usize> = vec![0; len_token + 1];
+    for (i, needle_grapheme) in graphemes_needle.iter().enumerate() {
+        cur[0] = i + 1;
+        for j in 1..=len_token {
+            let substitution = prev[j - 1] + usize::from(*needle_grapheme != graphemes_token[j - 1]);
+            cur[j] = std::cmp::min(substitution, std::cmp::min(prev[j], cur[j - 1]) + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    /* ...and end at any column */
+    prev.into_iter().min().unwrap_or(0)
+}
+
+/** Levenshtein distance of first 500 graphemes of strings along with the
+ * edits transforming `side_a` into `side_b`, ordered by position. Matching
+ * graphemes are preferred over substitutions of the same cost. */
+pub fn distance_ops(side_a: &str, side_b: &str) -> (usize, Vec<EditOp>) {
+    let graphemes_a = side_a.graphemes(true).take(500).collect::<Vec<&str>>();
+    let graphemes_b = side_b.graphemes(true).take(500).collect::<Vec<&str>>();
+    let (distance, matrix) = levenshtein_diff::levenshtein_tabulation(&graphemes_a, &graphemes_b);
+
+    /* Walk the DP matrix back from the end */
+    let mut ops = Vec::with_capacity(distance);
+    let (mut i, mut j) = (graphemes_a.len(), graphemes_b.len());
+    while i > 0 || j > 0 {
+        let current = matrix[i][j];
+        if i > 0 && j > 0 && graphemes_a[i - 1] == graphemes_b[j - 1] && matrix[i - 1][j - 1] == current {
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && j > 0 && matrix[i - 1][j - 1] + 1 == current {
+            ops.push(EditOp::Substitute { position: i - 1, grapheme: graphemes_b[j - 1].to_string() });
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && matrix[i - 1][j] + 1 == current {
+            ops.push(EditOp::Delete { position: i - 1 });
+            i -= 1;
+        } else {
+            ops.push(EditOp::Insert { position: i, grapheme: graphemes_b[j - 1].to_string() });
+            j -= 1;
+        }
+    }
+    ops.reverse();
+    (distance, ops)
+}
+
+/** Jaro-Winkler similarity of two strings compared by graphemes; 1.0 for
+ * identical strings, 0.0 for completely different ones. Favours strings with
+ * a common prefix. */
+pub fn jaro_winkler(side_a: &str, side_b: &str) -> f32 {
+    let graphemes_a = side_a.graphemes(true).take(500).collect::<Vec<&str>>();
+    let graphemes_b = side_b.graphemes(true).take(500).collect::<Vec<&str>>();
+    if graphemes_a.is_empty() && graphemes_b.is_empty() {
+        return 1.0;
+    }
+    if graphemes_a.is_empty() || graphemes_b.is_empty() {
+        return 0.0;
+    }
+
+    /* Graphemes match if equal and not further than half of longer string apart */
+    let window = (std::cmp::max(graphemes_a.len(), graphemes_b.len()) / 2).saturating_sub(1);
+    let mut matched_a = vec![false; graphemes_a.len()];
+    let mut matched_b = vec![false; graphemes_b.len()];
+    let mut matches = 0;
+    for (i, grapheme) in graphemes_a.iter().enumerate() {
+        let start = i.saturating_sub(window);
+        let end = std::cmp::min(i + window + 1, graphemes_b.len());
+        for j in start..end {
+            if !matched_b[j] && graphemes_b[j] == *grapheme {
+                matched_a[i] = true;
+                matched_b[j] = true;
+                matches += 1;
+                break;
+            }
+        }
+    }
+    if matches == 0 {
+        return 0.0;
+    }
+
+    /* Matched graphemes that are out of order */
+    let in_order_a = graphemes_a.iter().zip(matched_a).filter(|(_, m)| *m).map(|(g, _)| g);
+    let in_order_b = graphemes_b.iter().zip(matched_b).filter(|(_, m)| *m).map(|(g, _)| g);
+    let transpositions = in_order_a.zip(in_order_b).filter(|(a, b)| a != b).count() / 2;
+
+    let matches = matches as f32;
+    let jaro = (matches / graphemes_a.len() as f32
+                + matches / graphemes_b.len() as f32
+                + (matches - transpositions as f32) / matches) / 3.0;
+
+    /* Winkler boost for up to 4 common leading graphemes */
+    let prefix = graphemes_a.iter()
+        .zip(graphemes_b.iter())
+        .take(4)
+        .take_while(|(a, b)| a == b)
+        .count();
+    jaro + prefix as f32 * 0.1 * (1.0 - jaro)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -93,6 +560,24 @@ mod tests {
         assert!(!tokens.contains(&"b".to_string()));
     }
 
+    #[test]
+    fn it_folds_case() {
+        assert_eq!(tokenize("İstanbul", 1), vec!["i̇stanbul"]);
+        assert_eq!(tokenize_with_folding("İstanbul", 1, true), vec!["istanbul"]);
+        assert_eq!(tokenize_with_folding("ISTANBUL ıstanbul", 1, true), vec!["istanbul", "ıstanbul"]);
+        assert_eq!(tokenize("Straße STRASSE", 1), vec!["straße", "strasse"]);
+        assert_eq!(tokenize_with_folding("Straße STRASSE", 1, true), vec!["strasse", "strasse"]);
+        assert_eq!(case_fold("ΟΔΟΣ οδος"), "οδοσ οδοσ");
+    }
+
+    #[test]
+    fn it_tokenizes_on_unicode_separators() {
+        let phrase = "東京\u{3000}大阪\u{ff0c}Kraków「Main」a=b";
+        assert_eq!(tokenize(phrase, 1), vec!["東京\u{3000}大阪\u{ff0c}kraków「main」a", "b"]);
+        assert_eq!(tokenize_unicode(phrase, 1, false), vec!["東京", "大阪", "kraków", "main", "a=b"]);
+        assert_eq!(tokenize_unicode("Ul. Wąska, 7", 1, false), tokenize("Ul. Wąska, 7", 1));
+    }
+
     #[test]
     fn it_trigramizes() {
         let testcases = [
@@ -114,4 +599,143 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn it_calculates_jaro_winkler() {
+        assert_eq!(jaro_winkler("martha", "martha"), 1.0);
+        assert_eq!(jaro_winkler("abc", "xyz"), 0.0);
+        assert!((jaro_winkler("martha", "marhta") - 0.961).abs() < 0.001);
+        assert!((jaro_winkler("dixon", "dicksonx") - 0.813).abs() < 0.001);
+        assert!(jaro_winkler("martha", "marhta") > jaro_winkler("martha", "artham"));
+    }
+
+    #[test]
+    fn it_extends_typo_tolerance() {
+        let testcases = [
+            ("abc", vec!["abc"], vec!["a c"]),
+            ("abcd", vec!["abc", "bcd", "abd", "acd"], vec![]),
+            ("abcde", vec!["abc", "bcd", "cde", "abe", "ade"], vec![]),
+            ("abcdef", vec!["abc", "bcd", "cde", "def"], vec!["abf", "aef"]),
+            ("abcdefghijk", vec!["abc", "ijk"], vec![]),
+        ];
+        for (input, default_trigrams, extended_only) in testcases.iter() {
+            let default = trigramize(input);
+            let extended = trigramize_with_tolerance(input, true);
+            println!("Trigramized {} into {:?} and {:?}", input, default, extended);
+            assert_eq!(default.len() + extended_only.len(), extended.len());
+            for trigram in default_trigrams.iter() {
+                assert!(default.contains(&trigram.to_string()));
+                assert!(extended.contains(&trigram.to_string()));
+            }
+            for trigram in extended_only.iter() {
+                assert!(!default.contains(&trigram.to_string()));
+                assert!(extended.contains(&trigram.to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn it_lists_edit_operations() {
+        assert_eq!(distance_ops("warszawa", "warszawa"), (0, vec![]));
+        assert_eq!(distance_ops("wąrsawa", "warszawka"), (3, vec![
+            EditOp::Substitute { position: 1, grapheme: "a".to_string() },
+            EditOp::Insert { position: 4, grapheme: "z".to_string() },
+            EditOp::Insert { position: 6, grapheme: "k".to_string() },
+        ]));
+        assert_eq!(distance_ops("abc", ""), (3, vec![
+            EditOp::Delete { position: 0 },
+            EditOp::Delete { position: 1 },
+            EditOp::Delete { position: 2 },
+        ]));
+
+        let words = ["", "a", "warszawa", "waszawa", "wraszawa", "kwaszyn", "y̆es", "yes"];
+        for side_a in words.iter() {
+            for side_b in words.iter() {
+                let (distance, ops) = distance_ops(side_a, side_b);
+                assert_eq!(distance, super::distance(side_a, side_b));
+                assert_eq!(ops.len(), distance, "{} {}", side_a, side_b);
+            }
+        }
+    }
+
+    #[test]
+    fn it_calculates_damerau_distance() {
+        assert_eq!(damerau_distance("wraszawa", "warszawa"), 1);
+        assert_eq!(distance("wraszawa", "warszawa"), 2);
+        assert_eq!(damerau_distance("ca", "abc"), 3);
+        assert_eq!(damerau_distance("", "abc"), 3);
+
+        let words = ["", "a", "ab", "ba", "warszawa", "waszawa", "warsawa", "wraszawa",
+                     "wrasazwa", "rakszawa", "kwaszyn", "y̆es", "ey̆s", "abcabcabc", "bcabcabca"];
+        for side_a in words.iter() {
+            for side_b in words.iter() {
+                let full = damerau_distance(side_a, side_b);
+                assert!(full <= distance(side_a, side_b));
+                for max in 0..5 {
+                    let bounded = damerau_within(side_a, side_b, max);
+                    if full <= max {
+                        assert_eq!(bounded, Some(full), "{} {} {}", side_a, side_b, max);
+                    } else {
+                        assert_eq!(bounded, None, "{} {} {}", side_a, side_b, max);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_calculates_char_distance() {
+        assert_eq!(distance_chars("warszawa", "waszawa"), 1);
+        assert_eq!(distance_chars("y̆es", "yes"), 1);
+        assert_eq!(distance("y̆es", "yes"), 1);
+        assert_eq!(distance_chars("y̆es", "es"), 2);
+        assert_eq!(distance("y̆es", "es"), 1);
+    }
+
+    #[test]
+    fn it_calculates_distances_of_pairs() {
+        let pairs = [("warszawa", "waszawa"), ("y̆es", "es"), ("", "abc"), ("kraków", "kraków")];
+        assert_eq!(distances(&pairs), vec![1, 1, 3, 0]);
+        assert_eq!(distances_chars(&pairs), vec![1, 2, 3, 0]);
+        assert!(distances(&[]).is_empty());
+    }
+
+    #[test]
+    fn it_calculates_window_distance() {
+        assert_eq!(window_distance("beethovenstraat", "straat"), 0);
+        assert_eq!(window_distance("beethovenstraat", "strasse"), 3);
+        assert_eq!(window_distance("hauptbahnhof", "banhof"), 1);
+        assert_eq!(window_distance("abc", ""), 0);
+        assert_eq!(window_distance("", "abc"), 3);
+        assert_eq!(window_distance("y̆es", "y̆"), 0);
+    }
+
+    #[test]
+    fn it_trims_token_edges() {
+        assert_eq!(edge_trims("abc", 1), vec!["ab", "bc"]);
+        assert_eq!(edge_trims("abcd", 2), vec!["abc", "ab", "bcd", "bc", "cd"]);
+        assert_eq!(edge_trims("ab", 5), vec!["a", "b"]);
+        assert_eq!(edge_trims("y̆es", 1), vec!["y̆e", "es"]);
+        assert!(edge_trims("abc", 0).is_empty());
+        assert!(edge_trims("", 2).is_empty());
+    }
+
+    #[test]
+    fn it_calculates_bounded_distance() {
+        let words = ["", "a", "ab", "warszawa", "waszawa", "warsawa", "wraszawa",
+                     "rakszawa", "kwaszyn", "y̆es", "yes", "abcabcabc", "bcabcabca"];
+        for side_a in words.iter() {
+            for side_b in words.iter() {
+                let full = distance(side_a, side_b);
+                for max in 0..5 {
+                    let bounded = distance_within(side_a, side_b, max);
+                    if full <= max {
+                        assert_eq!(bounded, Some(full), "{} {} {}", side_a, side_b, max);
+                    } else {
+                        assert_eq!(bounded, None, "{} {} {}", side_a, side_b, max);
+                    }
+                }
+            }
+        }
+    }
 }
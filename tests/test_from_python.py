@@ -8,6 +8,8 @@ $ maturin develop
 $ pytest
 """
 
+import os
+import tempfile
 from concurrent.futures import ThreadPoolExecutor
 import fuzzdex
 
@@ -70,3 +72,523 @@ def test_distance():
 
     # This is a 2 unicode-character grapheme
     assert fuzzdex.distance("y̆es", "yes") == 1
+    assert fuzzdex.distance("y̆es", "es") == 1
+    assert fuzzdex.distance("y̆es", "es", mode="char") == 2
+    assert fuzzdex.distance("oneword", "onewXord", mode="char") == 1
+    try:
+        fuzzdex.distance("a", "b", mode="byte")
+        assert False, "Unknown mode should be rejected"
+    except ValueError:
+        pass
+
+    pairs = [("oneword", "oneXord"), ("onword", "onewoXrd"), ("y̆es", "es")]
+    assert fuzzdex.distances(pairs) == [1, 2, 1]
+    assert fuzzdex.distances(pairs, mode="char") == [1, 2, 2]
+    assert fuzzdex.distances([]) == []
+
+
+def test_len_and_phrases():
+    """Test reading the indexed phrases."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 2, constraints=set())
+    fud.add_phrase("Kraków", 1, constraints=set())
+    try:
+        len(fud)
+        assert False, "Unfinished index has no length"
+    except RuntimeError:
+        pass
+    fud.finish()
+    assert len(fud) == 2
+    assert fud.iter_phrases() == [(1, "Kraków"), (2, "Warszawa")]
+
+
+def test_multiple_must():
+    """Test searching with all must tokens required."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Pałac Kultury i Nauki", 1, constraints=set())
+    fud.add_phrase("Pałac na Wodzie", 2, constraints=set())
+    fud.finish()
+
+    results = fud.search(["palac"], [])
+    assert len(results) == 2
+    assert isinstance(results[0]['distance'], int)
+
+    results = fud.search(["palac", "kultury"], [], max_distance=2)
+    assert len(results) == 1
+    assert results[0]['index'] == 1
+    assert results[0]['token'] == ["pałac", "kultury"]
+    assert results[0]['distance'] == [1, 0]
+
+
+def test_save_load():
+    """Test index persistence."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints={3})
+    fud.add_phrase("Rakszawa", 2, constraints=set())
+    fud.finish()
+
+    with tempfile.TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "index.fuzzdex")
+        fud.save(path)
+        loaded = fuzzdex.FuzzDex.load(path, cache_size=10)
+        assert loaded.search(["waszawa"], [], constraint=3) == \
+            fud.search(["waszawa"], [], constraint=3)
+
+        try:
+            fuzzdex.FuzzDex.load(os.path.join(tmp, "missing"))
+            assert False, "Loading missing file should fail"
+        except RuntimeError:
+            pass
+
+
+def test_candidate_budget():
+    """Test stopping the scan after a number of candidates."""
+    fud = fuzzdex.FuzzDex()
+    for i in range(100):
+        fud.add_phrase(f"Warszawska {i}", i, constraints=set())
+    fud.finish()
+
+    results, stats = fud.search(["warszawsk"], [], max_candidates=10,
+                                with_stats=True)
+    assert len(results) == 10
+    assert stats == {"scanned": 10, "truncated": True}
+
+
+def test_add_from_tsv():
+    """Test loading phrases from a TSV file."""
+    fud = fuzzdex.FuzzDex()
+    with tempfile.TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "phrases.tsv")
+        with open(path, "w") as tsv:
+            tsv.write("1\tWarszawa\n2\tKraków\nbroken\n1\tDuplicate\n")
+        stats = fud.add_from_tsv(path)
+    assert stats == {"added": 2, "skipped": 1, "duplicates": 1,
+                     "empty": 0, "unsearchable": 0}
+    fud.finish()
+    assert len(fud) == 2
+
+
+def test_upsert_phrase():
+    """Test replacing a phrase with the same index."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    assert fud.upsert_phrase("Kraków", 2, constraints=set()) is False
+    assert fud.upsert_phrase("Gdańsk", 1, constraints=set()) is True
+    assert fud.remove_phrase(2) is True
+    fud.finish()
+    assert len(fud) == 1
+    assert fud.search(["warszawa"], [], max_distance=2) == []
+    assert fud.search(["gdansk"], [])[0]["index"] == 1
+
+
+def test_unsearchable_phrases():
+    """Test listing phrases which can't be found."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.add_phrase("\u0301", 2, constraints=set())
+    assert fud.unsearchable_phrases() == [2]
+    try:
+        fud.add_phrase(" - ", 3, constraints=set())
+        assert False, "Empty phrases should be rejected"
+    except RuntimeError as ex:
+        assert "no tokens" in str(ex)
+    try:
+        fud.add_phrase("\ud800", 3, constraints=set())
+        assert False, "Lone surrogates should be rejected"
+    except UnicodeEncodeError:
+        pass
+    try:
+        fud.add_phrase("\u0301", 3, constraints=set(), reject_unsearchable=True)
+        assert False, "Unsearchable phrases should be rejected"
+    except RuntimeError as ex:
+        assert "no trigrams" in str(ex)
+    try:
+        fud.add_phrase("Warszawa", 1, constraints=set(), reject_unsearchable=True)
+        assert False, "Duplicates should be rejected"
+    except RuntimeError as ex:
+        assert "Duplicate" in str(ex)
+    fud.finish()
+    assert fud.unsearchable_phrases() == [2]
+
+
+def test_search_best():
+    """Test returning a single best match."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.add_phrase("Warszawka", 2, constraints=set())
+    fud.finish()
+    assert fud.search_best(["warszawka"], [])["index"] == 2
+    assert fud.search_best(["gdansk"], [], max_distance=2) is None
+
+
+def test_min_must_length():
+    """Test rejecting too short must tokens."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Łódź", 1, constraints=set())
+    fud.finish()
+    assert fud.search(["łódź"], [], min_must_length=4)[0]["index"] == 1
+    try:
+        fud.search(["ł"], [], min_must_length=2)
+        assert False, "Short must token should be rejected"
+    except ValueError as ex:
+        assert "shorter than 2" in str(ex)
+
+
+def test_debug_phrase():
+    """Test showing phrase tokenization."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Ul. Wąska", 1, constraints={2})
+    fud.finish()
+    debug = fud.debug_phrase(1)
+    assert debug["origin"] == "Ul. Wąska"
+    assert debug["tokens"] == ["ul", "wąska"]
+    assert debug["trigrams"][0] == ["ul "]
+    assert debug["constraints"] == [2]
+    assert fud.debug_phrase(2) is None
+
+
+def test_explain_must():
+    """Test listing must trigrams present in the index."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.finish()
+    trigrams = fud.explain_must("Warsaw")
+    assert [t["trigram"] for t in trigrams] == ["war", "ars", "rsa", "saw"]
+    assert [t["present"] for t in trigrams] == [True, True, False, False]
+    assert trigrams[3]["score"] == 0.0
+
+
+def test_explain_edit_ops():
+    """Test reporting edits of the matched token."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawka", 1, constraints=set())
+    fud.finish()
+    result = fud.search(["warszawa"], [], explain=True)[0]
+    assert result["edit_ops"] == [("delete", 7, None)]
+    trigrams = sorted(trigram for trigram, _ in result["matched_trigrams"])
+    assert trigrams == ["ars", "rsz", "sza", "war", "zaw"]
+    assert result["short_token_match"] is False
+
+
+def test_constraints():
+    """Test counting phrases per constraint."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints={1, 2})
+    fud.add_phrase("Kraków", 2, constraints={2})
+    fud.add_phrase("Gdańsk", 3, constraints=set())
+    fud.finish()
+    assert fud.constraints() == {1: 1, 2: 2}
+
+
+def test_finish_progress():
+    """Test reporting progress of finishing the index."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    calls = []
+    fud.finish(progress=lambda processed, total: calls.append((processed, total)))
+    assert calls[0][0] == 0
+    assert calls[-1][0] == calls[-1][1]
+    assert fud.search(["warszawa"], [])[0]["index"] == 1
+
+
+def test_contains():
+    """Test checking phrase membership."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    assert fud.contains(1)
+    assert not fud.contains(2)
+    fud.finish()
+    assert fud.contains(1)
+
+
+def test_close():
+    """Test freeing the index with a context manager."""
+    with fuzzdex.FuzzDex() as fud:
+        fud.add_phrase("Warszawa", 1, constraints=set())
+        fud.finish()
+        assert fud.search(["warszawa"], [])[0]["index"] == 1
+    try:
+        fud.search(["warszawa"], [])
+        assert False, "Closed index should not be searchable"
+    except RuntimeError as ex:
+        assert "closed" in str(ex)
+    fud.close()
+
+
+def test_finished():
+    """Test reading the index state."""
+    fud = fuzzdex.FuzzDex()
+    assert not fud.finished
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.finish()
+    assert fud.finished
+    fud.close()
+    assert not fud.finished
+
+
+def test_string_constraints():
+    """Test constraining by interned strings."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints={"PL-14"})
+    fud.add_phrase("Warszawka", 2, constraints={"PL-12", "PL-14"})
+    fud.add_phrase("Warszów", 3, constraints=set())
+    try:
+        fud.add_phrase("Warszewa", 4, constraints={0})
+        assert False, "Integer constraints can't be mixed with strings"
+    except RuntimeError:
+        pass
+    fud.finish()
+    results = fud.search(["warszawa"], [], constraint_str="PL-12")
+    assert [result["index"] for result in results] == [2]
+    assert fud.search_best(["warszawa"], [], constraint_str="PL-14")["index"] == 1
+    assert fud.search(["warszawa"], [], constraint_str="PL-99") == []
+    assert fud.search(["warszawa"], [], constraint=0) == []
+
+
+def test_token_heatmap():
+    """Test reading trigram scores of the must token."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.add_phrase("Warta", 2, constraints=set())
+    fud.add_phrase("Kraków", 3, constraints=set())
+    fud.finish()
+    heatmap = fud.token_heatmap("Warszawa")
+    assert [phrase_idx for phrase_idx, _ in heatmap] == [1, 2]
+    assert heatmap[0][1] > heatmap[1][1]
+
+
+def test_query_object():
+    """Test searching with a prebuilt Query."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa Mazowieckie", 1, constraints=set())
+    fud.add_phrase("Warta Wielkopolskie", 2, constraints=set())
+    fud.finish()
+
+    query = fuzzdex.Query(["warszawa"], should=["mazowieckie"], limit=1)
+    assert query.must == ["warszawa"]
+    assert query.max_distance == 2
+    results = fud.search(query)
+    assert [result['index'] for result in results] == [1]
+    assert results == fud.search(["warszawa"], ["mazowieckie"], limit=1)
+
+    for kwargs in [{"must": []}, {"must": ["warta"], "scan_cutoff": 1.5}]:
+        try:
+            fuzzdex.Query(**kwargs)
+        except ValueError:
+            pass
+        else:
+            assert False, "Invalid query was accepted"
+
+
+def test_search_phrase():
+    """Test searching with free text."""
+    fud = fuzzdex.FuzzDex()
+    for i in range(20):
+        fud.add_phrase(f"Warszawska {i}", i, constraints=set())
+    fud.add_phrase("Warszawska Kutno", 100, constraints=set())
+    fud.finish()
+    results = fud.search_phrase("Warszawska Kutno", limit=1)
+    assert [result["index"] for result in results] == [100]
+    assert results[0]["token"] == "kutno"
+    assert fud.search_phrase(" - ") == []
+
+
+def test_fallback_best():
+    """Test returning the nearest phrase beyond the max distance."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.finish()
+    assert fud.search(["warszewka"], [], max_distance=1) == []
+    results = fud.search(["warszewka"], [], max_distance=1, fallback_best=True)
+    assert [result["index"] for result in results] == [1]
+    assert results[0]["within_distance"] is False
+    assert fud.search(["warszawa"], [])[0]["within_distance"] is True
+
+
+def test_min_token_length():
+    """Test dropping short tokens from phrases and queries."""
+    fud = fuzzdex.FuzzDex()
+    assert fud.min_token_length == 1
+    assert fud.tokenize("Aleja 3 Maja") == ["aleja", "3", "maja"]
+    assert fuzzdex.tokenize("Aleja 3 Maja") == fud.tokenize("Aleja 3 Maja")
+
+    fud = fuzzdex.FuzzDex(min_token_length=2)
+    fud.add_phrase("Aleja 3 Maja", 1, constraints=set())
+    fud.finish()
+    assert fud.min_token_length == 2
+    assert fud.tokenize("Aleja 3 Maja") == ["aleja", "maja"]
+    assert fuzzdex.tokenize("Aleja 3 Maja", 2) == fud.tokenize("Aleja 3 Maja")
+
+    try:
+        fuzzdex.FuzzDex(min_token_length=0)
+        assert False, "Should raise"
+    except ValueError:
+        pass
+
+
+def test_search_callback():
+    """Test passing results to a callback in ranked order."""
+    fud = fuzzdex.FuzzDex()
+    for i, phrase in enumerate(["Warszawa", "Warszawka", "Warszewa", "Kraków"]):
+        fud.add_phrase(phrase, i, constraints=set())
+    fud.finish()
+    expected = [result["index"] for result in fud.search(["warszawa"], [], scan_cutoff=0.0)]
+
+    received = []
+    assert fud.search(["warszawa"], [], scan_cutoff=0.0, callback=lambda r: received.append(r["index"])) == 3
+    assert received == expected
+
+    received = []
+    def first_two(result):
+        received.append(result["index"])
+        return len(received) < 2
+    assert fud.search(["warszawa"], [], scan_cutoff=0.0, callback=first_two) == 2
+    assert received == expected[:2]
+
+
+def test_suggest():
+    """Test suggesting indexed tokens close to a misspelled one."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa Centralna", 1, constraints=set())
+    fud.add_phrase("Warszawka", 2, constraints=set())
+    fud.finish()
+    assert fud.suggest("warszewa", 2) == [("warszawa", 1), ("warszawka", 2)]
+    assert fud.suggest("xyz", 2) == []
+
+
+def test_validate():
+    """Test checking index consistency."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    assert fud.validate() == []
+    fud.finish()
+    assert fud.validate() == []
+
+
+def test_phrase_tokens():
+    """Test reading tokens and constraints of a phrase."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Kraków Główny", 1, constraints={5, 3})
+    fud.finish()
+    assert fud.phrase_tokens(1) == ["kraków", "główny"]
+    assert fud.phrase_constraints(1) == [3, 5]
+    assert fud.phrase_tokens(2) is None
+    assert fud.phrase_constraints(2) is None
+
+
+def test_remove_by_constraint():
+    """Test removing all phrases of a constraint."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints={1})
+    fud.add_phrase("Warszawka", 2, constraints={2})
+    assert fud.remove_by_constraint(1) == 1
+    fud.finish()
+    assert [result["index"] for result in fud.search(["warszawa"], [])] == [2]
+
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints={"PL-12"})
+    fud.add_phrase("Warszewa", 3, constraints={"PL-14"})
+    assert fud.remove_by_constraint(constraint_str="PL-14") == 1
+    assert fud.remove_by_constraint(constraint_str="PL-99") == 0
+    fud.finish()
+    assert [result["index"] for result in fud.search(["warszawa"], [])] == [1]
+
+
+def test_estimate_candidates():
+    """Test estimating number of candidates of a must token."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.add_phrase("Warszawka", 2, constraints=set())
+    fud.add_phrase("Kraków", 3, constraints=set())
+    fud.finish()
+    # Upper bound, capped at the number of phrases
+    assert fud.estimate_candidates("warszawa") == 3
+    assert 1 <= fud.estimate_candidates("krakow") <= 3
+    assert fud.estimate_candidates("zzz") == 0
+
+
+def test_split_digits():
+    """Test splitting runs of digits and letters into separate tokens."""
+    fud = fuzzdex.FuzzDex()
+    assert fud.tokenize("A1 12B 1 May") == ["a1", "12b", "1", "may"]
+
+    fud = fuzzdex.FuzzDex(split_digits=True)
+    assert fud.tokenize("A1 12B 1 May") == ["a", "1", "12", "b", "1", "may"]
+
+
+def test_search_grouped_by_must():
+    """Test grouping results by the matched must spelling."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Muhammad Ali", 1, constraints=set())
+    fud.add_phrase("Mohammed Salah", 2, constraints=set())
+    fud.add_phrase("Mohamed Hadid", 3, constraints=set())
+    for i in range(40):
+        fud.add_phrase(f"Kraków {i}", 100 + i, constraints=set())
+    fud.finish()
+
+    grouped = fud.search_grouped_by_must("muhammad", ["Mohammed", "mahmud"],
+                                         max_distance=1, scan_cutoff=0.0)
+    assert set(grouped) == {"muhammad", "mohammed", "mahmud"}
+    assert [result["index"] for result in grouped["muhammad"]] == [1]
+    assert sorted(result["index"] for result in grouped["mohammed"]) == [2, 3]
+    assert grouped["mahmud"] == []
+
+
+def test_has_any_trigram():
+    """Test telling unknown tokens from too distant ones."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa", 1, constraints=set())
+    fud.finish()
+    assert fud.has_any_trigram("Warszawa")
+    assert fud.has_any_trigram("warxxxxx")
+    assert not fud.has_any_trigram("zzzqqq")
+
+
+def test_recommend_scan_cutoff():
+    """Test recommending a scan cutoff from sample must tokens."""
+    fud = fuzzdex.FuzzDex()
+    for i, phrase in enumerate(["Warszawa", "Warszewa", "Warszawka", "Gdańsk"]):
+        fud.add_phrase(phrase, i, constraints=set())
+    fud.finish()
+    assert fud.recommend_scan_cutoff([]) == 0.0
+    cutoff = fud.recommend_scan_cutoff(["warszawa", "gdansk"])
+    assert 0.0 <= cutoff < 1.0
+
+
+def test_normalized_origin():
+    """Test returning lowercased origins of the results."""
+    fud = fuzzdex.FuzzDex(normalized_origin=True)
+    fud.add_phrase("ŁÓDŹ Kaliska", 1, constraints=set())
+    fud.finish()
+    results = fud.search(["kalisk"], [], scan_cutoff=0.0, max_distance=1)
+    assert results[0]["origin"] == "ŁÓDŹ Kaliska"
+    assert results[0]["normalized_origin"] == "łódź kaliska"
+
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("ŁÓDŹ Kaliska", 1, constraints=set())
+    fud.finish()
+    assert "normalized_origin" not in fud.search(["łódź"], [])[0]
+
+
+def test_negative_should_weights():
+    """Test penalizing phrases with negatively weighted should tokens."""
+    fud = fuzzdex.FuzzDex()
+    fud.add_phrase("Warszawa Old", 1, constraints=set())
+    fud.add_phrase("Warszawa Nowa", 2, constraints=set())
+    for i in range(40):
+        fud.add_phrase(f"Kraków {i}", 100 + i, constraints=set())
+    fud.finish()
+
+    results = fud.search(["warszawa"], ["old"], scan_cutoff=0.0, should_weights=[-1.0])
+    assert [result["index"] for result in results] == [2, 1]
+    assert results[1]["should_score"] < 0
+
+    query = fuzzdex.Query(["warszawa"], ["old"], scan_cutoff=0.0, should_weights=[-1.0])
+    assert query.should_weights == [-1.0]
+    assert [result["index"] for result in fud.search(query)] == [2, 1]
+
+    try:
+        fud.search(["warszawa"], ["old"], should_weights=[float("nan")])
+        assert False, "Non-finite weights should be rejected"
+    except ValueError:
+        pass